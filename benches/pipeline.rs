@@ -0,0 +1,220 @@
+//! Benchmarks of the core pipeline, over the corpora of `huff::corpus`.
+//!
+//! Run with `cargo bench`; `cargo bench -- encode/` runs only the benchmarks whose names contain
+//! `encode/`. Names are `group/input`, and stay the same so that results can be compared across
+//! changes. Under `cargo test`, criterion runs each benchmark once instead of timing it.
+
+use std::hint::black_box;
+use std::io;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use huff::bitstream::{BitRead, BitReader};
+use huff::format::{CodeTable, Mode};
+use huff::heap::BinaryMinHeap;
+use huff::tree::Frequencies;
+use huff::{corpus, encode, tree};
+
+const INPUT_LEN: usize = 1 << 20;
+
+/// Number of items in the heap benchmarks. With debug assertions, as under `cargo test`, the heap
+/// checks its whole invariant after every operation, which would make popping 2^16 items take
+/// minutes.
+const HEAP_LEN: usize = if cfg!(debug_assertions) {
+    1 << 8
+} else {
+    1 << 16
+};
+
+/// Bits of a byte slice, in the order `BitReader` reads them, with as little overhead as
+/// possible.
+struct SliceBits<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl BitRead for SliceBits<'_> {
+    fn read_bit(&mut self) -> io::Result<Option<bool>> {
+        let bit = self
+            .bytes
+            .get(self.position / 8)
+            .map(|byte| byte >> (self.position % 8) & 1 != 0);
+        self.position += 1;
+        Ok(bit)
+    }
+}
+
+/// Code length profiles, as name, input and its frequencies: about 8 bits, short and skewed, up
+/// to 39 bits, and past a word.
+fn profiles() -> Vec<(&'static str, Vec<u8>, Frequencies)> {
+    let random = corpus::random(INPUT_LEN, 1);
+    let text = corpus::text_like(INPUT_LEN, 1);
+    vec![
+        ("random", random.clone(), tree::compute_frequencies(&random)),
+        ("text", text.clone(), tree::compute_frequencies(&text)),
+        (
+            "fibonacci40",
+            corpus::fibonacci_symbols(INPUT_LEN, 40, 1),
+            corpus::fibonacci_frequencies(40),
+        ),
+        (
+            "fibonacci90",
+            corpus::fibonacci_symbols(INPUT_LEN, 90, 1),
+            corpus::fibonacci_frequencies(90),
+        ),
+    ]
+}
+
+fn count(c: &mut Criterion) {
+    let mut group = c.benchmark_group("count");
+    group.throughput(Throughput::Bytes(INPUT_LEN as u64));
+    for (name, input) in &[
+        ("random", corpus::random(INPUT_LEN, 1)),
+        ("text", corpus::text_like(INPUT_LEN, 1)),
+        ("constant", corpus::constant(INPUT_LEN)),
+    ] {
+        group.bench_function(*name, |b| b.iter(|| tree::count_table(black_box(input))));
+    }
+    group.finish();
+}
+
+fn build_tree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree");
+    let small_frequencies = tree::compute_frequencies(b"gattaca, tactic, cat");
+    for (name, frequencies) in &[
+        (
+            "random",
+            tree::compute_frequencies(&corpus::random(INPUT_LEN, 1)),
+        ),
+        (
+            "text",
+            tree::compute_frequencies(&corpus::text_like(INPUT_LEN, 1)),
+        ),
+        ("small", small_frequencies.clone()),
+    ] {
+        group.bench_function(*name, |b| {
+            b.iter(|| tree::build_tree_arena(black_box(frequencies)))
+        });
+    }
+    // The same small alphabet as a boxed `Tree`, which allocates every node.
+    group.bench_function("small-boxed", |b| {
+        b.iter(|| tree::build_tree(black_box(&small_frequencies)))
+    });
+    group.finish();
+}
+
+fn heap(c: &mut Criterion) {
+    let mut group = c.benchmark_group("heap");
+    // Popping a large heap empty, where most of the time goes into sifting down.
+    let heap_items: Vec<u64> = corpus::random(INPUT_LEN, 1)
+        .chunks_exact(8)
+        .take(HEAP_LEN)
+        .map(|bytes| {
+            u64::from_le_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+            ])
+        })
+        .collect();
+    group.bench_function("pop", |b| {
+        b.iter(|| {
+            let mut heap = BinaryMinHeap::from(heap_items.clone());
+            while heap.pop().is_some() {}
+        })
+    });
+    // The same with keys that take longer to compare, sharing a long prefix.
+    let heap_strings: Vec<String> = heap_items
+        .iter()
+        .map(|x| format!("{:0>32}", x % 1_000_000))
+        .collect();
+    group.bench_function("pop-strings", |b| {
+        b.iter(|| {
+            let mut heap = BinaryMinHeap::from(heap_strings.clone());
+            while heap.pop().is_some() {}
+        })
+    });
+    group.finish();
+}
+
+fn code(c: &mut Criterion) {
+    let mut group = c.benchmark_group("code");
+    let frequencies = tree::compute_frequencies(&corpus::random(INPUT_LEN, 1));
+    for (name, mode) in &[
+        ("plain", Mode::Plain),
+        ("canonical", Mode::Canonical),
+        ("limited", Mode::LengthLimited),
+    ] {
+        group.bench_function(*name, |b| {
+            b.iter(|| CodeTable::new(black_box(&frequencies), *mode).to_code())
+        });
+    }
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Bytes(INPUT_LEN as u64));
+    for (name, input, frequencies) in &profiles() {
+        let code = tree::build_tree_arena(frequencies).to_code_with(tree::SingleLeaf::OneBit);
+        group.bench_function(*name, |b| {
+            b.iter(|| encode::encode_stream(&code, &input[..], io::sink()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(INPUT_LEN as u64));
+    for (name, input, frequencies) in &profiles() {
+        let table = CodeTable::Frequencies(frequencies.clone());
+        let code = table.to_code().unwrap();
+        let mut encoded = vec![];
+        encode::encode_stream(&code, &input[..], &mut encoded).unwrap();
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut decoder = table.to_decoder().unwrap();
+                let mut reader = BitReader::new(&encoded[..]);
+                for _ in 0..input.len() {
+                    black_box(decoder.decode_symbol(&mut reader).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The trie walk alone, on bits from memory rather than through `BitReader`.
+fn walk(c: &mut Criterion) {
+    let mut group = c.benchmark_group("walk");
+    group.throughput(Throughput::Bytes(INPUT_LEN as u64));
+    for (name, input, frequencies) in &profiles() {
+        let table = CodeTable::Frequencies(frequencies.clone());
+        let code = table.to_code().unwrap();
+        let mut encoded = vec![];
+        encode::encode_stream(&code, &input[..], &mut encoded).unwrap();
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut decoder = table.to_decoder().unwrap();
+                let mut bits = SliceBits {
+                    bytes: &encoded,
+                    position: 0,
+                };
+                for _ in 0..input.len() {
+                    black_box(decoder.decode_symbol(&mut bits).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn compress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compress");
+    group.throughput(Throughput::Bytes(INPUT_LEN as u64));
+    let text = corpus::text_like(INPUT_LEN, 1);
+    group.bench_function("text", |b| b.iter(|| huff::compress(black_box(&text))));
+    group.finish();
+}
+
+criterion_group!(benches, count, build_tree, heap, code, encode, decode, walk, compress);
+criterion_main!(benches);
@@ -0,0 +1,504 @@
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
+
+/// Read from `input` until `buf` is full or the input ends, and return the number of bytes read;
+/// less than `buf.len()` only at the end of the input.
+///
+/// Retries reads failing with `ErrorKind::Interrupted`, which a signal can cause at any point.
+pub fn read_chunk<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Scratch buffers for the streaming functions (see `encode::encode_stream_with` and
+/// `Decompressor::decompress_stream_with`), which can be kept across streams so that they're
+/// allocated and zeroed once, rather than for every stream.
+///
+/// Buffers hold whatever their last user left in them. Users only ever read back the part
+/// they've filled themselves, so nothing carries over from one stream to the next.
+#[derive(Default)]
+pub struct Buffers {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Output words of the encoder, see `Encoder::encode_to_writer`.
+    pub(crate) words: Vec<u64>,
+}
+
+impl Buffers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A buffer of `len` bytes to read input into.
+    pub fn input(&mut self, len: usize) -> &mut [u8] {
+        grow(&mut self.input, len)
+    }
+
+    /// A buffer of `len` bytes to collect output in.
+    pub fn output(&mut self, len: usize) -> &mut [u8] {
+        grow(&mut self.output, len)
+    }
+}
+
+fn grow(buf: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    if buf.len() < len {
+        buf.resize(len, 0);
+    }
+    &mut buf[..len]
+}
+
+/// Read all of `input` in chunks of `chunk_len` bytes (see `read_chunk`), and pass each to `f`,
+/// which runs on another thread: while it processes one chunk, the next one is read. So reading
+/// and processing overlap, rather than the disk idling during processing and the CPU during
+/// reading.
+///
+/// Two buffers alternate between the threads, which bounds how far reading gets ahead. Read
+/// errors get the context "reading input"; errors from `f` are returned as they are, and stop
+/// the reading.
+pub fn read_chunks_ahead<R, F>(mut input: R, chunk_len: usize, f: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(&[u8]) -> io::Result<()> + Send,
+{
+    let (full_tx, full_rx) = mpsc::sync_channel::<(Vec<u8>, usize)>(2);
+    let (empty_tx, empty_rx) = mpsc::sync_channel(2);
+    for _ in 0..2 {
+        empty_tx.send(vec![0; chunk_len]).unwrap();
+    }
+    thread::scope(|scope| {
+        let processor = scope.spawn(move || {
+            let mut f = f;
+            for (buf, len) in full_rx {
+                f(&buf[..len])?;
+                // Fails if reading has already ended.
+                let _ = empty_tx.send(buf);
+            }
+            Ok(())
+        });
+        // Stops early once the processor is gone, after an error.
+        let mut read_result = Ok(());
+        while let Ok(mut buf) = empty_rx.recv() {
+            match read_chunk(&mut input, &mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    if full_tx.send((buf, len)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    read_result = Err(with_context(err, "reading input"));
+                    break;
+                }
+            }
+        }
+        drop(full_tx);
+        let processed = processor
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        processed.and(read_result)
+    })
+}
+
+/// Prefix the error message with what was being done, e.g. the operation and path, keeping the
+/// error kind.
+pub fn with_context(err: io::Error, context: impl std::fmt::Display) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", context, err))
+}
+
+/// Reads a stream of bits from a byte stream.
+///
+/// Bits inside bytes are read in little-endian order (first bit at `1 << 0`), which matches the
+/// layout of the words produced by the encoder.
+pub struct BitReader<R> {
+    inner: R,
+    /// Bits not yet returned, starting at `1 << 0`.
+    buf: u8,
+    /// Number of valid bits in `buf`.
+    available: usize,
+    bits_read: u64,
+    /// Number of bits after which the stream ends, see `with_bit_limit`.
+    bit_limit: Option<u64>,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buf: 0,
+            available: 0,
+            bits_read: 0,
+            bit_limit: None,
+        }
+    }
+
+    /// End the stream after `bits` bits in total, e.g. to ignore the padding bits after the last
+    /// codeword, when the number of valid bits is known (see `Encoder::finish`).
+    pub fn with_bit_limit(mut self, bits: u64) -> Self {
+        self.bit_limit = Some(bits);
+        self
+    }
+
+    /// Reads the next bit, or returns `None` at the end of the stream.
+    pub fn read_bit(&mut self) -> io::Result<Option<bool>> {
+        if self.bit_limit == Some(self.bits_read) {
+            return Ok(None);
+        }
+        if self.available == 0 {
+            let mut byte = [0u8];
+            if read_chunk(&mut self.inner, &mut byte)? == 0 {
+                return Ok(None);
+            }
+            self.buf = byte[0];
+            self.available = 8;
+        }
+        let bit = self.buf & 1 != 0;
+        self.buf >>= 1;
+        self.available -= 1;
+        self.bits_read += 1;
+        Ok(Some(bit))
+    }
+
+    /// Total number of bits read so far.
+    pub fn bits_read(&self) -> u64 {
+        self.bits_read
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Reads a raw encoded stream written on a big-endian host by the old `main.rs`, before output
+/// words were written in little-endian order (see `encode::write_words`): reverses each group of
+/// 8 bytes, so that `BitReader` gets the bytes in the order written today. Those streams had no
+/// header, so the code has to be known some other way. On little-endian hosts, the old output is
+/// the same as today's, and needs no conversion.
+///
+/// The old writer only wrote whole words; input ending inside a word is `InvalidData`.
+pub struct BigEndianWords<R> {
+    inner: R,
+    /// The current word, in little-endian order.
+    word: [u8; 8],
+    /// Number of bytes of `word` returned so far.
+    pos: usize,
+}
+
+impl<R: Read> BigEndianWords<R> {
+    pub fn new(inner: R) -> Self {
+        BigEndianWords {
+            inner,
+            word: [0; 8],
+            pos: 8,
+        }
+    }
+}
+
+impl<R: Read> Read for BigEndianWords<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.word.len() {
+            match read_chunk(&mut self.inner, &mut self.word)? {
+                0 => return Ok(0),
+                8 => {}
+                len => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("stream ends {} bytes into a word", len),
+                    ))
+                }
+            }
+            self.word.reverse();
+            self.pos = 0;
+        }
+        let len = buf.len().min(self.word.len() - self.pos);
+        buf[..len].copy_from_slice(&self.word[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// A source of bits, for decoders that shouldn't depend on where the bits come from.
+pub trait BitRead {
+    /// Reads the next bit, or returns `None` at the end of the stream.
+    fn read_bit(&mut self) -> io::Result<Option<bool>>;
+}
+
+impl<R: Read> BitRead for BitReader<R> {
+    fn read_bit(&mut self) -> io::Result<Option<bool>> {
+        BitReader::read_bit(self)
+    }
+}
+
+/// A sink for bits, the counterpart of `BitRead`.
+pub trait BitWrite {
+    fn write_bit(&mut self, bit: bool) -> io::Result<()>;
+}
+
+/// Writes a stream of bits to a byte stream, in the order `BitReader` reads them back.
+pub struct BitWriter<W> {
+    inner: W,
+    /// Bits not yet written out, starting at `1 << 0`.
+    buf: u8,
+    /// Number of valid bits in `buf`.
+    len: usize,
+    bits_written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BitWriter {
+            inner,
+            buf: 0,
+            len: 0,
+            bits_written: 0,
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        self.buf |= (bit as u8) << self.len;
+        self.len += 1;
+        self.bits_written += 1;
+        if self.len == 8 {
+            self.inner.write_all(&[self.buf])?;
+            self.buf = 0;
+            self.len = 0;
+        }
+        Ok(())
+    }
+
+    /// Total number of bits written so far.
+    pub fn bits_written(&self) -> u64 {
+        self.bits_written
+    }
+
+    /// Write out the last partial byte, padded with zero bits, and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.len > 0 {
+            self.inner.write_all(&[self.buf])?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> BitWrite for BitWriter<W> {
+    fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        BitWriter::write_bit(self, bit)
+    }
+}
+
+#[test]
+fn test_read_bits() {
+    let mut reader = BitReader::new(&[0b0000_0110u8, 0b1000_0000][..]);
+    let mut bits = vec![];
+    while let Some(bit) = reader.read_bit().unwrap() {
+        bits.push(bit as u8);
+    }
+    assert_eq!(bits, vec![0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(reader.bits_read(), 16);
+}
+
+/// A reader or writer which transfers at most `max_len` bytes per call, and fails with
+/// `ErrorKind::Interrupted` on the calls whose numbers (counting from 0) are in `interrupt_at`.
+#[cfg(test)]
+pub struct Flaky<T> {
+    pub inner: T,
+    pub max_len: usize,
+    pub interrupt_at: Vec<usize>,
+    pub calls: usize,
+}
+
+#[cfg(test)]
+impl<T> Flaky<T> {
+    pub fn new(inner: T, max_len: usize, interrupt_at: &[usize]) -> Self {
+        Flaky {
+            inner,
+            max_len,
+            interrupt_at: interrupt_at.to_vec(),
+            calls: 0,
+        }
+    }
+
+    fn interrupted(&mut self) -> bool {
+        self.calls += 1;
+        self.interrupt_at.contains(&(self.calls - 1))
+    }
+}
+
+#[cfg(test)]
+impl<T: Read> Read for Flaky<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.interrupted() {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        let len = buf.len().min(self.max_len);
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+#[cfg(test)]
+impl<T: io::Write> io::Write for Flaky<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.interrupted() {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        let len = buf.len().min(self.max_len);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_bit_writer() {
+    let bits = [0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 1];
+    let mut writer = BitWriter::new(vec![]);
+    for &bit in &bits {
+        writer.write_bit(bit != 0).unwrap();
+    }
+    assert_eq!(writer.bits_written(), 19);
+    let bytes = writer.finish().unwrap();
+    assert_eq!(bytes, vec![0b0000_0110, 0b1000_0000, 0b101]);
+
+    let mut reader = BitReader::new(&bytes[..]);
+    for &bit in &bits {
+        assert_eq!(reader.read_bit().unwrap(), Some(bit != 0));
+    }
+}
+
+#[test]
+fn test_read_chunk() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut input = Flaky::new(&data[..], 7, &[0, 3, 4, 10]);
+    let mut buf = [0u8; 40];
+    assert_eq!(read_chunk(&mut input, &mut buf).unwrap(), 40);
+    assert_eq!(&buf[..], &data[..40]);
+    assert_eq!(read_chunk(&mut input, &mut buf).unwrap(), 40);
+    assert_eq!(&buf[..], &data[40..80]);
+    assert_eq!(read_chunk(&mut input, &mut buf).unwrap(), 20);
+    assert_eq!(&buf[..20], &data[80..]);
+    assert_eq!(read_chunk(&mut input, &mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_read_chunks_ahead() {
+    use std::time::Duration;
+
+    /// Reports the length of each read once it's done.
+    struct ReportingReader<'a> {
+        data: &'a [u8],
+        reads: mpsc::Sender<usize>,
+    }
+    impl Read for ReportingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.data.read(buf)?;
+            self.reads.send(len).unwrap();
+            Ok(len)
+        }
+    }
+
+    let data: Vec<u8> = (0..50).collect();
+    let (reads_tx, reads_rx) = mpsc::channel();
+    let input = ReportingReader {
+        data: &data,
+        reads: reads_tx,
+    };
+    let mut chunks = vec![];
+    let mut overlapped = 0;
+    let (chunks_ref, overlapped_ref) = (&mut chunks, &mut overlapped);
+    let mut reads = 0;
+    // Moves the receiver to the processing thread.
+    read_chunks_ahead(input, 10, move |chunk| {
+        chunks_ref.extend_from_slice(chunk);
+        // Before returning, wait for the next chunk to be read, which only happens if reading
+        // goes on while a chunk is being processed. The timeout only keeps a broken
+        // implementation from hanging the test.
+        let consumed = chunks_ref.len() / 10;
+        while reads <= consumed {
+            match reads_rx.recv_timeout(Duration::from_secs(10)) {
+                Ok(_) => reads += 1,
+                Err(_) => return Ok(()),
+            }
+        }
+        *overlapped_ref += 1;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(chunks, data);
+    // Each chunk was read while the one before it was being processed, and the end of the input
+    // while the last one was.
+    assert_eq!(overlapped, 5);
+
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk on fire"))
+        }
+    }
+    let err = read_chunks_ahead(Failing, 10, |_| Ok(())).unwrap_err();
+    assert_eq!(err.to_string(), "reading input: disk on fire");
+    // An error of the consumer stops the reader.
+    let err = read_chunks_ahead(io::repeat(0), 10, |_| {
+        Err(io::Error::new(io::ErrorKind::WriteZero, "full"))
+    })
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+}
+
+#[test]
+fn test_read_chunk_error() {
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk on fire"))
+        }
+    }
+    let err = read_chunk(&mut Failing, &mut [0; 4]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let err = with_context(err, "reading input");
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(err.to_string(), "reading input: disk on fire");
+}
+
+#[test]
+fn test_read_bits_interrupted() {
+    let mut reader = BitReader::new(Flaky::new(&[0b0000_0110u8, 0b1000_0000][..], 1, &[0, 1, 2]));
+    let mut bits = vec![];
+    while let Some(bit) = reader.read_bit().unwrap() {
+        bits.push(bit as u8);
+    }
+    assert_eq!(bits, vec![0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+}
+
+#[test]
+fn test_big_endian_words() {
+    let input = [1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    let mut output = vec![];
+    // One byte per read, with interruptions, to cross word boundaries inside `read`.
+    BigEndianWords::new(Flaky::new(&input[..], 3, &[0, 2]))
+        .read_to_end(&mut output)
+        .unwrap();
+    assert_eq!(
+        output,
+        vec![8, 7, 6, 5, 4, 3, 2, 1, 16, 15, 14, 13, 12, 11, 10, 9]
+    );
+
+    let mut reader = BigEndianWords::new(&input[..12]);
+    let mut output = [0; 8];
+    reader.read_exact(&mut output).unwrap();
+    let err = reader.read(&mut output).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "stream ends 4 bytes into a word");
+}
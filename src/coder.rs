@@ -0,0 +1,43 @@
+//! Symbol-at-a-time interfaces for entropy coders, which the Huffman `Encoder` and `Decoder`
+//! implement. The container in `lib.rs` doesn't go through them: it uses the Huffman coder
+//! directly, with its word-at-a-time encoding.
+
+use std::io;
+
+use crate::bitstream::{BitRead, BitWrite};
+use crate::decode::DecodeError;
+
+pub trait SymbolEncoder {
+    /// Write the encoding of `sym` to `out`.
+    fn encode_symbol(&mut self, sym: u8, out: &mut dyn BitWrite) -> io::Result<()>;
+}
+
+pub trait SymbolDecoder {
+    /// Decode exactly one symbol from `input`, leaving it positioned right after the symbol.
+    fn decode_symbol(&mut self, input: &mut dyn BitRead) -> Result<u8, DecodeError>;
+}
+
+/// Encode all of `input` with `encoder`.
+pub fn encode_all(
+    encoder: &mut dyn SymbolEncoder,
+    input: &[u8],
+    out: &mut dyn BitWrite,
+) -> io::Result<()> {
+    for &sym in input {
+        encoder.encode_symbol(sym, out)?;
+    }
+    Ok(())
+}
+
+/// Decode `len` symbols with `decoder`.
+pub fn decode_all(
+    decoder: &mut dyn SymbolDecoder,
+    len: u64,
+    input: &mut dyn BitRead,
+) -> Result<Vec<u8>, DecodeError> {
+    let mut output = vec![];
+    for _ in 0..len {
+        output.push(decoder.decode_symbol(input)?);
+    }
+    Ok(output)
+}
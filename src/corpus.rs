@@ -0,0 +1,128 @@
+//! Deterministic inputs for benchmarks and tests, generated in code so that every run sees the
+//! same data without shipping any files. Not part of the public API: it may change or go away in
+//! any release.
+
+use crate::tree::Frequencies;
+
+#[cfg(test)]
+use crate::tree;
+
+/// A small, fast generator (SplitMix64). Not for anything but making up test data.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, nearly uniformly distributed for `n` much smaller than `2^64`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Uniformly distributed bytes, which don't compress: every codeword is about 8 bits long.
+pub fn random(len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let mut output = Vec::with_capacity(len + 8);
+    while output.len() < len {
+        output.extend_from_slice(&rng.next_u64().to_le_bytes());
+    }
+    output.truncate(len);
+    output
+}
+
+/// Lowercase words separated by spaces, with a line break now and then. Words are drawn from a
+/// fixed vocabulary with a skewed distribution, so the byte frequencies look like English text.
+pub fn text_like(len: usize, seed: u64) -> Vec<u8> {
+    const WORDS: [&str; 24] = [
+        "the", "of", "and", "to", "in", "a", "is", "that", "for", "it", "as", "was", "with", "be",
+        "by", "on", "not", "he", "this", "are", "or", "his", "from", "huffman",
+    ];
+    let mut rng = Rng::new(seed);
+    let mut output = Vec::with_capacity(len + 16);
+    while output.len() < len {
+        // The minimum of two draws favors the words at the front.
+        let word = rng
+            .below(WORDS.len() as u64)
+            .min(rng.below(WORDS.len() as u64));
+        output.extend_from_slice(WORDS[word as usize].as_bytes());
+        output.push(if rng.below(12) == 0 { b'\n' } else { b' ' });
+    }
+    output.truncate(len);
+    output
+}
+
+/// The same byte over and over.
+pub fn constant(len: usize) -> Vec<u8> {
+    vec![b'x'; len]
+}
+
+/// Frequencies growing like the Fibonacci sequence, which give a maximally unbalanced tree:
+/// the longest codewords are `n - 1` bits long.
+pub fn fibonacci_frequencies(n: usize) -> Frequencies {
+    let (mut a, mut b) = (1, 1);
+    (0..n)
+        .map(|sym| {
+            let freq = a;
+            a = b;
+            b += freq;
+            (sym as u8, freq)
+        })
+        .collect()
+}
+
+/// Symbols drawn uniformly from the first `n` of `fibonacci_frequencies(n)`, so that most of
+/// them get long codewords in its code.
+pub fn fibonacci_symbols(len: usize, n: usize, seed: u64) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    (0..len).map(|_| rng.below(n as u64) as u8).collect()
+}
+
+#[test]
+fn test_corpora() {
+    let len = 100_000;
+    for input in &[
+        random(len, 1),
+        text_like(len, 1),
+        constant(len),
+        fibonacci_symbols(len, 40, 1),
+    ] {
+        assert_eq!(input.len(), len);
+    }
+    assert_eq!(random(len, 1), random(len, 1));
+    assert_ne!(random(len, 1), random(len, 2));
+    assert_eq!(text_like(len, 7), text_like(len, 7));
+    assert_eq!(random(len, 3)[..1000], random(1000, 3)[..]);
+
+    // Random bytes use the whole alphabet about evenly.
+    let counts = tree::count_table(&random(len, 1));
+    assert!(counts.iter().all(|&count| (250..550).contains(&count)));
+
+    // Text is mostly spaces and the letters of the vocabulary, with skewed frequencies.
+    let frequencies = tree::compute_frequencies(&text_like(len, 1));
+    assert!(frequencies
+        .iter()
+        .all(|&(sym, _)| sym.is_ascii_lowercase() || sym == b' ' || sym == b'\n'));
+    let max = frequencies.iter().map(|&(_, count)| count).max().unwrap();
+    let min = frequencies.iter().map(|&(_, count)| count).min().unwrap();
+    assert!(max > 20 * min);
+
+    assert_eq!(
+        tree::compute_frequencies(&constant(len)),
+        vec![(b'x', len as u64)]
+    );
+    let symbols = tree::compute_frequencies(&fibonacci_symbols(len, 40, 1));
+    assert_eq!(symbols.len(), 40);
+
+    let code = tree::tree_to_code(&tree::build_tree(&fibonacci_frequencies(40)));
+    assert_eq!(code[0].bit_len, 39);
+}
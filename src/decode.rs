@@ -0,0 +1,640 @@
+use std::io;
+
+use crate::bitstream::{BitRead, BitReader};
+use crate::coder::SymbolDecoder;
+use crate::tree::{Code, Node, SingleLeaf, Tree, TreeArena};
+
+#[cfg(test)]
+use crate::bitstream::BigEndianWords;
+#[cfg(test)]
+use crate::{encode, tree};
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The stream ended in the middle of a codeword (or before it).
+    UnexpectedEof,
+    /// The bits read so far don't form a prefix of any codeword.
+    InvalidCodeword,
+    /// The code has one symbol with a zero-length codeword (see `Decoder::single_symbol`), so the
+    /// number of symbols can't be told from the bits.
+    ZeroLengthCode,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => f.write_str("unexpected end of stream"),
+            DecodeError::InvalidCodeword => f.write_str("invalid codeword"),
+            DecodeError::ZeroLengthCode => {
+                f.write_str("can't find the end of a stream in a zero-length code")
+            }
+            DecodeError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        DecodeError::Io(err)
+    }
+}
+
+/// A child in `Decoder::nodes`: the index of another node, or a leaf if `LEAF` is set, with the
+/// symbol in the low bits. The tag lets a walk go from node to node with a plain lookup per bit.
+type Child = u32;
+
+const LEAF: Child = 1 << 31;
+
+/// Where there's no codeword. Tagged as a leaf, so that it ends a walk, but with no symbol.
+const MISSING: Child = Child::MAX;
+
+/// Decodes symbols by walking a binary trie built from the codewords.
+pub struct Decoder {
+    /// Where decoding of each symbol starts: node 0, or a leaf for a zero-length code.
+    root: Child,
+    /// Trie nodes as a flat array, each holding its children for bits 0 and 1.
+    nodes: Vec<[Child; 2]>,
+    /// Number of symbols successfully decoded so far.
+    symbols_decoded: u64,
+}
+
+impl Decoder {
+    /// Build a decoder for the given code.
+    ///
+    /// Panics if the code is not prefix-free.
+    pub fn new(code: &Code) -> Self {
+        let mut nodes = vec![[MISSING; 2]];
+        for (sym, cw) in code {
+            let mut node = 0;
+            for i in 0..cw.bit_len {
+                let bit = cw.get_bit(i) as usize;
+                let child = nodes[node][bit];
+                if i == cw.bit_len - 1 {
+                    assert_eq!(child, MISSING, "code is not prefix-free");
+                    nodes[node][bit] = LEAF | sym as Child;
+                } else if child == MISSING {
+                    nodes.push([MISSING; 2]);
+                    nodes[node][bit] = (nodes.len() - 1) as Child;
+                    node = nodes.len() - 1;
+                } else {
+                    assert_eq!(child & LEAF, 0, "code is not prefix-free");
+                    node = child as usize;
+                }
+            }
+        }
+        Decoder {
+            root: 0,
+            nodes,
+            symbols_decoded: 0,
+        }
+    }
+
+    /// `from_tree_arena` for a boxed tree.
+    pub fn from_tree(tree: &Tree, single_leaf: SingleLeaf) -> Self {
+        Self::from_tree_arena(&TreeArena::from_tree(tree), single_leaf)
+    }
+
+    /// Build a decoder for the code of a tree, like `Decoder::new(&tree.to_code_with(single_leaf))`
+    /// but without going through the codewords: the branches of the tree become the trie nodes.
+    pub fn from_tree_arena(tree: &TreeArena, single_leaf: SingleLeaf) -> Self {
+        let root = match tree.node(tree.root()) {
+            Node::Leaf(sym) => {
+                return match single_leaf {
+                    SingleLeaf::OneBit => Decoder {
+                        root: 0,
+                        nodes: vec![[LEAF | sym as Child, MISSING]],
+                        symbols_decoded: 0,
+                    },
+                    SingleLeaf::ZeroLength => Decoder::single_symbol(sym),
+                };
+            }
+            Node::Branch(..) => tree.root(),
+        };
+        // Trie nodes are numbered in the order they're reached from the root, which has to be
+        // node 0.
+        let mut trie_ids = vec![usize::MAX; tree.len()];
+        trie_ids[root] = 0;
+        let mut nodes = vec![[MISSING; 2]];
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            if let Node::Branch(left, right) = tree.node(id) {
+                for (bit, &child) in [left, right].iter().enumerate() {
+                    let entry = match tree.node(child) {
+                        Node::Leaf(sym) => LEAF | sym as Child,
+                        Node::Branch(..) => {
+                            nodes.push([MISSING; 2]);
+                            trie_ids[child] = nodes.len() - 1;
+                            stack.push(child);
+                            (nodes.len() - 1) as Child
+                        }
+                    };
+                    nodes[trie_ids[id]][bit] = entry;
+                }
+            }
+        }
+        Decoder {
+            root: 0,
+            nodes,
+            symbols_decoded: 0,
+        }
+    }
+
+    /// Build a decoder for a code with one symbol having a zero-length codeword (see
+    /// `SingleLeaf::ZeroLength`). Every decoded symbol is `sym`, and no bits are consumed.
+    pub fn single_symbol(sym: u8) -> Self {
+        Decoder {
+            root: LEAF | sym as Child,
+            nodes: vec![],
+            symbols_decoded: 0,
+        }
+    }
+
+    /// Decode exactly one symbol from the reader, leaving it positioned right after the
+    /// codeword.
+    pub fn decode_symbol<B: BitRead + ?Sized>(
+        &mut self,
+        reader: &mut B,
+    ) -> Result<u8, DecodeError> {
+        self.decode_from(self.root, reader)
+    }
+
+    /// Decode symbols until the end of the reader, which has to come right after a codeword:
+    /// for when the number of valid bits is known rather than the number of symbols (see
+    /// `BitReader::with_bit_limit`). Ending in the middle of a codeword is an `UnexpectedEof`.
+    ///
+    /// Fails with `ZeroLengthCode` for a zero-length code (see `single_symbol`), where the number
+    /// of symbols can't be told from the bits.
+    pub fn decode_to_end<B: BitRead + ?Sized>(
+        &mut self,
+        reader: &mut B,
+    ) -> Result<Vec<u8>, DecodeError> {
+        if self.root & LEAF != 0 {
+            return Err(DecodeError::ZeroLengthCode);
+        }
+        let root = self.root;
+        let mut output = vec![];
+        // The first bit of a codeword is read here, since the stream may end right before it.
+        while let Some(bit) = reader.read_bit()? {
+            output.push(self.decode_from(self.child(root, bit), reader)?);
+        }
+        Ok(output)
+    }
+
+    /// Decode symbols until the 64 bits of `marker` follow a codeword (or start the data), as
+    /// appended by `Encoder::finish_with_end_marker`. Returns the symbols and the number of bits
+    /// up to the end of the marker; whatever comes after it in `data` is ignored. Running out of
+    /// data before the marker is an `UnexpectedEof`.
+    ///
+    /// The first match ends the stream, so encoded data which contains the marker at a codeword
+    /// boundary is silently cut short there (see `Encoder::finish_with_end_marker`).
+    ///
+    /// Fails with `ZeroLengthCode` for a zero-length code (see `single_symbol`), where the number
+    /// of symbols can't be told from the bits.
+    pub fn decode_until_end_marker(
+        &mut self,
+        data: &[u8],
+        marker: u64,
+    ) -> Result<(Vec<u8>, u64), DecodeError> {
+        if self.root & LEAF != 0 {
+            return Err(DecodeError::ZeroLengthCode);
+        }
+        let mut reader = BitReader::new(data);
+        let mut output = vec![];
+        loop {
+            let position = reader.bits_read();
+            if word_at(data, position) == Some(marker) {
+                return Ok((output, position + 64));
+            }
+            output.push(self.decode_symbol(&mut reader)?);
+        }
+    }
+
+    /// Decode one symbol, starting from `child` in the trie.
+    fn decode_from<B: BitRead + ?Sized>(
+        &mut self,
+        mut child: Child,
+        reader: &mut B,
+    ) -> Result<u8, DecodeError> {
+        while child & LEAF == 0 {
+            let bit = match reader.read_bit()? {
+                Some(bit) => bit,
+                None => return Err(DecodeError::UnexpectedEof),
+            };
+            child = self.child(child, bit);
+        }
+        if child == MISSING {
+            return Err(DecodeError::InvalidCodeword);
+        }
+        self.symbols_decoded += 1;
+        Ok(child as u8)
+    }
+
+    /// The child of `node` for `bit`. Nodes only link to other nodes once they've been added, so
+    /// a node reached from the root is always in `nodes`, and with the `fast-unsafe` feature the
+    /// index isn't checked.
+    #[inline(always)]
+    fn child(&self, node: Child, bit: bool) -> Child {
+        debug_assert!((node as usize) < self.nodes.len());
+        #[cfg(feature = "fast-unsafe")]
+        #[allow(unsafe_code)]
+        // SAFETY: `node` is in `nodes`, see above.
+        unsafe {
+            self.nodes.get_unchecked(node as usize)[bit as usize]
+        }
+        #[cfg(not(feature = "fast-unsafe"))]
+        {
+            self.nodes[node as usize][bit as usize]
+        }
+    }
+
+    /// Number of symbols decoded so far (not counting failed attempts), e.g. for reporting
+    /// progress against the original length.
+    pub fn symbols_decoded(&self) -> u64 {
+        self.symbols_decoded
+    }
+}
+
+/// The 64 bits of `data` starting at bit `position`, in the order `BitReader` reads them, or
+/// `None` if the data ends before.
+fn word_at(data: &[u8], position: u64) -> Option<u64> {
+    let start = (position / 8) as usize;
+    let end = (position + 64).div_ceil(8) as usize;
+    let mut bytes = [0; 16];
+    bytes[..end - start].copy_from_slice(data.get(start..end)?);
+    Some((u128::from_le_bytes(bytes) >> (position % 8)) as u64)
+}
+
+impl SymbolDecoder for Decoder {
+    fn decode_symbol(&mut self, input: &mut dyn BitRead) -> Result<u8, DecodeError> {
+        Decoder::decode_symbol(self, input)
+    }
+}
+
+#[cfg(test)]
+fn words_to_bytes(words: &[u64]) -> Vec<u8> {
+    words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect()
+}
+
+#[test]
+fn test_decode_symbol() {
+    let input = b"appends_a_given_slice";
+    let (code, output) = encode::full_encode(input);
+    let mut decoder = Decoder::new(&code);
+    let bytes = words_to_bytes(&output);
+    let mut reader = BitReader::new(&bytes[..]);
+    let mut bits = 0;
+    for &expected in input.iter() {
+        assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), expected);
+        bits += code[expected].bit_len as u64;
+        assert_eq!(reader.bits_read(), bits);
+    }
+}
+
+#[test]
+fn test_decode_symbol_errors() {
+    let code = tree::tree_to_code(&tree::build_tree(&vec![(b'A', 2), (b'B', 1)]));
+    let mut decoder = Decoder::new(&code);
+
+    let mut reader = BitReader::new(&[][..]);
+    assert!(matches!(
+        decoder.decode_symbol(&mut reader),
+        Err(DecodeError::UnexpectedEof)
+    ));
+
+    let code = tree::tree_to_code(&tree::Tree::Leaf(b'A'));
+    let mut decoder = Decoder::new(&code);
+    let mut reader = BitReader::new(&[0b10u8][..]);
+    assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), b'A');
+    assert!(matches!(
+        decoder.decode_symbol(&mut reader),
+        Err(DecodeError::InvalidCodeword)
+    ));
+}
+
+#[test]
+fn test_decode_single_symbol_conventions() {
+    let tree = tree::Tree::Leaf(b'A');
+
+    let code = tree::tree_to_code_with(&tree, tree::SingleLeaf::OneBit);
+    let mut decoder = Decoder::new(&code);
+    let bytes = words_to_bytes(&encode::encode_with(&code, b"AAA"));
+    let mut reader = BitReader::new(&bytes[..]);
+    for _ in 0..3 {
+        assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), b'A');
+    }
+    assert_eq!(reader.bits_read(), 3);
+
+    let code = tree::tree_to_code_with(&tree, tree::SingleLeaf::ZeroLength);
+    assert_eq!(encode::encode_with(&code, b"AAA"), vec![]);
+    let mut decoder = Decoder::single_symbol(b'A');
+    let mut reader = BitReader::new(&[][..]);
+    for _ in 0..3 {
+        assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), b'A');
+    }
+    assert_eq!(reader.bits_read(), 0);
+}
+
+#[test]
+fn test_decode_zero_length_code_has_no_end() {
+    let mut decoder = Decoder::single_symbol(b'A');
+    let mut reader = BitReader::new(&[0u8][..]);
+    assert!(matches!(
+        decoder.decode_to_end(&mut reader),
+        Err(DecodeError::ZeroLengthCode)
+    ));
+    assert!(matches!(
+        decoder.decode_until_end_marker(&[0; 8], 0),
+        Err(DecodeError::ZeroLengthCode)
+    ));
+    assert_eq!(decoder.symbols_decoded(), 0);
+}
+
+#[test]
+fn test_decode_big_endian_words() {
+    // The 74 bits of `encode::test_encode_stream_flushes_partial_word` as the old host-endian
+    // writer wrote them on a big-endian host: padded to whole words, each with its bytes
+    // reversed.
+    let fixture = [
+        0xf4, 0x3f, 0x4d, 0xd8, 0xd9, 0x95, 0xf4, 0x0b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x93,
+    ];
+    let input = b"appends_a_given_slice";
+    let (code, _) = encode::full_encode(input);
+    let mut reader = BitReader::new(BigEndianWords::new(&fixture[..])).with_bit_limit(74);
+    assert_eq!(
+        Decoder::new(&code).decode_to_end(&mut reader).unwrap(),
+        input.to_vec()
+    );
+}
+
+#[test]
+fn test_decoder_from_tree_arena() {
+    let input = b"appends_a_given_slice";
+    let arena = tree::build_tree_arena(&tree::compute_frequencies(input));
+    let code = arena.to_code_with(tree::SingleLeaf::OneBit);
+    let bytes = words_to_bytes(&encode::encode_with(&code, input));
+    for decoder in &mut [
+        Decoder::new(&code),
+        Decoder::from_tree_arena(&arena, tree::SingleLeaf::OneBit),
+    ] {
+        // One trie node per branch of the tree.
+        assert_eq!(decoder.nodes.len(), (arena.len() - 1) / 2);
+        let mut reader = BitReader::new(&bytes[..]);
+        for &expected in input.iter() {
+            assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), expected);
+        }
+        let bits: usize = input.iter().map(|&sym| code[sym].bit_len).sum();
+        assert_eq!(reader.bits_read(), bits as u64);
+    }
+
+    let leaf = tree::TreeArena::from_tree(&tree::Tree::Leaf(b'A'));
+    let mut decoder = Decoder::from_tree_arena(&leaf, tree::SingleLeaf::OneBit);
+    let mut reader = BitReader::new(&[0b10u8][..]);
+    assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), b'A');
+    assert!(matches!(
+        decoder.decode_symbol(&mut reader),
+        Err(DecodeError::InvalidCodeword)
+    ));
+    let mut decoder = Decoder::from_tree_arena(&leaf, tree::SingleLeaf::ZeroLength);
+    let mut reader = BitReader::new(&[][..]);
+    assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), b'A');
+    assert_eq!(reader.bits_read(), 0);
+}
+
+/// Decode a symbol by walking the boxed tree, for comparison.
+#[cfg(test)]
+fn decode_with_tree<B: BitRead>(tree: &Tree, reader: &mut B) -> Result<u8, DecodeError> {
+    let mut node = tree;
+    loop {
+        match node {
+            Tree::Leaf(sym) => return Ok(*sym),
+            Tree::Branch(left, right) => {
+                node = match reader.read_bit()? {
+                    Some(false) => left,
+                    Some(true) => right,
+                    None => return Err(DecodeError::UnexpectedEof),
+                };
+            }
+        }
+    }
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_decoder_matches_tree_walk(
+    frequencies: Vec<(u8, u32)>,
+    input: Vec<u8>,
+    flips: Vec<u16>,
+    truncate: u16,
+) -> quickcheck::TestResult {
+    let frequencies: tree::Frequencies = frequencies
+        .into_iter()
+        .map(|(sym, freq)| (sym, freq as u64))
+        .collect();
+    if frequencies.iter().all(|&(_, freq)| freq == 0) {
+        return quickcheck::TestResult::discard();
+    }
+    let tree = tree::build_tree(&frequencies);
+    let code = tree::tree_to_code_with(&tree, SingleLeaf::ZeroLength);
+    let symbols: Vec<u8> = tree::nonzero_frequencies(&frequencies)
+        .map(|(sym, _)| sym)
+        .collect();
+    let input: Vec<u8> = input
+        .iter()
+        .map(|&sym| symbols[sym as usize % symbols.len()])
+        .collect();
+    // Corrupted and cut short, so that both also have to fail the same way.
+    let mut bytes = words_to_bytes(&encode::encode_with(&code, &input));
+    if !bytes.is_empty() {
+        for flip in flips {
+            let bit = flip as usize % (bytes.len() * 8);
+            bytes[bit / 8] ^= 1 << (bit % 8);
+        }
+        bytes.truncate(bytes.len() - truncate as usize % bytes.len());
+    }
+
+    let mut decoder = Decoder::from_tree(&tree, SingleLeaf::ZeroLength);
+    let mut reader = BitReader::new(&bytes[..]);
+    let mut tree_reader = BitReader::new(&bytes[..]);
+    for _ in 0..=input.len() {
+        let result = decoder.decode_symbol(&mut reader);
+        let expected = decode_with_tree(&tree, &mut tree_reader);
+        if format!("{:?}", result) != format!("{:?}", expected) {
+            return quickcheck::TestResult::failed();
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    quickcheck::TestResult::from_bool(reader.bits_read() == tree_reader.bits_read())
+}
+
+#[test]
+fn test_decode_deep_code() {
+    // Codewords of up to 255 bits, the deepest a tree over bytes gets.
+    let tree = encode::degenerate_tree(tree::NUM_SYMBOLS);
+    let code = tree::tree_to_code(&tree);
+    let input: Vec<u8> = (0..=255).rev().chain(0..=255).collect();
+    let bytes = words_to_bytes(&encode::encode_with(&code, &input));
+    let mut decoder = Decoder::from_tree(&tree, SingleLeaf::OneBit);
+    let mut reader = BitReader::new(&bytes[..]);
+    let mut tree_reader = BitReader::new(&bytes[..]);
+    for &expected in &input {
+        assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), expected);
+        assert_eq!(decode_with_tree(&tree, &mut tree_reader).unwrap(), expected);
+        assert_eq!(reader.bits_read(), tree_reader.bits_read());
+    }
+    assert_eq!(decoder.symbols_decoded(), input.len() as u64);
+}
+
+#[test]
+fn test_symbols_decoded() {
+    let input = b"appends_a_given_slice";
+    let (code, output) = encode::full_encode(input);
+    let mut decoder = Decoder::new(&code);
+    assert_eq!(decoder.symbols_decoded(), 0);
+    let bytes = words_to_bytes(&output);
+    // Cut the stream in the middle of the input, so that decoding stops partway.
+    let bits: usize = input[..10].iter().map(|&sym| code[sym].bit_len).sum();
+    let mut reader = BitReader::new(&bytes[..bits / 8]);
+    let mut decoded = vec![];
+    while let Ok(sym) = decoder.decode_symbol(&mut reader) {
+        decoded.push(sym);
+        assert_eq!(decoder.symbols_decoded(), decoded.len() as u64);
+    }
+    assert!(!decoded.is_empty() && decoded.len() < 10);
+    assert_eq!(decoder.symbols_decoded(), decoded.len() as u64);
+    assert_eq!(&decoded[..], &input[..decoded.len()]);
+}
+
+#[test]
+fn test_decode_to_end() {
+    // `a` is `0`, so zero padding bits would decode as more `a`s.
+    let code = tree::code_from_lengths(&[(b'a', 1), (b'b', 2), (b'c', 2)]);
+    assert_eq!(code[b'a'], "0".parse().unwrap());
+    let mut input = b"bc".repeat(16);
+    input.extend_from_slice(b"bab");
+    let mut encoder = encode::Encoder::new(&code);
+    let mut words = vec![0; 2];
+    assert_eq!(encoder.encode(&input, &mut words), Ok((input.len(), 1)));
+    let (last_word, valid_bits) = encoder.finish().unwrap();
+    assert_eq!(valid_bits, 5);
+    words[1] = last_word;
+    let bytes = words_to_bytes(&words);
+
+    let mut decoder = Decoder::new(&code);
+    let mut reader = BitReader::new(&bytes[..]).with_bit_limit(64 + 5);
+    assert_eq!(decoder.decode_to_end(&mut reader).unwrap(), input);
+    assert_eq!(reader.bits_read(), 64 + 5);
+
+    // Without the limit, the 59 padding bits come out as `a`s.
+    let mut reader = BitReader::new(&bytes[..]);
+    let mut expected = input.clone();
+    expected.extend_from_slice(&[b'a'; 59]);
+    assert_eq!(decoder.decode_to_end(&mut reader).unwrap(), expected);
+
+    // A limit in the middle of the last `b` cuts a codeword short.
+    let mut reader = BitReader::new(&bytes[..]).with_bit_limit(64 + 4);
+    assert!(matches!(
+        decoder.decode_to_end(&mut reader),
+        Err(DecodeError::UnexpectedEof)
+    ));
+}
+
+#[test]
+fn test_decode_until_end_marker() {
+    let code = tree::code_from_lengths(&[(b'a', 1), (b'b', 2), (b'c', 2)]);
+    let mut decoder = Decoder::new(&code);
+    // 8 bits per repeat, with at most 4 one bits in a row.
+    for (repeats, marker) in &[(10, 0x1234_5678_9abc_def0), (8, encode::END_MARKER)] {
+        let input = b"abcab".repeat(*repeats);
+        let mut encoder = encode::Encoder::new(&code);
+        let mut words = vec![0; 2];
+        let (_, words_written) = encoder.encode(&input, &mut words).unwrap();
+        words.truncate(words_written);
+        let (word, rest) = encoder.finish_with_end_marker(*marker);
+        words.push(word);
+        let mut bytes = words_to_bytes(&words);
+        match rest {
+            Some((rest, bits)) => {
+                assert_eq!(bits, 16);
+                bytes.extend_from_slice(&rest.to_le_bytes()[..2]);
+            }
+            None => assert_eq!(*repeats, 8),
+        }
+
+        let data_bits = *repeats as u64 * 8;
+        let expected = (input.clone(), data_bits + 64);
+        assert_eq!(
+            decoder.decode_until_end_marker(&bytes, *marker).unwrap(),
+            expected
+        );
+        // Anything after the marker is ignored.
+        bytes.extend_from_slice(&[0xff; 5]);
+        assert_eq!(
+            decoder.decode_until_end_marker(&bytes, *marker).unwrap(),
+            expected
+        );
+        // Without the whole marker, the data runs out.
+        let cut = (data_bits as usize + 63) / 8;
+        assert!(matches!(
+            decoder.decode_until_end_marker(&bytes[..cut], *marker),
+            Err(DecodeError::UnexpectedEof)
+        ));
+    }
+}
+
+#[test]
+fn test_decode_walks_every_node() {
+    // Every node of the trie is reached, through both constructors, as are a missing child and
+    // the end of the input. The lookups of `fast-unsafe` rely on the trie only linking to nodes
+    // it has; small enough to run under Miri.
+    let tree = encode::degenerate_tree(40);
+    let code = tree::tree_to_code(&tree);
+    let input: Vec<u8> = (0..40).rev().collect();
+    let mut bytes = vec![];
+    encode::encode_stream(&code, &input[..], &mut bytes).unwrap();
+    for decoder in &mut [
+        Decoder::new(&code),
+        Decoder::from_tree(&tree, SingleLeaf::OneBit),
+    ] {
+        let mut reader = BitReader::new(&bytes[..]);
+        for &expected in &input {
+            assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), expected);
+        }
+        let mut reader = BitReader::new(&[0xff][..]);
+        assert!(matches!(
+            decoder.decode_symbol(&mut reader),
+            Err(DecodeError::UnexpectedEof)
+        ));
+    }
+
+    let code = tree::code_from_lengths(&[(b'a', 1), (b'b', 2)]);
+    let mut reader = BitReader::new(&[0b0000_0011u8][..]);
+    assert!(matches!(
+        Decoder::new(&code).decode_to_end(&mut reader),
+        Err(DecodeError::InvalidCodeword)
+    ));
+}
+
+#[test]
+fn test_decode_incomplete_code() {
+    // a: 0, b: 10, and nothing starts with 11.
+    let code = tree::code_from_lengths(&[(b'a', 1), (b'b', 2)]);
+    assert!(!code.is_complete());
+    let mut decoder = Decoder::new(&code);
+    let mut reader = BitReader::new(&[0b0011_0010u8][..]);
+    for &expected in b"aba" {
+        assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), expected);
+    }
+    assert!(matches!(
+        decoder.decode_symbol(&mut reader),
+        Err(DecodeError::InvalidCodeword)
+    ));
+    assert_eq!(decoder.symbols_decoded(), 3);
+}
@@ -1,99 +1,1095 @@
-use crate::tree::Code;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bitstream::{read_chunk, with_context, BitWrite, Buffers};
+use crate::coder::SymbolEncoder;
+
+use crate::tree::{Code, CompactCode, MAX_CODEWORD_BITS, NUM_SYMBOLS};
 
 #[cfg(test)]
-use crate::tree;
+use crate::bitstream;
+#[cfg(test)]
+use crate::tree::{self, Codeword};
 
 const WORD_SIZE_IN_BITS: usize = 64;
 
-pub struct Encoder<'a> {
-    code: &'a Code,
+/// An output buffer of at least this many words can always hold the next codeword, so `encode`
+/// never fails with `OutputTooSmall` when given one.
+// Up to `WORD_SIZE_IN_BITS - 1` bits pending in the buffer, plus the longest codeword.
+pub const MIN_OUTPUT_WORDS: usize = MAX_CODEWORD_BITS.div_ceil(WORD_SIZE_IN_BITS);
+
+/// Returned by `Encoder::encode` when the output buffer can't hold even the next codeword.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct OutputTooSmall {
+    /// Number of words needed to encode the next symbol.
+    pub needed_words: usize,
+}
+
+impl std::fmt::Display for OutputTooSmall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "output buffer too small, {} words needed",
+            self.needed_words
+        )
+    }
+}
+
+impl std::error::Error for OutputTooSmall {}
+
+/// The next symbol has no codeword, i.e. it didn't occur in the data the code was built for.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct UncodedSymbol {
+    pub symbol: u8,
+    /// Offset of the symbol in all of the input given to this encoder.
+    pub position: u64,
+}
+
+impl std::fmt::Display for UncodedSymbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "symbol {} at input offset {} has no codeword",
+            self.symbol, self.position
+        )
+    }
+}
+
+impl std::error::Error for UncodedSymbol {}
+
+/// Returned by `Encoder::encode` when no symbol could be encoded.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EncodeError {
+    OutputTooSmall(OutputTooSmall),
+    UncodedSymbol(UncodedSymbol),
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EncodeError::OutputTooSmall(err) => err.fmt(f),
+            EncodeError::UncodedSymbol(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<OutputTooSmall> for EncodeError {
+    fn from(err: OutputTooSmall) -> Self {
+        EncodeError::OutputTooSmall(err)
+    }
+}
+
+/// Returned by `Encoder::encode_within` when no symbol could be encoded.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EncodeWithinError {
+    OutputTooSmall(OutputTooSmall),
+    UncodedSymbol(UncodedSymbol),
+    /// The next symbol would make the output exceed the byte budget.
+    BudgetExhausted,
+}
+
+impl std::fmt::Display for EncodeWithinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EncodeWithinError::OutputTooSmall(err) => err.fmt(f),
+            EncodeWithinError::UncodedSymbol(err) => err.fmt(f),
+            EncodeWithinError::BudgetExhausted => f.write_str("output size budget exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeWithinError {}
+
+impl From<EncodeError> for EncodeWithinError {
+    fn from(err: EncodeError) -> Self {
+        match err {
+            EncodeError::OutputTooSmall(err) => EncodeWithinError::OutputTooSmall(err),
+            EncodeError::UncodedSymbol(err) => EncodeWithinError::UncodedSymbol(err),
+        }
+    }
+}
+
+/// Counters of an `Encoder`, see `Encoder::stats`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct EncoderStats {
+    pub symbols_encoded: u64,
+    /// Output words filled, including the partial word returned by `finish`.
+    pub words_emitted: u64,
+    /// Time spent in `encode`, if enabled with `Encoder::with_timing`.
+    pub time: Option<Duration>,
+}
+
+/// Where the encoding of a group is in the output of an encoder, see `Encoder::encode_group`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct GroupInfo {
+    /// Position of the first bit, counting from the first bit the encoder output.
+    pub start_bit: u64,
+    pub bit_len: u64,
+}
+
+/// Length in `Encoder::packed` of symbols that take the general path.
+const NOT_PACKED: u8 = u8::MAX;
+
+/// Codes whose codewords are all at most this long are encoded by `encode_short`.
+const SHORT_CODEWORD_BITS: usize = 16;
+
+/// What an `Encoder` looks codewords up in, built from a `Code` once. Encoders created from the
+/// same tables with `Encoder::with_tables` share them, so that each one only holds its own
+/// state, e.g. with an encoder per connection.
+pub struct EncoderTables {
+    code: CompactCode,
+    /// For each symbol, its codeword as a single word and its length, if it fits in a word, or
+    /// `NOT_PACKED`. Denser than `code`, for the fast path of `encode`. Empty codewords are
+    /// `NOT_PACKED` unless they're legitimate, so that the general path can stop at them.
+    packed: [(u64, u8); NUM_SYMBOLS],
+    /// Like `packed`, with 16-bit codewords, if the whole code fits in them (see
+    /// `SHORT_CODEWORD_BITS`). Then every symbol takes the tighter loop of `encode_short`.
+    short: Option<[(u16, u8); NUM_SYMBOLS]>,
+    /// Whether empty codewords are legitimate, because the whole code is empty (see
+    /// `SingleLeaf::ZeroLength`). Otherwise they mark symbols that can't be encoded.
+    zero_length_code: bool,
+}
+
+impl EncoderTables {
+    pub fn new(code: &Code) -> Self {
+        let code = CompactCode::new(code);
+        let zero_length_code = code.max_len() == 0;
+        let mut packed = [(0, NOT_PACKED); NUM_SYMBOLS];
+        for (sym, entry) in packed.iter_mut().enumerate() {
+            let bit_len = code.bit_len(sym as u8);
+            if bit_len <= WORD_SIZE_IN_BITS && (bit_len > 0 || zero_length_code) {
+                *entry = (
+                    code.words(sym as u8).first().copied().unwrap_or(0),
+                    bit_len as u8,
+                );
+            }
+        }
+        let short = if code.max_len() <= SHORT_CODEWORD_BITS {
+            let mut short = [(0, NOT_PACKED); NUM_SYMBOLS];
+            for (entry, &(bits, bit_len)) in short.iter_mut().zip(packed.iter()) {
+                *entry = (bits as u16, bit_len);
+            }
+            Some(short)
+        } else {
+            None
+        };
+        EncoderTables {
+            code,
+            packed,
+            short,
+            zero_length_code,
+        }
+    }
+}
+
+pub struct Encoder {
+    tables: Arc<EncoderTables>,
     /// Buffer of bits to output.
     buf: u64,
     /// Number of bits in buffer.
     offset: usize,
+    /// Number of words filled in output buffers so far.
+    words_written: u64,
+    /// Number of input symbols consumed so far.
+    symbols_encoded: u64,
+    /// Number of times each symbol was encoded, and the number of bits it contributed.
+    /// Only tallied when enabled with `with_symbol_stats`.
+    symbol_stats: Option<Box<[(u64, u64); 256]>>,
+    /// Time spent in `encode`, if enabled with `with_timing`.
+    time: Option<Duration>,
+    /// `symbols_encoded` and `words_written` at the last `reset`, which `stats` counts from.
+    stats_base: (u64, u64),
+    /// Output buffer of `encode_to_writer`, allocated on first use and kept for later calls.
+    write_buf: Vec<u64>,
+    /// Number of words in `write_buf` not yet written out.
+    write_buf_len: usize,
 }
 
-impl<'a> Encoder<'a> {
-    pub fn new(code: &'a Code) -> Self {
+impl Encoder {
+    pub fn new(code: &Code) -> Self {
+        Self::with_tables(Arc::new(EncoderTables::new(code)))
+    }
+
+    /// Create an encoder that shares `tables` with other encoders, rather than building its own.
+    pub fn with_tables(tables: Arc<EncoderTables>) -> Self {
         Encoder {
-            code,
+            tables,
             buf: 0,
             offset: 0,
+            words_written: 0,
+            symbols_encoded: 0,
+            symbol_stats: None,
+            time: None,
+            stats_base: (0, 0),
+            write_buf: Vec::new(),
+            write_buf_len: 0,
+        }
+    }
+
+    /// Create an encoder which also tallies per-symbol statistics (see `symbol_stats`).
+    ///
+    /// The tallies are updated once per `encode` call, outside of the encoding loop.
+    pub fn with_symbol_stats(code: &Code) -> Self {
+        Encoder {
+            symbol_stats: Some(Box::new([(0, 0); 256])),
+            ..Self::new(code)
+        }
+    }
+
+    /// Create an encoder which also measures the time spent encoding (see `stats`).
+    ///
+    /// The clock is read twice per `encode` call, so chunks should be large enough for that not
+    /// to matter. Without this, `stats` has no time, and the clock isn't read at all.
+    pub fn with_timing(code: &Code) -> Self {
+        Encoder {
+            time: Some(Duration::ZERO),
+            ..Self::new(code)
+        }
+    }
+
+    /// Create an encoder which resumes packing into a word another writer left partially filled,
+    /// with `offset` bits in `buf` (LSB first, as returned by `peek_buffered` or `finish`). Bits of
+    /// `buf` at and above `offset` are ignored.
+    ///
+    /// The initial bits count towards `encoded_bits`, and are part of the first word written
+    /// out. Panics if `offset` is not less than 64.
+    pub fn with_state(code: &Code, buf: u64, offset: usize) -> Self {
+        assert!(
+            offset < WORD_SIZE_IN_BITS,
+            "offset {} is not within a word",
+            offset
+        );
+        Encoder {
+            buf: buf & ((1 << offset) - 1),
+            offset,
+            ..Self::new(code)
+        }
+    }
+
+    /// For each symbol, the number of times it was encoded and the total number of bits it
+    /// contributed to the output.
+    ///
+    /// All zeros unless the encoder was created with `with_symbol_stats`.
+    pub fn symbol_stats(&self) -> [(u64, u64); 256] {
+        match &self.symbol_stats {
+            Some(stats) => **stats,
+            None => [(0, 0); 256],
+        }
+    }
+
+    /// Counters of the work done since the encoder was created or `reset`.
+    pub fn stats(&self) -> EncoderStats {
+        EncoderStats {
+            symbols_encoded: self.symbols_encoded - self.stats_base.0,
+            words_emitted: self.words_written - self.stats_base.1,
+            time: self.time,
+        }
+    }
+
+    /// Start the counters of `stats` from zero again, and the tallies of `symbol_stats` if
+    /// enabled. Encoding itself is unaffected, e.g. `encoded_bits` still counts everything.
+    pub fn reset(&mut self) {
+        self.stats_base = (self.symbols_encoded, self.words_written);
+        if let Some(time) = &mut self.time {
+            *time = Duration::ZERO;
+        }
+        if let Some(stats) = &mut self.symbol_stats {
+            **stats = [(0, 0); 256];
         }
     }
 
     /// Encode a chunk of the input to the given output buffer.
     /// Stops when either input is exhausted or buffer is full.
     /// Returns the number of input bytes consumed, and the number of u64 words filled in the buffer.
-    pub fn encode(&mut self, input: &[u8], output: &mut [u64]) -> (usize, usize) {
+    ///
+    /// When the buffer fills up, the caller should write out the filled words and call again
+    /// with the rest of the input. If not even one symbol could be encoded, because the buffer is
+    /// too small for its codeword, returns an error instead, so that the caller doesn't loop
+    /// forever. This can't happen for buffers of at least `MIN_OUTPUT_WORDS` words.
+    ///
+    /// Encoding also stops before a symbol with an empty codeword, which would otherwise vanish
+    /// from the output without a trace; once it's the next symbol, returns
+    /// `EncodeError::UncodedSymbol`. (Except when all codewords are empty, as for a single symbol
+    /// with `SingleLeaf::ZeroLength`: then everything encodes to nothing.)
+    ///
+    /// Never allocates.
+    pub fn encode(
+        &mut self,
+        input: &[u8],
+        output: &mut [u64],
+    ) -> Result<(usize, usize), EncodeError> {
+        let start = self.time.map(|_| Instant::now());
+        let (input_consumed, output_len) = self.encode_symbols(input, output);
+        if let (Some(time), Some(start)) = (&mut self.time, start) {
+            *time += start.elapsed();
+        }
+        if input_consumed == 0 && !input.is_empty() {
+            let bit_len = self.tables.code.bit_len(input[0]);
+            if bit_len == 0 {
+                return Err(EncodeError::UncodedSymbol(UncodedSymbol {
+                    symbol: input[0],
+                    position: self.symbols_encoded,
+                }));
+            }
+            return Err(EncodeError::OutputTooSmall(OutputTooSmall {
+                needed_words: (self.offset + bit_len) / WORD_SIZE_IN_BITS,
+            }));
+        }
+        self.words_written += output_len as u64;
+        self.symbols_encoded += input_consumed as u64;
+        if let Some(stats) = &mut self.symbol_stats {
+            for &sym in &input[..input_consumed] {
+                stats[sym as usize].0 += 1;
+                stats[sym as usize].1 += self.tables.code.bit_len(sym) as u64;
+            }
+        }
+        Ok((input_consumed, output_len))
+    }
+
+    /// Encode all of `input` as one group, e.g. a record, and report where its bits are in the
+    /// whole output of this encoder, for indexing the groups. Returns the number of words filled
+    /// in `output`, like `encode`.
+    ///
+    /// Unlike `encode`, either the whole group is encoded or nothing is: if `output` can't hold
+    /// it, returns `EncodeError::OutputTooSmall` with the number of words needed, and if it has a
+    /// symbol without a codeword, `EncodeError::UncodedSymbol`.
+    pub fn encode_group(
+        &mut self,
+        input: &[u8],
+        output: &mut [u64],
+    ) -> Result<(usize, GroupInfo), EncodeError> {
+        let mut bit_len = 0;
+        for (index, &sym) in input.iter().enumerate() {
+            let cw_len = self.tables.code.bit_len(sym);
+            if cw_len == 0 && !self.tables.zero_length_code {
+                return Err(EncodeError::UncodedSymbol(UncodedSymbol {
+                    symbol: sym,
+                    position: self.symbols_encoded + index as u64,
+                }));
+            }
+            bit_len += cw_len as u64;
+        }
+        let needed_words = ((self.offset as u64 + bit_len) / WORD_SIZE_IN_BITS as u64) as usize;
+        if needed_words > output.len() {
+            return Err(EncodeError::OutputTooSmall(OutputTooSmall { needed_words }));
+        }
+        let start_bit = self.encoded_bits();
+        let (input_consumed, output_len) = self.encode(input, output)?;
+        debug_assert_eq!(input_consumed, input.len());
+        Ok((output_len, GroupInfo { start_bit, bit_len }))
+    }
+
+    /// Like `encode`, but also stops before the total output of this encoder would exceed
+    /// `max_bytes` bytes, counting only the bytes of the final partial word that hold encoded
+    /// bits, like `encode_stream` does.
+    ///
+    /// The budget is logical, it's independent of the size of `output`. Once it's exhausted,
+    /// returns `EncodeWithinError::BudgetExhausted`.
+    pub fn encode_within(
+        &mut self,
+        input: &[u8],
+        output: &mut [u64],
+        max_bytes: u64,
+    ) -> Result<(usize, usize), EncodeWithinError> {
+        let max_bits = max_bytes * 8;
+        let mut bits = self.encoded_bits();
+        let fitting = input
+            .iter()
+            .take_while(|&&sym| {
+                bits += self.tables.code.bit_len(sym) as u64;
+                bits <= max_bits
+            })
+            .count();
+        if fitting == 0 && !input.is_empty() {
+            return Err(EncodeWithinError::BudgetExhausted);
+        }
+        Ok(self.encode(&input[..fitting], output)?)
+    }
+
+    /// Total number of bits encoded so far, including the ones not yet written out.
+    pub fn encoded_bits(&self) -> u64 {
+        self.words_written * WORD_SIZE_IN_BITS as u64 + self.offset as u64
+    }
+
+    fn encode_symbols(&mut self, input: &[u8], output: &mut [u64]) -> (usize, usize) {
+        let tables = &*self.tables;
+        if let Some(short) = &tables.short {
+            return encode_short(short, &mut self.buf, &mut self.offset, input, output);
+        }
         let mut num_output_words_written = 0;
 
         for (index, &sym) in input.iter().enumerate() {
-            let cw = &self.code[sym];
+            // Fast path for codewords fitting in a single word, which is almost all of them: at
+            // most one word is filled, and there's no need for the loop below.
+            let (bits, bit_len) = tables.packed[sym as usize];
+            if bit_len != NOT_PACKED {
+                let new_offset = self.offset + bit_len as usize;
+                if new_offset < WORD_SIZE_IN_BITS {
+                    self.buf |= bits << self.offset;
+                    self.offset = new_offset;
+                } else {
+                    if num_output_words_written == output.len() {
+                        return (index, num_output_words_written);
+                    }
+                    store(
+                        output,
+                        num_output_words_written,
+                        self.buf | (bits << self.offset),
+                    );
+                    num_output_words_written += 1;
+                    self.buf = if self.offset == 0 {
+                        0
+                    } else {
+                        bits >> (WORD_SIZE_IN_BITS - self.offset)
+                    };
+                    self.offset = new_offset - WORD_SIZE_IN_BITS;
+                }
+                continue;
+            }
+
+            let (cw_len, cw_words) = (tables.code.bit_len(sym), tables.code.words(sym));
+            if cw_len == 0 {
+                // Not a legitimate empty codeword, or it would have been packed.
+                return (index, num_output_words_written);
+            }
 
             // Number of full words we'll copy.
             // Note: (W * num_words) may be larger than number of full words of codeword.
-            // This counts codeword bits and leftovers in the buffer together.
-            let num_words = (self.offset + cw.bit_len) / WORD_SIZE_IN_BITS;
+            // This counts codeword bits and leftovers in the buffer together. Since the offset is
+            // less than W, it's still at most the number of words the codeword spans, so each
+            // copied word comes from the codeword.
+            let num_words = (self.offset + cw_len) / WORD_SIZE_IN_BITS;
+            debug_assert!(num_words <= cw_words.len());
 
             if num_output_words_written + num_words > output.len() {
                 return (index, num_output_words_written);
             }
 
             // Copy all full words.
-            for w in 0..num_words {
-                output[num_output_words_written] = self.buf | (cw.bits[w] << self.offset);
+            for &word in &cw_words[..num_words] {
+                store(
+                    output,
+                    num_output_words_written,
+                    self.buf | (word << self.offset),
+                );
                 num_output_words_written += 1;
-                self.buf = cw.bits[w] >> (WORD_SIZE_IN_BITS - self.offset);
+                // With an empty buffer the whole word was just written out; shifting by the
+                // full word size would overflow.
+                self.buf = if self.offset == 0 {
+                    0
+                } else {
+                    word >> (WORD_SIZE_IN_BITS - self.offset)
+                };
             }
 
             // At this point, we know:
-            // - we have consumed num_words*W bits of codeword.
-            // - we know that (cw->len - num_words*W) < W. (Proof?)
+            // - we have written num_words*W bits, the first `offset` of which came from the
+            //   buffer, so we have consumed num_words*W - offset bits of codeword.
+            // - the remaining (cw_len + offset - num_words*W) bits are fewer than W, by the
+            //   definition of num_words.
+            // - the buffer holds the `offset` codeword bits preceding bit num_words*W, and the
+            //   rest of the codeword is exactly the contents of cw_words[num_words].
             // So we can copy one last part of the codeword, into the partially filled buffer.
-            self.buf |= cw.bits[num_words] << self.offset;
+            // If the codeword ends exactly at the end of its last word, there's nothing left.
+            debug_assert!(self.offset + cw_len - num_words * WORD_SIZE_IN_BITS < WORD_SIZE_IN_BITS);
+            if let Some(&word) = cw_words.get(num_words) {
+                self.buf |= word << self.offset;
+            }
 
             // Shift the offset by codeword len.
-            self.offset = (self.offset + cw.bit_len) % WORD_SIZE_IN_BITS;
+            self.offset = (self.offset + cw_len) % WORD_SIZE_IN_BITS;
+            // Codeword words are zero past the codeword, so the buffer still holds nothing
+            // above the offset.
+            debug_assert_eq!(self.buf >> self.offset, 0);
         }
 
         (input.len(), num_output_words_written)
     }
 
-    pub fn finish(&mut self, output: &mut [u64]) -> usize {
-        // One last (partial) output word.
+    /// The bits not yet written out, and how many there are, without flushing them: unlike
+    /// `finish`, the encoder can go on encoding afterwards.
+    pub fn peek_buffered(&self) -> (u64, usize) {
+        (self.buf, self.offset)
+    }
+
+    /// Flush the bits not yet written out: returns the last, partially filled output word and
+    /// the number of valid bits in it (the rest are zero), or `None` if there are no pending bits.
+    ///
+    /// The encoder is left empty, so calling `finish` again returns `None`.
+    pub fn finish(&mut self) -> Option<(u64, usize)> {
         if self.offset > 0 {
-            output[0] = self.buf;
+            let pending = (self.buf, self.offset);
+            self.words_written += 1;
             self.offset = 0;
             self.buf = 0;
-            1
+            Some(pending)
         } else {
+            None
+        }
+    }
+
+    /// Like `finish`, but first append the 64 bits of `marker` right after the last codeword, for
+    /// formats that find the end of the stream by looking for it rather than by a stored length
+    /// (see `Decoder::decode_until_end_marker`). The marker always completes one more output word,
+    /// which is returned first and goes before what's left, if anything.
+    ///
+    /// The marker is ambiguous if the encoded data can contain the same 64 bits starting at a
+    /// codeword boundary: the decoder then stops early, at the first of them, and nothing detects
+    /// it. With `END_MARKER`, that's any run of codewords with 64 one bits in a row, e.g. repeats
+    /// of the symbol with the all-ones codeword, which every complete code has. Use it only where
+    /// the data is known not to contain such a run, or prefer storing the length.
+    pub fn finish_with_end_marker(&mut self, marker: u64) -> (u64, Option<(u64, usize)>) {
+        let word = self.buf | (marker << self.offset);
+        self.buf = if self.offset == 0 {
             0
+        } else {
+            marker >> (WORD_SIZE_IN_BITS - self.offset)
+        };
+        self.words_written += 1;
+        (word, self.finish())
+    }
+
+    /// Encode the input into an internal buffer of `WRITER_BUF_WORDS` words, writing it to
+    /// `output` (see `write_words`) whenever it fills up. Words left in the buffer are written
+    /// by later calls, or by `finish_to_writer`, which must be called at the end.
+    ///
+    /// Returns the number of bytes written to `output` by this call. Write errors get the context
+    /// "writing output", and symbols without a codeword fail with `ErrorKind::InvalidData`
+    /// wrapping an `UncodedSymbol`. After an error, it's unknown how much was written, and the
+    /// encoder shouldn't be used further.
+    ///
+    /// The buffer is allocated by the first call, unless it came from `Buffers` (see
+    /// `encode_stream_with`). After that, neither this nor `finish_to_writer` allocates, except
+    /// for the messages of errors, so encoding chunk after chunk is allocation-free as long as
+    /// `output` is.
+    pub fn encode_to_writer<W: Write>(&mut self, input: &[u8], output: &mut W) -> io::Result<u64> {
+        let mut buf = std::mem::take(&mut self.write_buf);
+        if buf.is_empty() {
+            buf = vec![0; WRITER_BUF_WORDS];
+        }
+        let result = self.encode_to_writer_with(input, output, &mut buf);
+        self.write_buf = buf;
+        result
+    }
+
+    fn encode_to_writer_with<W: Write>(
+        &mut self,
+        mut input: &[u8],
+        output: &mut W,
+        buf: &mut [u64],
+    ) -> io::Result<u64> {
+        let mut output_bytes = 0;
+        while !input.is_empty() {
+            if self.write_buf_len == buf.len() {
+                output_bytes += self.flush_write_buf(output, buf)?;
+            }
+            match self.encode(input, &mut buf[self.write_buf_len..]) {
+                Ok((input_consumed, output_len)) => {
+                    input = &input[input_consumed..];
+                    self.write_buf_len += output_len;
+                }
+                // Only when the buffer is nearly full, so flushing makes room.
+                Err(EncodeError::OutputTooSmall(_)) => {
+                    output_bytes += self.flush_write_buf(output, buf)?;
+                }
+                Err(err @ EncodeError::UncodedSymbol(_)) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+        }
+        Ok(output_bytes)
+    }
+
+    fn flush_write_buf<W: Write>(&mut self, output: &mut W, buf: &[u64]) -> io::Result<u64> {
+        write_words(output, &buf[..self.write_buf_len])
+            .map_err(|err| with_context(err, "writing output"))?;
+        let output_bytes = self.write_buf_len as u64 * 8;
+        self.write_buf_len = 0;
+        Ok(output_bytes)
+    }
+
+    /// Write out what `encode_to_writer` has buffered, and the bits not yet written out (see
+    /// `finish`). Of the final partial word, only the bytes holding encoded bits are written, so
+    /// the output is padded with less than a byte of zero bits.
+    ///
+    /// Returns the number of bytes written.
+    pub fn finish_to_writer<W: Write>(&mut self, output: &mut W) -> io::Result<u64> {
+        let buf = std::mem::take(&mut self.write_buf);
+        let result = self.flush_write_buf(output, &buf);
+        self.write_buf = buf;
+        let mut output_bytes = result?;
+        if let Some((word, bits)) = self.finish() {
+            let len = bits.div_ceil(8);
+            output
+                .write_all(&word.to_le_bytes()[..len])
+                .map_err(|err| with_context(err, "writing output"))?;
+            output_bytes += len as u64;
         }
+        Ok(output_bytes)
     }
 }
 
+/// `output[index] = word`, for the encoding loops, which check that `index < output.len()`
+/// before encoding a symbol that fills a word. With the `fast-unsafe` feature, the check isn't
+/// made again.
+#[inline(always)]
+fn store(output: &mut [u64], index: usize, word: u64) {
+    debug_assert!(index < output.len());
+    #[cfg(feature = "fast-unsafe")]
+    #[allow(unsafe_code)]
+    // SAFETY: `index < output.len()`, see above.
+    unsafe {
+        *output.get_unchecked_mut(index) = word;
+    }
+    #[cfg(not(feature = "fast-unsafe"))]
+    {
+        output[index] = word;
+    }
+}
+
+/// Number of symbols `encode_short` combines per iteration. Their codewords take at most
+/// `SHORT_UNROLL * SHORT_CODEWORD_BITS` = 64 bits together, so they fit in one word.
+const SHORT_UNROLL: usize = 4;
+
+/// `Encoder::encode_symbols` for codes of at most `SHORT_CODEWORD_BITS` bits. Four symbols at a
+/// time are combined into one word before touching the buffer, which shortens the dependency
+/// chain on it. A group with a symbol missing from the code, or one that would fill a word
+/// without room in `output`, and the tail of `input` go through `encode_short_scalar`.
+fn encode_short(
+    short: &[(u16, u8); NUM_SYMBOLS],
+    buf: &mut u64,
+    offset: &mut usize,
+    input: &[u8],
+    output: &mut [u64],
+) -> (usize, usize) {
+    let (mut bits_buf, mut bits_offset) = (*buf, *offset);
+    let mut num_output_words_written = 0;
+    let mut index = 0;
+    for syms in input.chunks_exact(SHORT_UNROLL) {
+        let (bits0, len0) = short[syms[0] as usize];
+        let (bits1, len1) = short[syms[1] as usize];
+        let (bits2, len2) = short[syms[2] as usize];
+        let (bits3, len3) = short[syms[3] as usize];
+        if len0 == NOT_PACKED || len1 == NOT_PACKED || len2 == NOT_PACKED || len3 == NOT_PACKED {
+            break;
+        }
+        let (len0, len1, len2) = (len0 as usize, len1 as usize, len2 as usize);
+        let bits = bits0 as u64
+            | (bits1 as u64) << len0
+            | (bits2 as u64) << (len0 + len1)
+            | (bits3 as u64) << (len0 + len1 + len2);
+        let new_offset = bits_offset + len0 + len1 + len2 + len3 as usize;
+        if new_offset < WORD_SIZE_IN_BITS {
+            bits_buf |= bits << bits_offset;
+            bits_offset = new_offset;
+        } else {
+            if num_output_words_written == output.len() {
+                break;
+            }
+            store(
+                output,
+                num_output_words_written,
+                bits_buf | (bits << bits_offset),
+            );
+            num_output_words_written += 1;
+            // The group may take a full word, so the buffer may be empty before it.
+            bits_buf = if bits_offset == 0 {
+                0
+            } else {
+                bits >> (WORD_SIZE_IN_BITS - bits_offset)
+            };
+            bits_offset = new_offset - WORD_SIZE_IN_BITS;
+        }
+        index += SHORT_UNROLL;
+    }
+    *buf = bits_buf;
+    *offset = bits_offset;
+    let (consumed, written) = encode_short_scalar(
+        short,
+        buf,
+        offset,
+        &input[index..],
+        &mut output[num_output_words_written..],
+    );
+    (index + consumed, num_output_words_written + written)
+}
+
+/// One symbol at a time, what `encode_short` does. A codeword then never fills more than one
+/// word, and can only do so when the buffer isn't empty, which leaves a single branch per symbol
+/// in the common case.
+fn encode_short_scalar(
+    short: &[(u16, u8); NUM_SYMBOLS],
+    buf: &mut u64,
+    offset: &mut usize,
+    input: &[u8],
+    output: &mut [u64],
+) -> (usize, usize) {
+    // Kept in locals, so that they stay in registers.
+    let (mut bits_buf, mut bits_offset) = (*buf, *offset);
+    let mut num_output_words_written = 0;
+    let mut consumed = input.len();
+    for (index, &sym) in input.iter().enumerate() {
+        let (bits, bit_len) = short[sym as usize];
+        let bits = bits as u64;
+        let new_offset = bits_offset + bit_len as usize;
+        if new_offset < WORD_SIZE_IN_BITS {
+            bits_buf |= bits << bits_offset;
+            bits_offset = new_offset;
+            continue;
+        }
+        // A full word, or `NOT_PACKED`: stop before the symbol if it can't be encoded.
+        if bit_len == NOT_PACKED || num_output_words_written == output.len() {
+            consumed = index;
+            break;
+        }
+        store(
+            output,
+            num_output_words_written,
+            bits_buf | (bits << bits_offset),
+        );
+        num_output_words_written += 1;
+        // Not shifting by the full word size, since `bits_offset` is at least 64 - 16.
+        bits_buf = bits >> (WORD_SIZE_IN_BITS - bits_offset);
+        bits_offset = new_offset - WORD_SIZE_IN_BITS;
+    }
+    *buf = bits_buf;
+    *offset = bits_offset;
+    (consumed, num_output_words_written)
+}
+
+/// The default marker for `Encoder::finish_with_end_marker`: a word of all one bits.
+pub const END_MARKER: u64 = u64::MAX;
+
+/// Size of the output buffer of `Encoder::encode_to_writer`, in words (64 KiB).
+pub const WRITER_BUF_WORDS: usize = 8192;
+
+/// Writes codewords bit by bit, bypassing the word buffer used by `encode`, so the two shouldn't
+/// be mixed on one encoder. Symbols are tallied like in `encode`.
+impl SymbolEncoder for Encoder {
+    fn encode_symbol(&mut self, sym: u8, out: &mut dyn BitWrite) -> io::Result<()> {
+        let bit_len = self.tables.code.bit_len(sym);
+        if bit_len == 0 && !self.tables.zero_length_code {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                UncodedSymbol {
+                    symbol: sym,
+                    position: self.symbols_encoded,
+                },
+            ));
+        }
+        for i in 0..bit_len {
+            out.write_bit(self.tables.code.get_bit(sym, i))?;
+        }
+        self.symbols_encoded += 1;
+        if let Some(stats) = &mut self.symbol_stats {
+            stats[sym as usize].0 += 1;
+            stats[sym as usize].1 += bit_len as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Encode everything read from `input` with the given code, and write the encoded words to
+/// `output` (see `write_words`). Of the final partial word, only the bytes holding encoded bits
+/// are written, so the output is padded with less than a byte of zero bits.
+///
+/// Returns the number of bytes written. I/O errors say whether they came from reading or writing.
+pub fn encode_stream<R: Read, W: Write>(code: &Code, input: R, output: W) -> io::Result<u64> {
+    encode_stream_with(code, input, output, &mut Buffers::new())
+}
+
+/// `encode_stream`, with scratch buffers kept from earlier streams.
+///
+/// Only setting up the encoder allocates, and with new `buffers`, the first chunk. After that,
+/// reading and encoding chunks allocates nothing but what `input` and `output` do (see
+/// `Encoder::encode_to_writer`).
+pub fn encode_stream_with<R: Read, W: Write>(
+    code: &Code,
+    mut input: R,
+    mut output: W,
+    buffers: &mut Buffers,
+) -> io::Result<u64> {
+    let mut encoder = Encoder::new(code);
+    encoder.write_buf = std::mem::take(&mut buffers.words);
+    let mut encode = || {
+        let mut output_bytes = 0;
+        loop {
+            let input_buf = buffers.input(8192);
+            let input_len = read_chunk(&mut input, input_buf)
+                .map_err(|err| with_context(err, "reading input"))?;
+            if input_len == 0 {
+                break;
+            }
+            output_bytes += encoder.encode_to_writer(&input_buf[..input_len], &mut output)?;
+        }
+        Ok(output_bytes + encoder.finish_to_writer(&mut output)?)
+    };
+    let result = encode();
+    buffers.words = std::mem::take(&mut encoder.write_buf);
+    result
+}
+
+/// Write encoded words as bytes.
+///
+/// The byte order of the stream is little-endian, regardless of the host: together with the
+/// order of bits inside the words, this means that the first bit of the stream is at `1 << 0` of
+/// the first byte.
+///
+/// Short writes and writes failing with `ErrorKind::Interrupted` are retried until all words are
+/// written.
+pub fn write_words<W: Write>(output: &mut W, words: &[u64]) -> io::Result<()> {
+    // Convert through a buffer on the stack; on little-endian hosts, this compiles to a plain
+    // copy.
+    const CHUNK_WORDS: usize = 512;
+    let mut bytes = [0u8; CHUNK_WORDS * 8];
+    for chunk in words.chunks(CHUNK_WORDS) {
+        for (dst, word) in bytes.chunks_exact_mut(8).zip(chunk) {
+            dst.copy_from_slice(&word.to_le_bytes());
+        }
+        output.write_all(&bytes[..chunk.len() * 8])?;
+    }
+    Ok(())
+}
+
+/// Size of the blocks that `encode_stream_parallel` hands to worker threads.
+pub const PARALLEL_BLOCK_LEN: usize = 1 << 20;
+
+/// Like `encode_stream`, but encodes blocks of `PARALLEL_BLOCK_LEN` bytes on `threads` worker
+/// threads. The output is the same: the blocks are joined bit by bit in order, as if one encoder
+/// had encoded them all.
+///
+/// `input` and `output` are only used on the calling thread. At most `2 * threads` blocks are
+/// between being read and being written at any time, so memory use doesn't grow with the input.
+pub fn encode_stream_parallel<R: Read, W: Write>(
+    code: &Code,
+    input: R,
+    output: W,
+    threads: usize,
+) -> io::Result<u64> {
+    if threads <= 1 {
+        return encode_stream(code, input, output);
+    }
+    Ok(encode_blocks(code, input, output, threads, PARALLEL_BLOCK_LEN)?.0)
+}
+
+/// The encoded bits of one block.
+struct EncodedBlock {
+    words: Vec<u64>,
+    /// The final partial word, and the number of valid bits in it, as returned by `finish`.
+    last_word: u64,
+    last_bits: usize,
+}
+
+/// Encode `block`, which starts at `position` in the input.
+fn encode_block(code: &Code, block: &[u8], position: u64) -> Result<EncodedBlock, UncodedSymbol> {
+    let mut encoder = Encoder::new(code);
+    let mut words = vec![];
+    let mut output_buf = [0u64; 1024];
+    let mut input_off = 0;
+    while input_off < block.len() {
+        let (input_consumed, output_len) = encoder
+            .encode(&block[input_off..], &mut output_buf)
+            .map_err(|err| match err {
+                EncodeError::OutputTooSmall(_) => unreachable!("output buffer holds any codeword"),
+                EncodeError::UncodedSymbol(err) => UncodedSymbol {
+                    position: position + err.position,
+                    ..err
+                },
+            })?;
+        input_off += input_consumed;
+        words.extend_from_slice(&output_buf[..output_len]);
+    }
+    let (last_word, last_bits) = encoder.finish().unwrap_or((0, 0));
+    Ok(EncodedBlock {
+        words,
+        last_word,
+        last_bits,
+    })
+}
+
+/// Joins runs of bits into words, in the layout of the encoder.
+#[derive(Default)]
+struct BitJoiner {
+    /// Bits not yet in a complete word, starting at `1 << 0`; the rest are zero.
+    buf: u64,
+    /// Number of bits in `buf`.
+    offset: usize,
+}
+
+impl BitJoiner {
+    /// Append the low `bits` bits of `value`, whose other bits must be zero, and push the words
+    /// this completes to `words`.
+    fn push(&mut self, value: u64, bits: usize, words: &mut Vec<u64>) {
+        self.buf |= value << self.offset;
+        if self.offset + bits >= WORD_SIZE_IN_BITS {
+            words.push(self.buf);
+            // The bits of `value` that didn't fit; none if `value` started at a word boundary.
+            self.buf = value
+                .checked_shr((WORD_SIZE_IN_BITS - self.offset) as u32)
+                .unwrap_or(0);
+            self.offset = self.offset + bits - WORD_SIZE_IN_BITS;
+        } else {
+            self.offset += bits;
+        }
+    }
+}
+
+/// Implementation of `encode_stream_parallel` with a configurable block length. Also returns the
+/// highest number of blocks that were in flight at once.
+fn encode_blocks<R: Read, W: Write>(
+    code: &Code,
+    input: R,
+    output: W,
+    threads: usize,
+    block_len: usize,
+) -> io::Result<(u64, usize)> {
+    let max_in_flight = 2 * threads;
+    let (job_tx, job_rx) = mpsc::sync_channel(max_in_flight);
+    let job_rx = Mutex::new(job_rx);
+    let (result_tx, result_rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let job_rx = &job_rx;
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                // Bound separately, so that the lock isn't held while encoding.
+                let job = job_rx.lock().unwrap().recv();
+                let (index, position, block): (u64, u64, Vec<u8>) = match job {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let encoded = encode_block(code, &block, position);
+                if result_tx.send((index, encoded)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(result_tx);
+        // Takes the channels, so that the workers stop once it returns, even on errors.
+        run_blocks(job_tx, result_rx, input, output, block_len, max_in_flight)
+    })
+}
+
+/// The calling thread's side of `encode_blocks`: read blocks and queue them for the workers, and
+/// write out their results in order.
+fn run_blocks<R: Read, W: Write>(
+    job_tx: mpsc::SyncSender<(u64, u64, Vec<u8>)>,
+    result_rx: mpsc::Receiver<(u64, Result<EncodedBlock, UncodedSymbol>)>,
+    mut input: R,
+    mut output: W,
+    block_len: usize,
+    max_in_flight: usize,
+) -> io::Result<(u64, usize)> {
+    let mut blocks_read: u64 = 0;
+    let mut blocks_written: u64 = 0;
+    let mut position: u64 = 0;
+    let mut input_done = false;
+    let mut peak_in_flight = 0;
+    // Results that came in before the ones of earlier blocks.
+    let mut results = BTreeMap::new();
+    let mut joiner = BitJoiner::default();
+    let mut words = vec![];
+    let mut output_bytes = 0;
+    loop {
+        while !input_done && ((blocks_read - blocks_written) as usize) < max_in_flight {
+            let mut block = vec![0; block_len];
+            let input_len = read_chunk(&mut input, &mut block)
+                .map_err(|err| with_context(err, "reading input"))?;
+            if input_len == 0 {
+                input_done = true;
+                break;
+            }
+            block.truncate(input_len);
+            job_tx
+                .send((blocks_read, position, block))
+                .expect("workers run until the queue is closed");
+            blocks_read += 1;
+            position += input_len as u64;
+            peak_in_flight = peak_in_flight.max((blocks_read - blocks_written) as usize);
+        }
+        if blocks_read == blocks_written {
+            break;
+        }
+        let (index, result) = result_rx
+            .recv()
+            .expect("workers send a result for each block");
+        results.insert(index, result);
+        while let Some(result) = results.remove(&blocks_written) {
+            let block = result.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            for &word in &block.words {
+                joiner.push(word, WORD_SIZE_IN_BITS, &mut words);
+            }
+            joiner.push(block.last_word, block.last_bits, &mut words);
+            write_words(&mut output, &words).map_err(|err| with_context(err, "writing output"))?;
+            output_bytes += words.len() as u64 * 8;
+            words.clear();
+            blocks_written += 1;
+        }
+    }
+    // Like in `encode_stream`, only the bytes holding encoded bits.
+    if joiner.offset > 0 {
+        let len = joiner.offset.div_ceil(8);
+        output
+            .write_all(&joiner.buf.to_le_bytes()[..len])
+            .map_err(|err| with_context(err, "writing output"))?;
+        output_bytes += len as u64;
+    }
+    Ok((output_bytes, peak_in_flight))
+}
+
 /// Build code for input and encode it using the code.
 #[cfg(test)]
 pub fn full_encode(input: &[u8]) -> (Code, Vec<u64>) {
     let code = tree::tree_to_code(&tree::build_tree(&tree::compute_frequencies(input)));
-    let mut encoder = Encoder::new(&code);
-    let mut output: Vec<u64> = (0..input.len()).map(|_| 0).collect();
-    let (input_consumed, mut output_consumed) = encoder.encode(input, &mut output);
+    let output = encode_with(&code, input);
+    (code, output)
+}
+
+/// Encode input using the given code, with an output buffer large enough for any code.
+#[cfg(test)]
+pub fn encode_with(code: &Code, input: &[u8]) -> Vec<u64> {
+    let mut encoder = Encoder::new(code);
+    let max_words = input.len() * tree::MAX_CODEWORD_BITS / WORD_SIZE_IN_BITS + 1;
+    let mut output = vec![0; max_words];
+    let (input_consumed, output_consumed) = encoder.encode(input, &mut output).unwrap();
     assert_eq!(input_consumed, input.len());
-    output_consumed += encoder.finish(&mut output[output_consumed..]);
-    (code, output[..output_consumed].to_vec())
+    output.truncate(output_consumed);
+    output.extend(encoder.finish().map(|(word, _)| word));
+    output
+}
+
+/// Slow but obviously correct encoder, pushing the codeword bits one at a time.
+#[cfg(test)]
+pub fn reference_encode(code: &Code, input: &[u8]) -> Vec<u64> {
+    let mut output = vec![];
+    let mut num_bits = 0;
+    for &sym in input {
+        let cw = &code[sym];
+        for i in 0..cw.bit_len {
+            if num_bits % WORD_SIZE_IN_BITS == 0 {
+                output.push(0);
+            }
+            output[num_bits / WORD_SIZE_IN_BITS] |=
+                (cw.get_bit(i) as u64) << (num_bits % WORD_SIZE_IN_BITS);
+            num_bits += 1;
+        }
+    }
+    output
 }
 
+#[cfg(test)]
+pub use crate::corpus::fibonacci_frequencies;
+
 #[cfg(test)]
 fn bit_sequence_to_string(words: &[u64]) -> String {
     let mut output = String::new();
     for &w in words {
-        let mut s = format!("{:b}", w);
-        unsafe {
-            s.as_mut_vec().reverse();
-        }
-        output.push_str(&s);
+        output.extend(format!("{:b}", w).chars().rev());
     }
     output
 }
@@ -119,23 +1115,952 @@ fn test_full_encode() {
         format!("{}", &code),
         strip_indent(
             "
-            _: 101
-            a: 000
-            c: 11110
-            d: 11101
-            e: 110
-            g: 11111
-            i: 011
-            l: 1000
-            n: 010
-            p: 001
-            s: 1001
-            v: 11100
+            _: 100
+            a: 1101
+            c: 0100
+            d: 0101
+            e: 101
+            g: 0110
+            i: 1110
+            l: 0111
+            n: 1111
+            p: 000
+            s: 001
+            v: 1100
             "
         )
     );
     assert_eq!(
         bit_sequence_to_string(&output),
-        "000001001110010111011001101000101111110111110011001010110011111111011".to_string()
+        "11010000001011111010100110011011000110111011001011111100001011111100100101".to_string()
+    );
+}
+
+/// A tree where each branch has a leaf on the left, so that the codeword of the last symbol is
+/// `n - 1` bits long.
+#[cfg(test)]
+pub fn degenerate_tree(n: usize) -> tree::Tree {
+    let mut tree = tree::Tree::Leaf((n - 1) as u8);
+    for sym in (0..n - 1).rev() {
+        tree = tree::Tree::Branch(Box::new(tree::Tree::Leaf(sym as u8)), Box::new(tree));
+    }
+    tree
+}
+
+/// Encode `long` codewords after `offset` one-bit codewords, so that they start at every
+/// possible position in a word, and compare with the reference encoder.
+#[cfg(test)]
+fn check_at_all_offsets(code: &Code, short: u8, long: &[u8]) {
+    assert_eq!(code[short].bit_len, 1);
+    for offset in 0..WORD_SIZE_IN_BITS {
+        for &sym in long {
+            let mut input = vec![short; offset];
+            input.extend_from_slice(&[sym, sym, short, sym]);
+            assert_eq!(
+                encode_with(code, &input),
+                reference_encode(code, &input),
+                "symbol {} at offset {}",
+                sym,
+                offset
+            );
+        }
+    }
+}
+
+#[test]
+fn test_encode_multi_word_codewords_at_all_offsets() {
+    // In the degenerate tree, symbol `i` has a codeword of `i + 1` bits.
+    let code = tree::tree_to_code(&degenerate_tree(256));
+    for &sym in &[63, 64, 99, 127, 128, 199] {
+        assert_eq!(code[sym].bit_len, sym as usize + 1);
+    }
+    check_at_all_offsets(&code, 0, &[62, 63, 64, 99, 127, 128, 199]);
+}
+
+#[test]
+fn test_encode_multi_word_codewords_ending_on_word_boundary() {
+    // Codewords of 64 and 128 bits leave the encoder at offset 0, where the carried-over part of
+    // the last word is empty rather than a shift by the full word size.
+    let code = tree::tree_to_code(&degenerate_tree(256));
+    let input = [127, 127, 63, 199, 127, 63, 63, 0, 127, 98];
+    assert_eq!(encode_with(&code, &input), reference_encode(&code, &input));
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_multi_word_matches_reference(num_symbols: u8, offset: u8, input: Vec<u8>) -> bool {
+    let num_symbols = 65 + num_symbols as usize % 192;
+    let code = tree::tree_to_code(&degenerate_tree(num_symbols));
+    let mut padded = vec![0; offset as usize % WORD_SIZE_IN_BITS];
+    padded.extend(
+        input
+            .into_iter()
+            .map(|sym| (sym as usize % num_symbols) as u8),
+    );
+    encode_with(&code, &padded) == reference_encode(&code, &padded)
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_keeps_buffer_invariant(
+    codewords: Vec<Vec<bool>>,
+    input: Vec<u8>,
+    offset: u8,
+    output_len: u8,
+) -> quickcheck::TestResult {
+    // Arbitrary codewords, up to the maximal length: the encoder doesn't need a prefix code.
+    let mut code = Code::empty();
+    for (sym, bits) in codewords.iter().take(NUM_SYMBOLS).enumerate() {
+        let bits: Vec<bool> = bits
+            .iter()
+            .cycle()
+            .take((1 + sym % 4) * bits.len())
+            .take(tree::MAX_CODEWORD_BITS)
+            .cloned()
+            .collect();
+        code[sym as u8] = Codeword::from_bits(&bits);
+    }
+    if codewords.is_empty() {
+        return quickcheck::TestResult::discard();
+    }
+    let input: Vec<u8> = input
+        .iter()
+        .map(|&sym| (sym as usize % codewords.len()) as u8)
+        .collect();
+    let offset = offset as usize % WORD_SIZE_IN_BITS;
+
+    // Interrupted by a small output buffer, which still fits the longest codeword, and all at
+    // once.
+    let mut outputs = vec![];
+    for &buf_len in &[4 + output_len as usize % 4, 4 * input.len() + 1] {
+        let mut encoder = Encoder::with_state(&code, u64::MAX, offset);
+        let mut output = vec![];
+        let mut buf = vec![0; buf_len];
+        let mut input_off = 0;
+        while let Ok((input_consumed, output_len)) = encoder.encode(&input[input_off..], &mut buf) {
+            if (input_consumed, output_len) == (0, 0) {
+                break;
+            }
+            assert_eq!(encoder.buf >> encoder.offset, 0);
+            input_off += input_consumed;
+            output.extend_from_slice(&buf[..output_len]);
+        }
+        outputs.push((output, input_off, encoder.finish()));
+    }
+    quickcheck::TestResult::from_bool(outputs[0] == outputs[1])
+}
+
+#[test]
+fn test_encode_maximal_codewords_at_all_offsets() {
+    let code = tree::tree_to_code(&degenerate_tree(256));
+    assert_eq!(code[255].bit_len, 255);
+    check_at_all_offsets(&code, 0, &[191, 192, 193, 254, 255]);
+
+    // Longer than any tree over 256 symbols can produce, but still a valid codeword.
+    let mut code = Code::empty();
+    code[0] = Codeword::from_bits(&[tree::B0]);
+    code[1] = Codeword::from_bits(&[tree::B1; tree::MAX_CODEWORD_BITS]);
+    code[2] = (0..tree::MAX_CODEWORD_BITS)
+        .map(|i| if i % 3 == 0 { '1' } else { '0' })
+        .collect::<String>()
+        .parse()
+        .unwrap();
+    check_at_all_offsets(&code, 0, &[1, 2]);
+}
+
+#[test]
+fn test_encode_long_codewords_at_zero_offset() {
+    let code = tree::tree_to_code(&tree::build_tree(&fibonacci_frequencies(90)));
+    let short = (0..90u8).find(|&sym| code[sym].bit_len == 1).unwrap();
+    let long: Vec<u8> = (0..90u8).filter(|&sym| code[sym].bit_len >= 64).collect();
+    assert!(!long.is_empty());
+    // Pad with 64 one-bit codewords, so that every long codeword is encoded with an empty bit
+    // buffer.
+    for &sym in &long {
+        let mut input = vec![short; 64];
+        input.push(sym);
+        input.extend(vec![short; 64 - code[sym].bit_len % 64]);
+        input.push(sym);
+        assert_eq!(encode_with(&code, &input), reference_encode(&code, &input));
+    }
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_matches_reference_own_code(input: Vec<u8>) -> quickcheck::TestResult {
+    if input.is_empty() {
+        return quickcheck::TestResult::discard();
+    }
+    let (code, output) = full_encode(&input);
+    quickcheck::TestResult::from_bool(output == reference_encode(&code, &input))
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_matches_reference(input: Vec<u8>) -> bool {
+    let code = tree::tree_to_code(&tree::build_tree(&fibonacci_frequencies(90)));
+    let input: Vec<u8> = input.into_iter().map(|sym| sym % 90).collect();
+    encode_with(&code, &input) == reference_encode(&code, &input)
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_packed_matches_general_path(num_symbols: u8, input: Vec<u8>, chunk_len: u8) -> bool {
+    // Codewords of all lengths up to 255 bits, some packed and some not.
+    let num_symbols = 2 + num_symbols as usize % 255;
+    let code = tree::tree_to_code(&degenerate_tree(num_symbols));
+    let input: Vec<u8> = input
+        .into_iter()
+        .map(|sym| (sym as usize % num_symbols) as u8)
+        .collect();
+    let mut general = Encoder::new(&code);
+    let tables = Arc::get_mut(&mut general.tables).unwrap();
+    tables.packed = [(0, NOT_PACKED); NUM_SYMBOLS];
+    tables.short = None;
+    let packed = Encoder::new(&code);
+    // Small output buffers, so that both stop and resume in the middle of the input.
+    let chunk_len = MIN_OUTPUT_WORDS + chunk_len as usize % 8;
+    let mut outputs = vec![];
+    for encoder in &mut [general, packed] {
+        let mut output = vec![];
+        let mut buf = vec![0; chunk_len];
+        let mut input_off = 0;
+        while input_off < input.len() {
+            let (input_consumed, output_len) =
+                encoder.encode(&input[input_off..], &mut buf).unwrap();
+            input_off += input_consumed;
+            output.extend_from_slice(&buf[..output_len]);
+        }
+        outputs.push((output, encoder.finish()));
+    }
+    outputs[0] == outputs[1]
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_short_matches_general_path(
+    frequencies: Vec<(u8, u8)>,
+    input: Vec<u8>,
+    offset: u8,
+    chunk_len: u8,
+) -> quickcheck::TestResult {
+    let frequencies: tree::Frequencies = frequencies
+        .into_iter()
+        .map(|(sym, freq)| (sym, freq as u64))
+        .collect();
+    if frequencies.iter().all(|&(_, freq)| freq == 0) {
+        return quickcheck::TestResult::discard();
+    }
+    let code = tree::tree_to_code(&tree::build_tree(&frequencies));
+    if code.max_len() > SHORT_CODEWORD_BITS {
+        return quickcheck::TestResult::discard();
+    }
+    // Some input symbols may have no codeword, which both have to stop at.
+    let offset = offset as usize % WORD_SIZE_IN_BITS;
+    let mut general = Encoder::with_state(&code, u64::MAX, offset);
+    Arc::get_mut(&mut general.tables).unwrap().short = None;
+    let short = Encoder::with_state(&code, u64::MAX, offset);
+    assert!(short.tables.short.is_some());
+    let mut outputs = vec![];
+    for encoder in &mut [general, short] {
+        let mut output = vec![];
+        let mut buf = vec![0; 1 + chunk_len as usize % 4];
+        let mut input_off = 0;
+        let result = loop {
+            match encoder.encode(&input[input_off..], &mut buf) {
+                Ok((0, 0)) => break Ok(()),
+                Ok((input_consumed, output_len)) => {
+                    input_off += input_consumed;
+                    output.extend_from_slice(&buf[..output_len]);
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        outputs.push((output, input_off, result, encoder.finish()));
+    }
+    quickcheck::TestResult::from_bool(outputs[0] == outputs[1])
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_encode_short_matches_scalar(
+    frequencies: Vec<(u8, u8)>,
+    input: Vec<u8>,
+    offset: u8,
+    output_len: u8,
+    longest: bool,
+) -> quickcheck::TestResult {
+    // Mostly the longest short codes, so that a group of four symbols can take a full word.
+    let (code, input) = if longest {
+        let input = input
+            .iter()
+            .map(|&sym| [16, 15, 14, sym % 20][sym as usize % 4])
+            .collect();
+        (tree::tree_to_code(&degenerate_tree(17)), input)
+    } else {
+        let frequencies: tree::Frequencies = frequencies
+            .into_iter()
+            .map(|(sym, freq)| (sym, freq as u64))
+            .collect();
+        if frequencies.iter().all(|&(_, freq)| freq == 0) {
+            return quickcheck::TestResult::discard();
+        }
+        (tree::tree_to_code(&tree::build_tree(&frequencies)), input)
+    };
+    let short = match Encoder::new(&code).tables.short {
+        Some(short) => short,
+        None => return quickcheck::TestResult::discard(),
+    };
+    let offset = offset as usize % WORD_SIZE_IN_BITS;
+    let buf = if offset == 0 {
+        0
+    } else {
+        u64::MAX >> (WORD_SIZE_IN_BITS - offset)
+    };
+    let output_len = output_len as usize % 8;
+    let mut results = vec![];
+    for &unrolled in &[false, true] {
+        let (mut buf, mut offset) = (buf, offset);
+        let mut output = vec![0; output_len];
+        let encode = if unrolled {
+            encode_short
+        } else {
+            encode_short_scalar
+        };
+        let counts = encode(&short, &mut buf, &mut offset, &input, &mut output);
+        results.push((counts, buf, offset, output));
+    }
+    quickcheck::TestResult::from_bool(results[0] == results[1])
+}
+
+#[test]
+fn test_encoders_share_tables() {
+    let input = b"appends_a_given_slice";
+    let (code, expected) = full_encode(input);
+    let tables = Arc::new(EncoderTables::new(&code));
+    let mut encoders: Vec<_> = (0..3)
+        .map(|_| Encoder::with_tables(tables.clone()))
+        .collect();
+    assert_eq!(Arc::strong_count(&tables), 4);
+    for encoder in &mut encoders {
+        let mut output = vec![0; expected.len()];
+        let (_, output_len) = encoder.encode(input, &mut output).unwrap();
+        output.truncate(output_len);
+        output.extend(encoder.finish().map(|(word, _)| word));
+        assert_eq!(output, expected);
+    }
+    // Each encoder holds its own state, and a pointer to the tables.
+    assert!(std::mem::size_of::<Encoder>() < 256);
+}
+
+#[test]
+fn test_encode_fills_output_exactly() {
+    // Outputs of a few words are filled to the last one by every loop: the unrolled and scalar
+    // short ones, the packed one and the multi-word one. That's where the stores of
+    // `fast-unsafe` rely on their checks; small enough to run under Miri.
+    let text = b"abracadabra, abracadabra, abracadabra, abracadabra";
+    for &n in &[8, 17, 40, 100] {
+        let code = tree::tree_to_code(&degenerate_tree(n));
+        let input: Vec<u8> = text.iter().map(|&sym| sym % n as u8).collect();
+        let expected = reference_encode(&code, &input);
+        // A codeword of 100 bits can fill two words after a partial one.
+        let min_len = if n > WORD_SIZE_IN_BITS { 2 } else { 1 };
+        for output_len in min_len..min_len + 3 {
+            let mut encoder = Encoder::new(&code);
+            let mut output = vec![];
+            let mut buf = vec![0; output_len];
+            let mut input_off = 0;
+            while input_off < input.len() {
+                let (input_consumed, output_consumed) =
+                    encoder.encode(&input[input_off..], &mut buf).unwrap();
+                input_off += input_consumed;
+                output.extend_from_slice(&buf[..output_consumed]);
+            }
+            output.extend(encoder.finish().map(|(word, _)| word));
+            assert_eq!(output, expected);
+        }
+    }
+}
+
+#[test]
+fn test_encode_short_codes() {
+    // Codes of 16 bits and less take the short path, longer ones don't.
+    for (n, short) in &[(17, true), (18, false)] {
+        let code = tree::tree_to_code(&degenerate_tree(*n));
+        assert_eq!(code.max_len(), n - 1);
+        assert_eq!(Encoder::new(&code).tables.short.is_some(), *short);
+    }
+    let code = tree::tree_to_code(&degenerate_tree(17));
+    let input: Vec<u8> = (0..1000).map(|i| (i * 7 % 17) as u8).collect();
+    assert_eq!(encode_with(&code, &input), reference_encode(&code, &input));
+}
+
+#[test]
+fn test_encode_stream_flushes_partial_word() {
+    let input = b"appends_a_given_slice";
+    let (code, words) = full_encode(input);
+    assert_eq!(words.len(), 2);
+    let mut output = vec![];
+    let output_bytes = encode_stream(&code, &input[..], &mut output).unwrap();
+    // 74 bits of output: one full word, and two bytes of the partial word.
+    assert_eq!(output_bytes, 10);
+    assert_eq!(
+        output,
+        vec![0x0b, 0xf4, 0x95, 0xd9, 0xd8, 0x4d, 0x3f, 0xf4, 0x93, 0x02]
+    );
+}
+
+#[test]
+fn test_encode_group() {
+    let input = b"appends_a_given_slice_to_the_end_of_the_vector";
+    let (code, expected) = full_encode(input);
+    let mut encoder = Encoder::new(&code);
+    let mut output = vec![];
+    let mut buf = [0; 2];
+    let mut start_bit = 0;
+    for group in input.chunks(7) {
+        let (output_len, info) = encoder.encode_group(group, &mut buf).unwrap();
+        output.extend_from_slice(&buf[..output_len]);
+        let bit_len: u64 = group.iter().map(|&sym| code[sym].bit_len as u64).sum();
+        assert_eq!(info, GroupInfo { start_bit, bit_len });
+        start_bit += bit_len;
+    }
+    output.extend(encoder.finish().map(|(word, _)| word));
+    assert_eq!(output, expected);
+
+    // Each group decodes on its own from its start.
+    let bytes: Vec<u8> = output.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let mut decoder = crate::decode::Decoder::new(&code);
+    let mut start_bit = 0;
+    for group in input.chunks(7) {
+        let mut reader = bitstream::BitReader::new(&bytes[..]);
+        for _ in 0..start_bit {
+            reader.read_bit().unwrap();
+        }
+        for &sym in group {
+            assert_eq!(decoder.decode_symbol(&mut reader).unwrap(), sym);
+        }
+        start_bit = reader.bits_read();
+    }
+}
+
+#[test]
+fn test_encode_group_errors() {
+    let code = tree::tree_to_code(&degenerate_tree(20));
+    let mut encoder = Encoder::new(&code);
+    let mut buf = [0; 1];
+    // 19 bits pending, and 6 more codewords of 19 bits fill two words.
+    encoder.encode_group(&[19], &mut buf).unwrap();
+    assert_eq!(
+        encoder.encode_group(&[18, 19, 18, 19, 18, 19], &mut buf),
+        Err(EncodeError::OutputTooSmall(OutputTooSmall {
+            needed_words: 2
+        }))
+    );
+    assert_eq!(
+        encoder.encode_group(&[0, 1, 200], &mut buf),
+        Err(EncodeError::UncodedSymbol(UncodedSymbol {
+            symbol: 200,
+            position: 3,
+        }))
+    );
+    // Nothing of the failed groups was encoded.
+    assert_eq!(encoder.encoded_bits(), 19);
+    assert_eq!(
+        encoder.encode_group(&[18, 19], &mut buf),
+        Ok((
+            0,
+            GroupInfo {
+                start_bit: 19,
+                bit_len: 38
+            }
+        ))
+    );
+}
+
+#[test]
+fn test_encoder_stats() {
+    let code = tree::tree_to_code(&tree::build_tree(&fibonacci_frequencies(20)));
+    let input: Vec<u8> = (0..5000).map(|i| (i * 7 % 20) as u8).collect();
+    let bits: u64 = input.iter().map(|&sym| code[sym].bit_len as u64).sum();
+
+    for timed in &[false, true] {
+        let mut encoder = if *timed {
+            Encoder::with_timing(&code)
+        } else {
+            Encoder::new(&code)
+        };
+        assert_eq!(encoder.stats().symbols_encoded, 0);
+        // Many chunks, each stopping when the output buffer fills up.
+        let mut buf = [0; MIN_OUTPUT_WORDS];
+        let mut input_off = 0;
+        let mut chunks = 0;
+        while input_off < input.len() {
+            let (input_consumed, _) = encoder.encode(&input[input_off..], &mut buf).unwrap();
+            input_off += input_consumed;
+            chunks += 1;
+        }
+        assert!(chunks > 10);
+        let stats = encoder.stats();
+        assert_eq!(stats.symbols_encoded, input.len() as u64);
+        assert_eq!(stats.words_emitted, bits / 64);
+        // Without timing, the clock is never read.
+        assert_eq!(stats.time.is_some(), *timed);
+
+        assert!(encoder.finish().is_some());
+        assert_eq!(encoder.stats().words_emitted, bits.div_ceil(64));
+
+        encoder.reset();
+        let stats = encoder.stats();
+        assert_eq!((stats.symbols_encoded, stats.words_emitted), (0, 0));
+        assert_eq!(stats.time, Some(Duration::ZERO).filter(|_| *timed));
+        assert_eq!(encoder.encoded_bits(), bits.div_ceil(64) * 64);
+        encoder.encode(&input[..3], &mut buf).unwrap();
+        assert_eq!(encoder.stats().symbols_encoded, 3);
+    }
+}
+
+#[test]
+fn test_symbol_stats() {
+    let (code, _) = full_encode(b"abracadabra");
+    let mut output = [0u64; 4];
+
+    let mut encoder = Encoder::new(&code);
+    encoder.encode(b"abracadabra", &mut output).unwrap();
+    assert!(encoder.symbol_stats().iter().all(|&s| s == (0, 0)));
+
+    let mut encoder = Encoder::with_symbol_stats(&code);
+    encoder.encode(b"abracad", &mut output).unwrap();
+    encoder.encode(b"abra", &mut output).unwrap();
+    let stats = encoder.symbol_stats();
+    for &(sym, count) in &[(b'a', 5), (b'b', 2), (b'r', 2), (b'c', 1), (b'd', 1)] {
+        assert_eq!(
+            stats[sym as usize],
+            (count, count * code[sym].bit_len as u64)
+        );
+    }
+    assert_eq!(stats.iter().map(|s| s.0).sum::<u64>(), 11);
+}
+
+#[test]
+fn test_encode_small_output_buffer() {
+    // Symbol 99 has a 100-bit codeword, which needs two words to be written out, unless it
+    // starts early enough in the buffer.
+    let code = tree::tree_to_code(&degenerate_tree(256));
+    let input = [99; 10];
+    let mut encoder = Encoder::new(&code);
+    let mut output = vec![];
+    let mut input_off = 0;
+    let mut buffer_too_small = false;
+    while input_off < input.len() {
+        let mut buf = [0u64; 1];
+        match encoder.encode(&input[input_off..], &mut buf) {
+            Ok((input_consumed, output_len)) => {
+                assert!(input_consumed > 0);
+                input_off += input_consumed;
+                output.extend_from_slice(&buf[..output_len]);
+            }
+            Err(err) => {
+                buffer_too_small = true;
+                assert_eq!(
+                    err,
+                    EncodeError::OutputTooSmall(OutputTooSmall { needed_words: 2 })
+                );
+                let mut buf = [0u64; 2];
+                let (input_consumed, output_len) = encoder
+                    .encode(&input[input_off..input_off + 1], &mut buf)
+                    .unwrap();
+                assert_eq!((input_consumed, output_len), (1, 2));
+                input_off += 1;
+                output.extend_from_slice(&buf);
+            }
+        }
+    }
+    assert!(buffer_too_small);
+    output.extend(encoder.finish().map(|(word, _)| word));
+    assert_eq!(output, reference_encode(&code, &input));
+}
+
+#[test]
+fn test_encode_min_output_words() {
+    let code = tree::tree_to_code(&degenerate_tree(256));
+    let mut encoder = Encoder::new(&code);
+    let mut output = [0u64; MIN_OUTPUT_WORDS];
+    assert_eq!(encoder.encode(&[0; 63], &mut output), Ok((63, 0)));
+    assert_eq!(encoder.encode(&[255], &mut output), Ok((1, 4)));
+    assert_eq!(encoder.encode(&[], &mut []), Ok((0, 0)));
+}
+
+#[test]
+fn test_encode_uncoded_symbol() {
+    let code = tree::tree_to_code(&tree::build_tree(&vec![(b'a', 2), (b'b', 1)]));
+    let mut encoder = Encoder::new(&code);
+    let mut output = [0u64; MIN_OUTPUT_WORDS];
+    // Stops right before the uncoded symbol, then refuses to go on.
+    assert_eq!(encoder.encode(b"abxab", &mut output), Ok((2, 0)));
+    let uncoded = UncodedSymbol {
+        symbol: b'x',
+        position: 2,
+    };
+    assert_eq!(
+        encoder.encode(b"xab", &mut output),
+        Err(EncodeError::UncodedSymbol(uncoded))
+    );
+    assert_eq!(
+        encoder.encode_within(b"xab", &mut output, 8),
+        Err(EncodeWithinError::UncodedSymbol(uncoded))
+    );
+    assert_eq!(encoder.encoded_bits(), 2);
+
+    // The position counts across calls and buffer refills.
+    let mut input = b"ab".repeat(10000);
+    input[12345] = b'z';
+    let err = encode_stream(&code, &input[..], vec![]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        "symbol 122 at input offset 12345 has no codeword"
+    );
+
+    // A zero-length code is different: there, the empty codeword is the intended encoding.
+    let code = tree::tree_to_code_with(&tree::Tree::Leaf(b'a'), tree::SingleLeaf::ZeroLength);
+    let mut encoder = Encoder::new(&code);
+    assert_eq!(encoder.encode(b"aaa", &mut output), Ok((3, 0)));
+}
+
+#[test]
+fn test_encode_within() {
+    // Symbol 99 has a 100-bit codeword, so three of them fit in 40 bytes (5 words), but not
+    // four.
+    let code = tree::tree_to_code(&degenerate_tree(256));
+    let input = [99; 10];
+    let mut encoder = Encoder::new(&code);
+    let mut output = [0u64; 16];
+    assert_eq!(encoder.encode_within(&input, &mut output, 47), Ok((3, 4)));
+    assert_eq!(encoder.encoded_bits(), 300);
+    assert_eq!(
+        encoder.encode_within(&input, &mut output[4..], 47),
+        Err(EncodeWithinError::BudgetExhausted)
     );
+    // One-bit codewords still fit in the last word, filling it exactly.
+    assert_eq!(
+        encoder.encode_within(&[0; 30], &mut output[4..], 40),
+        Ok((20, 1))
+    );
+    assert_eq!(encoder.finish(), None);
+    assert_eq!(encoder.encoded_bits(), 5 * 64);
+
+    let mut expected_input = vec![99; 3];
+    expected_input.extend_from_slice(&[0; 20]);
+    assert_eq!(&output[..5], &reference_encode(&code, &expected_input)[..]);
+}
+
+#[test]
+fn test_peek_buffered() {
+    let (code, _) = full_encode(b"abracadabra");
+    let mut encoder = Encoder::new(&code);
+    assert_eq!(encoder.peek_buffered(), (0, 0));
+
+    let mut output = [0u64; 1];
+    assert_eq!(encoder.encode(b"abra", &mut output), Ok((4, 0)));
+    let bits = code[b'a'].bit_len * 2 + code[b'b'].bit_len + code[b'r'].bit_len;
+    let expected = (reference_encode(&code, b"abra")[0], bits);
+    assert_eq!(encoder.peek_buffered(), expected);
+    assert_eq!(encoder.peek_buffered(), expected);
+    assert_eq!(encoder.encoded_bits(), bits as u64);
+
+    // Encoding goes on where it left off.
+    assert_eq!(encoder.encode(b"cadabra", &mut output), Ok((7, 0)));
+    assert_eq!(
+        encoder.finish(),
+        Some((reference_encode(&code, b"abracadabra")[0], 23))
+    );
+    assert_eq!(encoder.peek_buffered(), (0, 0));
+}
+
+#[test]
+fn test_with_state() {
+    let code = tree::canonical_code(&vec![(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]).unwrap();
+    let input = b"abacabad".repeat(10);
+    let expected = encode_with(&code, &input);
+
+    let mut first = Encoder::new(&code);
+    let mut output = [0u64; 2];
+    assert_eq!(first.encode(&input[..2], &mut output), Ok((2, 0)));
+    let (buf, offset) = first.peek_buffered();
+    assert_eq!(offset, 3);
+
+    // Garbage above the offset doesn't leak into the output.
+    for &buf in &[buf, buf | !0 << 3] {
+        let mut resumed = Encoder::with_state(&code, buf, 3);
+        assert_eq!(resumed.encoded_bits(), 3);
+        let mut words = vec![];
+        let mut rest = &input[2..];
+        while !rest.is_empty() {
+            let (consumed, written) = resumed.encode(rest, &mut output).unwrap();
+            words.extend_from_slice(&output[..written]);
+            rest = &rest[consumed..];
+        }
+        assert_eq!(resumed.encoded_bits(), 14 * 10);
+        words.extend(resumed.finish().map(|(word, _)| word));
+        assert_eq!(words, expected);
+    }
+}
+
+#[test]
+#[should_panic(expected = "offset 64 is not within a word")]
+fn test_with_state_offset_too_large() {
+    let code = tree::canonical_code(&vec![(b'a', 1), (b'b', 1)]).unwrap();
+    Encoder::with_state(&code, 0, 64);
+}
+
+#[test]
+fn test_finish() {
+    let (code, _) = full_encode(b"abracadabra");
+    let mut encoder = Encoder::new(&code);
+    assert_eq!(encoder.finish(), None);
+
+    let mut output = [0u64; 1];
+    assert_eq!(encoder.encode(b"abra", &mut output), Ok((4, 0)));
+    let bits = code[b'a'].bit_len * 2 + code[b'b'].bit_len + code[b'r'].bit_len;
+    assert_eq!(
+        encoder.finish(),
+        Some((reference_encode(&code, b"abra")[0], bits))
+    );
+    assert_eq!(encoder.finish(), None);
+    assert_eq!(encoder.encoded_bits(), 64);
+}
+
+#[test]
+fn test_write_words() {
+    // Longer than the conversion buffer, and not a multiple of it.
+    let words: Vec<u64> = (0..1300u64)
+        .map(|i| i.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+        .collect();
+    let mut output = vec![];
+    write_words(&mut output, &words).unwrap();
+    let expected: Vec<u8> = words
+        .iter()
+        .flat_map(|w| w.to_le_bytes().to_vec())
+        .collect();
+    assert_eq!(output, expected);
+
+    let mut output = vec![];
+    write_words(&mut output, &[]).unwrap();
+    assert_eq!(output, vec![]);
+}
+
+#[test]
+fn test_write_words_short_and_interrupted() {
+    let words = [
+        0x0706050403020100u64,
+        0x0f0e0d0c0b0a0908,
+        0x1716151413121110,
+    ];
+    let mut output = bitstream::Flaky::new(vec![], 5, &[0, 2, 3]);
+    write_words(&mut output, &words).unwrap();
+    assert_eq!(output.inner, (0..24).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_encode_stream_flaky_io() {
+    let input = b"abracadabra".repeat(2000);
+    let code = tree::tree_to_code(&tree::build_tree(&tree::compute_frequencies(&input)));
+    let mut expected = vec![];
+    encode_stream(&code, &input[..], &mut expected).unwrap();
+
+    let flaky_input = bitstream::Flaky::new(&input[..], 1000, &[0, 1, 5, 20]);
+    let mut output = bitstream::Flaky::new(vec![], 3000, &[0, 2, 3, 7]);
+    let len = encode_stream(&code, flaky_input, &mut output).unwrap();
+    assert_eq!(len, expected.len() as u64);
+    assert_eq!(output.inner, expected);
+
+    // Genuine errors go through, with context.
+    let mut full = [0u8; 16];
+    let err = encode_stream(&code, &input[..], &mut full[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    assert!(err.to_string().starts_with("writing output: "));
+}
+
+#[test]
+fn test_encoded_bits_past_32_bits() {
+    let code = tree::tree_to_code(&tree::build_tree(&vec![(b'a', 2), (b'b', 1)]));
+    let mut encoder = Encoder::new(&code);
+    // As if 1 GiB had been written already: 2^33 bits.
+    encoder.words_written = 1 << 27;
+    let mut output = [0u64; MIN_OUTPUT_WORDS];
+    assert_eq!(encoder.encode(b"ab", &mut output), Ok((2, 0)));
+    assert_eq!(encoder.encoded_bits(), (1 << 33) + 2);
+    // The budget is in bytes, and also past 32 bits.
+    assert_eq!(
+        encoder.encode_within(b"b", &mut output, 1 << 30),
+        Err(EncodeWithinError::BudgetExhausted)
+    );
+    assert_eq!(
+        encoder.encode_within(b"b", &mut output, (1 << 30) + 8),
+        Ok((1, 0))
+    );
+}
+
+#[test]
+fn test_encode_stream_parallel() {
+    // Codewords of up to 89 bits, so blocks end at all kinds of bit offsets.
+    let code = tree::tree_to_code(&tree::build_tree(&fibonacci_frequencies(90)));
+    let input: Vec<u8> = (0..200_000u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8 % 90)
+        .collect();
+    let mut expected = vec![];
+    let expected_bytes = encode_stream(&code, &input[..], &mut expected).unwrap();
+    assert_eq!(expected_bytes, expected.len() as u64);
+    for &threads in &[1, 2, 3, 8] {
+        for &block_len in &[7, 1000, 4099, 1 << 20] {
+            let mut output = vec![];
+            let (output_bytes, peak_in_flight) =
+                encode_blocks(&code, &input[..], &mut output, threads, block_len).unwrap();
+            assert!(
+                output == expected,
+                "{} threads, {} bytes",
+                threads,
+                block_len
+            );
+            assert_eq!(output_bytes, expected_bytes);
+            // Blocks in flight are bounded, and there's more than one when there are enough.
+            assert!(peak_in_flight <= 2 * threads);
+            if block_len < input.len() / (2 * threads) {
+                assert_eq!(peak_in_flight, 2 * threads);
+            }
+        }
+    }
+
+    let mut output = vec![];
+    assert_eq!(
+        encode_stream_parallel(&code, &b""[..], &mut output, 4).unwrap(),
+        0
+    );
+    assert_eq!(output, b"");
+}
+
+#[test]
+fn test_encode_to_writer() {
+    /// Counts the calls to `write`.
+    struct Counting {
+        inner: Vec<u8>,
+        writes: usize,
+    }
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let input: Vec<u8> = (0..1_000_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8 % 30)
+        .collect();
+    // With 90 symbols, the rarest ones in the input get codewords longer than a word.
+    for &num_symbols in &[30, 90] {
+        let code = tree::tree_to_code(&tree::build_tree(&fibonacci_frequencies(num_symbols)));
+        // The loop callers used to write by hand.
+        let mut expected = vec![];
+        let mut encoder = Encoder::new(&code);
+        let mut buf = [0u64; 1000];
+        let mut input_off = 0;
+        while input_off < input.len() {
+            let (input_consumed, output_len) =
+                encoder.encode(&input[input_off..], &mut buf).unwrap();
+            input_off += input_consumed;
+            write_words(&mut expected, &buf[..output_len]).unwrap();
+        }
+        let (word, bits) = encoder.finish().unwrap();
+        expected.extend_from_slice(&word.to_le_bytes()[..bits.div_ceil(8)]);
+
+        for &chunk_len in &[1, 1000, 100_000, input.len()] {
+            let mut output = Counting {
+                inner: vec![],
+                writes: 0,
+            };
+            let mut encoder = Encoder::new(&code);
+            let mut output_bytes = 0;
+            for chunk in input.chunks(chunk_len) {
+                output_bytes += encoder.encode_to_writer(chunk, &mut output).unwrap();
+            }
+            if num_symbols == 30 {
+                // Every codeword fits in a word, so only full buffers are written before the end.
+                assert_eq!(output_bytes % (WRITER_BUF_WORDS as u64 * 8), 0);
+            }
+            output_bytes += encoder.finish_to_writer(&mut output).unwrap();
+            assert_eq!(output_bytes, expected.len() as u64);
+            assert!(output.inner == expected, "chunk length {}", chunk_len);
+            // `write_words` writes 4 KiB at a time.
+            assert!(output.writes <= expected.len() / 4096 + 2);
+        }
+    }
+}
+
+#[test]
+fn test_encode_to_writer_errors() {
+    let code = tree::tree_to_code(&tree::build_tree(&vec![(b'a', 2), (b'b', 1)]));
+    let input = b"aab".repeat(1 << 20);
+    // Runs out of space in the middle of flushing the buffer.
+    let mut space = vec![0u8; WRITER_BUF_WORDS * 8 + 100];
+    let mut output = &mut space[..];
+    let mut encoder = Encoder::new(&code);
+    let err = encoder.encode_to_writer(&input, &mut output).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    assert!(err.to_string().starts_with("writing output: "));
+
+    let mut encoder = Encoder::new(&code);
+    assert_eq!(encoder.encode_to_writer(b"ab", &mut vec![]).unwrap(), 0);
+    let err = encoder.encode_to_writer(b"abc", &mut vec![]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        "symbol 99 at input offset 4 has no codeword"
+    );
+
+    let mut encoder = Encoder::new(&code);
+    encoder.encode_to_writer(b"aab", &mut vec![]).unwrap();
+    let mut output = &mut [][..];
+    let err = encoder.finish_to_writer(&mut output).unwrap_err();
+    assert!(err.to_string().starts_with("writing output: "));
+}
+
+#[test]
+fn test_encode_stream_parallel_errors() {
+    let code = tree::tree_to_code(&tree::build_tree(&vec![(b'a', 2), (b'b', 1)]));
+    // The position of an uncoded symbol is counted from the start of the input.
+    let mut input = b"ab".repeat(10000);
+    input[12345] = b'z';
+    let err = encode_blocks(&code, &input[..], vec![], 4, 1000).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        "symbol 122 at input offset 12345 has no codeword"
+    );
+
+    let input = b"ab".repeat(10000);
+    let err = encode_blocks(
+        &code,
+        bitstream::Flaky::new(&input[..], 100, &[]),
+        bitstream::Flaky::new(vec![], 0, &[]),
+        4,
+        1000,
+    )
+    .unwrap_err();
+    assert!(err.to_string().starts_with("writing output: "));
+}
+
+#[test]
+fn test_encode_const_code() {
+    // a: 0, b: 10, c: 11, and d with a codeword longer than a word, fixed at compile time.
+    static CODE: [(u8, Codeword); 4] = [
+        (b'a', Codeword::from_raw(1, [0b0, 0, 0, 0])),
+        (b'b', Codeword::from_raw(2, [0b01, 0, 0, 0])),
+        (b'c', Codeword::from_raw(3, [0b011, 0, 0, 0])),
+        (b'd', Codeword::from_raw(67, [u64::MAX, 0b111, 0, 0])),
+    ];
+    let mut code = Code::empty();
+    for (sym, cw) in &CODE {
+        code[*sym] = cw.clone();
+    }
+    assert_eq!(code[b'c'].to_string(), "110");
+    assert_eq!(code[b'd'], Codeword::from_bits(&[tree::B1; 67]));
+    // 0, 10, 110, 0, from the lowest bit up.
+    assert_eq!(encode_with(&code, b"abca"), vec![0b001_1010]);
 }
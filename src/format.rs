@@ -0,0 +1,1009 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use crate::decode::{DecodeError, Decoder};
+use crate::tree::{self, CanonicalError, Code, Frequencies, Lengths};
+
+/// Magic bytes at the start of every compressed stream.
+pub const MAGIC: [u8; 4] = *b"HUFF";
+
+/// Version of the container format.
+pub const VERSION: u8 = 1;
+
+/// Version of the container format with a metadata field after the code table. Only used when
+/// there is metadata, so that other streams stay readable by version 1 readers.
+pub const VERSION_WITH_METADATA: u8 = 2;
+
+/// Version of the container format whose payload is a sequence of blocks (see `BlockHeader`).
+/// Its metadata field is preceded by a byte telling whether there is any.
+pub const VERSION_BLOCKS: u8 = 3;
+
+/// Version of the container format whose payload is the original data as it is, with no code
+/// table. Like in `VERSION_BLOCKS`, the metadata field is preceded by a byte telling whether there
+/// is any.
+pub const VERSION_STORED: u8 = 4;
+
+/// Strategy for building the code, which also determines the kind of code table stored in the
+/// header.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Mode {
+    /// Plain Huffman code. The header stores the frequencies, and the decoder rebuilds the tree.
+    Plain,
+    /// Canonical Huffman code, with the same codeword lengths as `Plain`. The header stores only
+    /// the lengths.
+    Canonical,
+    /// Canonical code with codewords of at most `tree::DEFLATE_MAX_CODE_LENGTH` bits. The header
+    /// stores only the lengths.
+    LengthLimited,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "plain" => Ok(Mode::Plain),
+            "canonical" => Ok(Mode::Canonical),
+            "limited" | "length-limited" => Ok(Mode::LengthLimited),
+            _ => Err(format!(
+                "unknown mode {:?}, expected plain, canonical or limited",
+                s
+            )),
+        }
+    }
+}
+
+/// The information the decoder needs to reconstruct the code.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum CodeTable {
+    Frequencies(Frequencies),
+    Lengths(Lengths),
+    /// Frequencies written before ties in `tree::build_tree` were broken explicitly, which are
+    /// decoded with `tree::build_tree_legacy`. Only read, never built by `CodeTable::new`.
+    LegacyFrequencies(Frequencies),
+}
+
+impl CodeTable {
+    /// Build the code table for the given frequencies.
+    ///
+    /// Symbols with a count of zero are left out of the table.
+    pub fn new(frequencies: &Frequencies, mode: Mode) -> Self {
+        let frequencies: &Frequencies = &tree::nonzero_frequencies(frequencies).collect();
+        if frequencies.is_empty() {
+            return match mode {
+                Mode::Plain => CodeTable::Frequencies(vec![]),
+                Mode::Canonical | Mode::LengthLimited => CodeTable::Lengths(vec![]),
+            };
+        }
+        match mode {
+            Mode::Plain => CodeTable::Frequencies(frequencies.clone()),
+            Mode::Canonical => CodeTable::Lengths(tree::code_lengths(
+                &tree::build_tree_arena(frequencies).to_code_with(tree::SingleLeaf::OneBit),
+            )),
+            Mode::LengthLimited => CodeTable::Lengths(tree::length_limited_lengths(
+                frequencies,
+                tree::DEFLATE_MAX_CODE_LENGTH,
+            )),
+        }
+    }
+
+    /// Number of symbols in the table.
+    pub fn len(&self) -> usize {
+        match self {
+            CodeTable::Frequencies(frequencies) | CodeTable::LegacyFrequencies(frequencies) => {
+                frequencies.len()
+            }
+            CodeTable::Lengths(lengths) => lengths.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check everything `write_header` guarantees about a table for `original_len` symbols, and
+    /// that the table describes a valid code. Both `write_header` and `read_header` go through
+    /// this, so a header that's read successfully can always be decoded.
+    pub fn validate(&self, original_len: u64) -> Result<(), TableError> {
+        if self.len() > tree::NUM_SYMBOLS {
+            return Err(TableError::TooManySymbols(self.len()));
+        }
+        if self.is_empty() != (original_len == 0) {
+            return Err(TableError::EmptyMismatch);
+        }
+        let entries: Vec<(u8, u64)> = match self {
+            CodeTable::Frequencies(frequencies) | CodeTable::LegacyFrequencies(frequencies) => {
+                frequencies.clone()
+            }
+            CodeTable::Lengths(lengths) => lengths
+                .iter()
+                .map(|&(sym, len)| (sym, len as u64))
+                .collect(),
+        };
+        for (i, &(sym, value)) in entries.iter().enumerate() {
+            if value == 0 {
+                return Err(TableError::ZeroValue(sym));
+            }
+            if i > 0 && entries[i - 1].0 >= sym {
+                return Err(TableError::UnsortedSymbol(sym));
+            }
+        }
+        match self {
+            CodeTable::Frequencies(frequencies) | CodeTable::LegacyFrequencies(frequencies) => {
+                let total = frequencies
+                    .iter()
+                    .try_fold(0u64, |total, &(_, freq)| total.checked_add(freq));
+                if total != Some(original_len) {
+                    return Err(TableError::CountMismatch {
+                        declared: original_len,
+                        total,
+                    });
+                }
+            }
+            CodeTable::Lengths(lengths) if !lengths.is_empty() => {
+                tree::canonical_code(lengths).map_err(TableError::Lengths)?;
+            }
+            CodeTable::Lengths(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Reconstruct the code. The table must not be empty.
+    pub fn to_code(&self) -> Result<Code, CanonicalError> {
+        match self {
+            CodeTable::Frequencies(frequencies) => {
+                Ok(tree::build_tree_arena(frequencies).to_code_with(tree::SingleLeaf::OneBit))
+            }
+            CodeTable::LegacyFrequencies(frequencies) => {
+                Ok(tree::tree_to_code(&tree::build_tree_legacy(frequencies)))
+            }
+            CodeTable::Lengths(lengths) => tree::canonical_code(lengths),
+        }
+    }
+
+    /// Build a decoder for the code, like `Decoder::new(&self.to_code()?)`. Frequency tables
+    /// skip the codewords, building the decoder from the tree. The table must not be empty.
+    pub fn to_decoder(&self) -> Result<Decoder, CanonicalError> {
+        match self {
+            CodeTable::Frequencies(frequencies) => Ok(Decoder::from_tree_arena(
+                &tree::build_tree_arena(frequencies),
+                tree::SingleLeaf::OneBit,
+            )),
+            _ => Ok(Decoder::new(&self.to_code()?)),
+        }
+    }
+}
+
+/// Header of a compressed stream.
+///
+/// The container consists of:
+///
+/// - `MAGIC`, followed by a `VERSION` byte,
+/// - the length of the original data, as a little-endian `u64`,
+/// - except in `VERSION_STORED`, the kind of code table: `2` for frequencies, `1` for codeword
+///   lengths, or `0` for frequencies from before ties were broken explicitly (see
+///   `CodeTable::LegacyFrequencies`),
+/// - the number of entries in the code table, as a little-endian `u16`,
+/// - for each entry, ordered by symbol, the symbol byte followed by either its count as a
+///   little-endian `u64`, or its codeword length as a byte,
+/// - in `VERSION_BLOCKS` and `VERSION_STORED` only: `1` if there is metadata, `0` if not,
+/// - in `VERSION_WITH_METADATA`, or the later versions with metadata: the length of the metadata
+///   as a little-endian `u32`, followed by the metadata in UTF-8,
+/// - the payload: the encoded bitstream (see `encode::write_words`), up to the end of the data.
+///   It ends at the first byte boundary after the last codeword, padded with zero bits. In
+///   `VERSION_BLOCKS`, the payload is a sequence of blocks instead (see `BlockHeader`), and in
+///   `VERSION_STORED`, the original data.
+///
+/// The decoder reconstructs the code from the table, and decodes exactly `original_len`
+/// symbols, ignoring the padding bits at the end of the payload. This matters for inputs with a
+/// single distinct byte, where the lone symbol gets the codeword `0` (see `tree::SingleLeaf`):
+/// the zero padding bits would decode as valid symbols too, so only the length tells where the
+/// data ends.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Header {
+    pub original_len: u64,
+    /// The code of the payload; for `Payload::Stored`, not written, and read back empty.
+    pub table: CodeTable,
+    /// Free-form information for tools, e.g. the original file name. Not used for decoding.
+    pub metadata: Option<String>,
+    pub payload: Payload,
+}
+
+/// What follows the header.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Payload {
+    /// A single bitstream, encoded with the code of the header.
+    Bitstream,
+    /// A sequence of blocks, see `BlockHeader`. Written as `VERSION_BLOCKS`.
+    Blocks,
+    /// The original data as it is, for inputs that coding would make larger. Written as
+    /// `VERSION_STORED`.
+    Stored,
+}
+
+impl Header {
+    pub fn metadata(&self) -> Option<&str> {
+        self.metadata.as_deref()
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data doesn't start with `MAGIC`.
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The header or a block header is truncated or malformed, or the metadata isn't valid
+    /// UTF-8.
+    CorruptHeader,
+    /// The code table in the header is malformed, or doesn't match the declared length.
+    InvalidCodeTable(TableError),
+    /// The header declares more output than the caller allows.
+    LimitExceeded {
+        limit: u64,
+        declared: u64,
+    },
+    Decode(DecodeError),
+    /// Writing the decompressed data failed (only when streaming, see `decompress_stream`).
+    Write(io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::BadMagic => f.write_str("not a huff stream (bad magic bytes)"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {}", version)
+            }
+            Error::CorruptHeader => f.write_str("corrupt header"),
+            Error::InvalidCodeTable(err) => write!(f, "invalid code table: {}", err),
+            Error::LimitExceeded { limit, declared } => write!(
+                f,
+                "output of {} bytes exceeds the limit of {} bytes",
+                declared, limit
+            ),
+            Error::Decode(err) => write!(f, "corrupt payload: {}", err),
+            Error::Write(err) => write!(f, "writing output: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<DecodeError> for Error {
+    fn from(err: DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<TableError> for Error {
+    fn from(err: TableError) -> Self {
+        Error::InvalidCodeTable(err)
+    }
+}
+
+impl From<CanonicalError> for Error {
+    fn from(err: CanonicalError) -> Self {
+        Error::InvalidCodeTable(TableError::Lengths(err))
+    }
+}
+
+/// Why a code table was rejected by `CodeTable::validate`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TableError {
+    UnknownKind(u8),
+    /// More entries than there are symbols.
+    TooManySymbols(usize),
+    /// The table is empty but the declared length isn't, or the other way around.
+    EmptyMismatch,
+    /// The symbol has a count or length of zero.
+    ZeroValue(u8),
+    /// The symbol is out of order, or a duplicate.
+    UnsortedSymbol(u8),
+    /// The counts don't add up to the declared length (`None` if the sum overflows).
+    CountMismatch {
+        declared: u64,
+        total: Option<u64>,
+    },
+    /// The lengths don't describe a prefix code.
+    Lengths(CanonicalError),
+}
+
+impl std::fmt::Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TableError::UnknownKind(kind) => write!(f, "unknown table kind {}", kind),
+            TableError::TooManySymbols(n) => write!(f, "{} entries, at most 256 allowed", n),
+            TableError::EmptyMismatch => {
+                f.write_str("table is empty but the data isn't, or vice versa")
+            }
+            TableError::ZeroValue(sym) => write!(f, "symbol {} has a zero entry", sym),
+            TableError::UnsortedSymbol(sym) => write!(f, "symbol {} is out of order", sym),
+            TableError::CountMismatch {
+                declared,
+                total: Some(total),
+            } => write!(f, "counts add up to {}, not {}", total, declared),
+            TableError::CountMismatch {
+                declared,
+                total: None,
+            } => write!(f, "counts overflow, expected {} in total", declared),
+            TableError::Lengths(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::CorruptHeader
+        } else {
+            Error::Decode(DecodeError::Io(err))
+        }
+    }
+}
+
+const TABLE_LEGACY_FREQUENCIES: u8 = 0;
+const TABLE_LENGTHS: u8 = 1;
+const TABLE_FREQUENCIES: u8 = 2;
+
+/// Write a header, as described in `Header`, leaving the payload to the caller.
+///
+/// Fails with `io::ErrorKind::InvalidInput` if the metadata is longer than `u32::MAX` bytes, or
+/// if the table doesn't pass `CodeTable::validate`.
+pub fn write_header<W: Write>(output: &mut W, header: &Header) -> io::Result<()> {
+    if header.payload != Payload::Stored {
+        header
+            .table
+            .validate(header.original_len)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    }
+    let metadata_len = match &header.metadata {
+        Some(metadata) => Some(u32::try_from(metadata.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "header metadata is too long")
+        })?),
+        None => None,
+    };
+    output.write_all(&MAGIC)?;
+    let version = match (header.payload, metadata_len) {
+        (Payload::Stored, _) => VERSION_STORED,
+        (Payload::Blocks, _) => VERSION_BLOCKS,
+        (Payload::Bitstream, Some(_)) => VERSION_WITH_METADATA,
+        (Payload::Bitstream, None) => VERSION,
+    };
+    output.write_all(&[version])?;
+    output.write_all(&header.original_len.to_le_bytes())?;
+    if header.payload != Payload::Stored {
+        write_table(output, &header.table)?;
+    }
+    if header.payload != Payload::Bitstream {
+        output.write_all(&[metadata_len.is_some() as u8])?;
+    }
+    if let (Some(metadata), Some(len)) = (&header.metadata, metadata_len) {
+        output.write_all(&len.to_le_bytes())?;
+        output.write_all(metadata.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read a header, leaving `input` positioned at the start of the payload.
+///
+/// The input is untrusted: the table is validated with `CodeTable::validate`, and nothing is
+/// allocated based on declared sizes beyond what's actually read.
+pub fn read_header<R: Read>(input: &mut R) -> Result<Header, Error> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let version = read_u8(input)?;
+    let payload = match version {
+        VERSION | VERSION_WITH_METADATA => Payload::Bitstream,
+        VERSION_BLOCKS => Payload::Blocks,
+        VERSION_STORED => Payload::Stored,
+        _ => return Err(Error::UnsupportedVersion(version)),
+    };
+    let original_len = read_u64(input)?;
+    let table = if payload == Payload::Stored {
+        CodeTable::Frequencies(vec![])
+    } else {
+        let table = read_table(input)?;
+        table.validate(original_len)?;
+        table
+    };
+    let has_metadata = match version {
+        VERSION => false,
+        VERSION_WITH_METADATA => true,
+        _ => match read_u8(input)? {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::CorruptHeader),
+        },
+    };
+    let metadata = if has_metadata {
+        let mut len_bytes = [0u8; 4];
+        input.read_exact(&mut len_bytes)?;
+        let len = u32::from_le_bytes(len_bytes) as u64;
+        // Don't trust the length for the allocation, the data may be truncated.
+        let mut bytes = vec![];
+        input.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(Error::CorruptHeader);
+        }
+        Some(String::from_utf8(bytes).map_err(|_| Error::CorruptHeader)?)
+    } else {
+        None
+    };
+    Ok(Header {
+        original_len,
+        table,
+        metadata,
+        payload,
+    })
+}
+
+/// Header of a block in the payload of a `VERSION_BLOCKS` stream, which lets parts of the data
+/// with different statistics have codes of their own.
+///
+/// Each block consists of:
+///
+/// - the number of original bytes in the block, as a little-endian `u32`, never 0,
+/// - how the block is coded: `0` with the code of the stream header, `1` with a code of its own,
+///   followed by its code table, laid out like in the stream header, or `2` not at all,
+/// - for a coded block, the encoded bitstream, padded with zero bits to the next byte boundary,
+///   or for a stored block, its bytes as they are.
+///
+/// The lengths of the blocks add up to the `original_len` of the stream header.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct BlockHeader {
+    pub len: u32,
+    pub coding: BlockCoding,
+}
+
+/// How the bytes of a block are stored, see `BlockHeader`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum BlockCoding {
+    /// Encoded with the code of the stream header.
+    Shared,
+    /// Encoded with a code of its own.
+    Own(CodeTable),
+    /// Not encoded, for data that Huffman coding would expand, like already compressed data.
+    Stored,
+}
+
+const BLOCK_SHARED: u8 = 0;
+const BLOCK_OWN: u8 = 1;
+const BLOCK_STORED: u8 = 2;
+
+/// Write a block header, validating its table like `write_header` does.
+pub fn write_block_header<W: Write>(output: &mut W, header: &BlockHeader) -> io::Result<()> {
+    output.write_all(&header.len.to_le_bytes())?;
+    match &header.coding {
+        BlockCoding::Shared => output.write_all(&[BLOCK_SHARED]),
+        BlockCoding::Own(table) => {
+            table
+                .validate(header.len as u64)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            output.write_all(&[BLOCK_OWN])?;
+            write_table(output, table)
+        }
+        BlockCoding::Stored => output.write_all(&[BLOCK_STORED]),
+    }
+}
+
+/// Read a block header, leaving `input` positioned at the start of its bitstream. At most
+/// `remaining` bytes of the stream are left to decode, so a longer block is corrupt.
+pub fn read_block_header<R: Read>(input: &mut R, remaining: u64) -> Result<BlockHeader, Error> {
+    let mut len_bytes = [0u8; 4];
+    input.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes);
+    if len == 0 || len as u64 > remaining {
+        return Err(Error::CorruptHeader);
+    }
+    let coding = match read_u8(input)? {
+        BLOCK_SHARED => BlockCoding::Shared,
+        BLOCK_OWN => {
+            let table = read_table(input)?;
+            table.validate(len as u64)?;
+            BlockCoding::Own(table)
+        }
+        BLOCK_STORED => BlockCoding::Stored,
+        _ => return Err(Error::CorruptHeader),
+    };
+    Ok(BlockHeader { len, coding })
+}
+
+/// Write the kind of `table`, its number of entries and the entries.
+fn write_table<W: Write>(output: &mut W, table: &CodeTable) -> io::Result<()> {
+    match table {
+        CodeTable::Frequencies(frequencies) | CodeTable::LegacyFrequencies(frequencies) => {
+            let kind = match table {
+                CodeTable::LegacyFrequencies(_) => TABLE_LEGACY_FREQUENCIES,
+                _ => TABLE_FREQUENCIES,
+            };
+            output.write_all(&[kind])?;
+            output.write_all(&(frequencies.len() as u16).to_le_bytes())?;
+            for &(sym, freq) in frequencies {
+                output.write_all(&[sym])?;
+                output.write_all(&freq.to_le_bytes())?;
+            }
+        }
+        CodeTable::Lengths(lengths) => {
+            output.write_all(&[TABLE_LENGTHS])?;
+            output.write_all(&(lengths.len() as u16).to_le_bytes())?;
+            for &(sym, len) in lengths {
+                output.write_all(&[sym, len as u8])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a table written by `write_table`, without validating it.
+fn read_table<R: Read>(input: &mut R) -> Result<CodeTable, Error> {
+    let kind = read_u8(input)?;
+    let mut len_bytes = [0u8; 2];
+    input.read_exact(&mut len_bytes)?;
+    let num_symbols = u16::from_le_bytes(len_bytes) as usize;
+    // Checked before reading the entries, so that we don't read further than necessary.
+    if ![TABLE_FREQUENCIES, TABLE_LENGTHS, TABLE_LEGACY_FREQUENCIES].contains(&kind) {
+        return Err(TableError::UnknownKind(kind).into());
+    }
+    if num_symbols > tree::NUM_SYMBOLS {
+        return Err(TableError::TooManySymbols(num_symbols).into());
+    }
+    let mut entries: Vec<(u8, u64)> = Vec::with_capacity(num_symbols);
+    for _ in 0..num_symbols {
+        let sym = read_u8(input)?;
+        let value = match kind {
+            TABLE_LENGTHS => read_u8(input)? as u64,
+            _ => read_u64(input)?,
+        };
+        entries.push((sym, value));
+    }
+    Ok(match kind {
+        TABLE_FREQUENCIES => CodeTable::Frequencies(entries),
+        TABLE_LEGACY_FREQUENCIES => CodeTable::LegacyFrequencies(entries),
+        _ => CodeTable::Lengths(
+            entries
+                .into_iter()
+                .map(|(sym, len)| (sym, len as usize))
+                .collect(),
+        ),
+    })
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Serialize frequencies as CSV, one `symbol,count` row per symbol, with symbols as decimal byte
+/// values.
+///
+/// ```
+/// assert_eq!(
+///     huff::format::frequencies_to_csv(&vec![(b'A', 6), (b'B', 4)]),
+///     "65,6\n66,4\n"
+/// );
+/// ```
+pub fn frequencies_to_csv(frequencies: &Frequencies) -> String {
+    let mut output = String::new();
+    for (sym, freq) in frequencies {
+        output.push_str(&format!("{},{}\n", sym, freq));
+    }
+    output
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum CsvError {
+    /// The row is not of the form `symbol,count`, or one of the values is out of range.
+    Malformed { line: usize },
+    /// The symbol already appeared on an earlier row.
+    DuplicateSymbol { line: usize, symbol: u8 },
+}
+
+impl std::fmt::Display for CsvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CsvError::Malformed { line } => write!(f, "line {}: expected `symbol,count`", line),
+            CsvError::DuplicateSymbol { line, symbol } => {
+                write!(f, "line {}: duplicate symbol {}", line, symbol)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CsvError {}
+
+/// Parse frequencies in the format produced by `frequencies_to_csv`.
+///
+/// Blank lines are ignored, and rows may come in any order; the result is sorted by symbol.
+/// Line numbers in errors start at 1.
+pub fn frequencies_from_csv(input: &str) -> Result<Frequencies, CsvError> {
+    let mut frequencies = Frequencies::new();
+    for (index, row) in input.lines().enumerate() {
+        let line = index + 1;
+        let row = row.trim();
+        if row.is_empty() {
+            continue;
+        }
+        let mut fields = row.split(',');
+        let (symbol, count) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(symbol), Some(count), None) => (symbol.trim(), count.trim()),
+            _ => return Err(CsvError::Malformed { line }),
+        };
+        let symbol: u8 = symbol.parse().map_err(|_| CsvError::Malformed { line })?;
+        let count: u64 = count.parse().map_err(|_| CsvError::Malformed { line })?;
+        if frequencies.iter().any(|&(s, _)| s == symbol) {
+            return Err(CsvError::DuplicateSymbol { line, symbol });
+        }
+        frequencies.push((symbol, count));
+    }
+    frequencies.sort();
+    Ok(frequencies)
+}
+
+#[test]
+fn test_frequencies_csv_round_trip() {
+    let frequencies = crate::tree::compute_frequencies(b"appends_a_given_slice\n\x00\xff");
+    assert_eq!(
+        frequencies_from_csv(&frequencies_to_csv(&frequencies)),
+        Ok(frequencies)
+    );
+}
+
+#[test]
+fn test_frequencies_from_csv_errors() {
+    assert_eq!(
+        frequencies_from_csv("65,6\n\n256,1\n"),
+        Err(CsvError::Malformed { line: 3 })
+    );
+    assert_eq!(
+        frequencies_from_csv("65,6,1"),
+        Err(CsvError::Malformed { line: 1 })
+    );
+    assert_eq!(
+        frequencies_from_csv("65,-1"),
+        Err(CsvError::Malformed { line: 1 })
+    );
+    assert_eq!(
+        frequencies_from_csv("65,6\n65,1"),
+        Err(CsvError::DuplicateSymbol {
+            line: 2,
+            symbol: 65
+        })
+    );
+}
+
+#[test]
+fn test_header_round_trip() {
+    for table in &[
+        CodeTable::Frequencies(vec![(b'A', 6), (b'B', 4), (b'C', 2)]),
+        CodeTable::Lengths(vec![(b'A', 1), (b'B', 2), (b'C', 2)]),
+        CodeTable::LegacyFrequencies(vec![(b'A', 6), (b'B', 4), (b'C', 2)]),
+    ] {
+        let payloads = [Payload::Bitstream, Payload::Blocks, Payload::Stored];
+        for (metadata, &payload) in [None, Some(""), Some("name=ünïcode.txt")]
+            .iter()
+            .flat_map(|metadata| payloads.iter().map(move |payload| (metadata, payload)))
+        {
+            let header = Header {
+                original_len: 12,
+                table: match payload {
+                    Payload::Stored => CodeTable::Frequencies(vec![]),
+                    _ => table.clone(),
+                },
+                metadata: metadata.map(String::from),
+                payload,
+            };
+            let mut bytes = vec![];
+            write_header(&mut bytes, &header).unwrap();
+            let version = match (payload, metadata) {
+                (Payload::Stored, _) => VERSION_STORED,
+                (Payload::Blocks, _) => VERSION_BLOCKS,
+                (Payload::Bitstream, Some(_)) => VERSION_WITH_METADATA,
+                (Payload::Bitstream, None) => VERSION,
+            };
+            assert_eq!(bytes[4], version);
+            bytes.push(0xff);
+            let mut input = &bytes[..];
+            let read = read_header(&mut input).unwrap();
+            assert_eq!(read, header);
+            assert_eq!(read.metadata(), *metadata);
+            assert_eq!(input, &[0xff]);
+        }
+    }
+}
+
+#[test]
+fn test_stored_header() {
+    // No table: the magic, the version, the length and the metadata flag.
+    let header = Header {
+        original_len: 1 << 40,
+        table: CodeTable::Frequencies(vec![(b'A', 6)]),
+        metadata: None,
+        payload: Payload::Stored,
+    };
+    let mut bytes = vec![];
+    write_header(&mut bytes, &header).unwrap();
+    assert_eq!(bytes.len(), 4 + 1 + 8 + 1);
+    let read = read_header(&mut &bytes[..]).unwrap();
+    assert_eq!(read.original_len, 1 << 40);
+    assert!(read.table.is_empty());
+    assert_eq!(read.payload, Payload::Stored);
+
+    bytes[13] = 2;
+    assert!(matches!(
+        read_header(&mut &bytes[..]),
+        Err(Error::CorruptHeader)
+    ));
+}
+
+#[test]
+fn test_read_header_metadata_errors() {
+    let header = Header {
+        original_len: 3,
+        table: CodeTable::Frequencies(vec![(b'A', 2), (b'B', 1)]),
+        metadata: Some("abc".to_string()),
+        payload: Payload::Bitstream,
+    };
+    let mut bytes = vec![];
+    write_header(&mut bytes, &header).unwrap();
+    assert!(matches!(
+        read_header(&mut &bytes[..bytes.len() - 1]),
+        Err(Error::CorruptHeader)
+    ));
+    let len = bytes.len();
+    bytes[len - 1] = 0xff;
+    assert!(matches!(
+        read_header(&mut &bytes[..]),
+        Err(Error::CorruptHeader)
+    ));
+}
+
+#[test]
+fn test_header_past_32_bits() {
+    // Field widths don't depend on the host.
+    let header = Header {
+        original_len: 6 << 30,
+        table: CodeTable::Frequencies(vec![(b'A', 5 << 30), (b'B', 1 << 30)]),
+        metadata: None,
+        payload: Payload::Bitstream,
+    };
+    let mut bytes = vec![];
+    write_header(&mut bytes, &header).unwrap();
+    assert_eq!(bytes.len(), 4 + 1 + 8 + 1 + 2 + 2 * (1 + 8));
+    assert_eq!(&bytes[5..13], &(6u64 << 30).to_le_bytes());
+    assert_eq!(read_header(&mut &bytes[..]).unwrap(), header);
+}
+
+#[test]
+fn test_header_single_symbol() {
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let header = Header {
+            original_len: 10000,
+            table: CodeTable::new(&vec![(b'x', 10000)], mode),
+            metadata: None,
+            payload: Payload::Bitstream,
+        };
+        assert_eq!(header.table.len(), 1);
+        let mut bytes = vec![];
+        write_header(&mut bytes, &header).unwrap();
+        assert_eq!(read_header(&mut &bytes[..]).unwrap(), header);
+        assert_eq!(format!("{}", header.table.to_code().unwrap()), "x: 0\n");
+    }
+}
+
+#[test]
+fn test_read_header_errors() {
+    assert!(matches!(
+        read_header(&mut &b"HUFZ"[..]),
+        Err(Error::BadMagic)
+    ));
+    assert!(matches!(
+        read_header(&mut &b"HUFF\x05"[..]),
+        Err(Error::UnsupportedVersion(5))
+    ));
+    assert!(matches!(
+        read_header(&mut &b"HUFF\x01\x00"[..]),
+        Err(Error::CorruptHeader)
+    ));
+}
+
+/// Header bytes with the given fields, not necessarily valid.
+#[cfg(test)]
+fn raw_header(original_len: u64, kind: u8, num_symbols: u16, entries: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&original_len.to_le_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(&num_symbols.to_le_bytes());
+    for entry in entries {
+        bytes.extend_from_slice(entry);
+    }
+    bytes
+}
+
+#[test]
+fn test_read_header_table_errors() {
+    let read_table_error = |bytes: Vec<u8>| match read_header(&mut &bytes[..]) {
+        Err(Error::InvalidCodeTable(err)) => Some(err),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => None,
+    };
+    let count = |sym: u8, count: u64| {
+        let mut entry = vec![sym];
+        entry.extend_from_slice(&count.to_le_bytes());
+        entry
+    };
+    let (a1, a2, b1) = (count(b'A', 1), count(b'A', 2), count(b'B', 1));
+
+    assert_eq!(read_table_error(raw_header(3, 0, 2, &[&a2, &b1])), None);
+    assert_eq!(
+        read_table_error(raw_header(3, 7, 2, &[&a2, &b1])),
+        Some(TableError::UnknownKind(7))
+    );
+    // Rejected before trying to read all the entries.
+    assert_eq!(
+        read_table_error(raw_header(3, 0, 0xffff, &[])),
+        Some(TableError::TooManySymbols(0xffff))
+    );
+    assert_eq!(
+        read_table_error(raw_header(3, 0, 0, &[])),
+        Some(TableError::EmptyMismatch)
+    );
+    assert_eq!(
+        read_table_error(raw_header(0, 0, 1, &[&a1])),
+        Some(TableError::EmptyMismatch)
+    );
+    assert_eq!(
+        read_table_error(raw_header(1, 0, 2, &[&a1, &count(b'B', 0)])),
+        Some(TableError::ZeroValue(b'B'))
+    );
+    assert_eq!(
+        read_table_error(raw_header(3, 0, 2, &[&b1, &a2])),
+        Some(TableError::UnsortedSymbol(b'A'))
+    );
+    assert_eq!(
+        read_table_error(raw_header(3, 0, 2, &[&a1, &a2])),
+        Some(TableError::UnsortedSymbol(b'A'))
+    );
+    assert_eq!(
+        read_table_error(raw_header(1 << 40, 0, 2, &[&a2, &b1])),
+        Some(TableError::CountMismatch {
+            declared: 1 << 40,
+            total: Some(3)
+        })
+    );
+    assert_eq!(
+        read_table_error(raw_header(
+            3,
+            0,
+            2,
+            &[&count(b'A', u64::MAX), &count(b'B', 4)]
+        )),
+        Some(TableError::CountMismatch {
+            declared: 3,
+            total: None
+        })
+    );
+    assert_eq!(
+        read_table_error(raw_header(3, 1, 3, &[b"A\x01", b"B\x01", b"C\x01"])),
+        Some(TableError::Lengths(CanonicalError::Oversubscribed))
+    );
+    assert_eq!(
+        read_table_error(raw_header(3, 1, 2, &[b"A\x01", b"B\x00"])),
+        Some(TableError::ZeroValue(b'B'))
+    );
+    // Truncated entries.
+    assert!(matches!(
+        read_header(&mut &raw_header(3, 0, 2, &[&a2])[..]),
+        Err(Error::CorruptHeader)
+    ));
+}
+
+#[test]
+fn test_write_header_validates() {
+    let mut bytes = vec![];
+    let err = write_header(
+        &mut bytes,
+        &Header {
+            original_len: 4,
+            table: CodeTable::Frequencies(vec![(b'A', 2), (b'B', 1)]),
+            metadata: None,
+            payload: Payload::Bitstream,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(bytes, vec![]);
+}
+
+#[test]
+fn test_code_table_zero_counts() {
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let table = CodeTable::new(&vec![(b'A', 2), (b'B', 0), (b'C', 1)], mode);
+        assert_eq!(table.len(), 2);
+        let header = Header {
+            original_len: 3,
+            table,
+            metadata: None,
+            payload: Payload::Bitstream,
+        };
+        let mut bytes = vec![];
+        write_header(&mut bytes, &header).unwrap();
+        assert_eq!(read_header(&mut &bytes[..]).unwrap(), header);
+        assert!(CodeTable::new(&vec![(b'A', 0)], mode).is_empty());
+    }
+}
+
+#[test]
+fn test_block_header_round_trip() {
+    for coding in &[
+        BlockCoding::Shared,
+        BlockCoding::Own(CodeTable::Frequencies(vec![
+            (b'A', 6),
+            (b'B', 4),
+            (b'C', 2),
+        ])),
+        BlockCoding::Own(CodeTable::Lengths(vec![(b'A', 1), (b'B', 2), (b'C', 2)])),
+        BlockCoding::Stored,
+    ] {
+        let header = BlockHeader {
+            len: 12,
+            coding: coding.clone(),
+        };
+        let mut bytes = vec![];
+        write_block_header(&mut bytes, &header).unwrap();
+        bytes.push(0xff);
+        let mut input = &bytes[..];
+        assert_eq!(read_block_header(&mut input, 12).unwrap(), header);
+        assert_eq!(input, &[0xff]);
+        assert!(matches!(
+            read_block_header(&mut &bytes[..], 11),
+            Err(Error::CorruptHeader)
+        ));
+    }
+
+    let header = BlockHeader {
+        len: 13,
+        coding: BlockCoding::Own(CodeTable::Frequencies(vec![
+            (b'A', 6),
+            (b'B', 4),
+            (b'C', 2),
+        ])),
+    };
+    let err = write_block_header(&mut vec![], &header).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_read_block_header_errors() {
+    for (bytes, remaining) in &[
+        (&b"\x00\x00\x00\x00\x00"[..], 10),
+        (b"\x05\x00\x00\x00\x03", 10),
+        (b"\x05\x00\x00", 10),
+        (b"\x05\x00\x00\x00\x01\x01\x01", 10),
+    ] {
+        assert!(matches!(
+            read_block_header(&mut &bytes[..], *remaining),
+            Err(Error::CorruptHeader)
+        ));
+    }
+    // The table has to match the length of the block.
+    let mut bytes = 5u32.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&[1, TABLE_FREQUENCIES, 1, 0, b'A']);
+    bytes.extend_from_slice(&4u64.to_le_bytes());
+    assert!(matches!(
+        read_block_header(&mut &bytes[..], 10),
+        Err(Error::InvalidCodeTable(TableError::CountMismatch { .. }))
+    ));
+}
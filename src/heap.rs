@@ -2,22 +2,338 @@
 // wrapping it in a negation
 #![allow(clippy::nonminimal_bool)]
 
+//! A binary min-heap stored in a plain `Vec`.
+//!
+//! `BinaryMinHeap` maintains the invariant that each element is `<=` its children, so the
+//! smallest element (by `Ord`) is always at the front. Elements that compare equal come out in
+//! unspecified order. To order by a key other than the element itself, wrap the elements in
+//! `Keyed`, or in `MaxKeyed` for a max-heap.
+//!
+//! ```
+//! use huff::heap::{BinaryMinHeap, Keyed};
+//!
+//! let mut queue = BinaryMinHeap::new();
+//! queue.push(Keyed::new(3, "low"));
+//! queue.push(Keyed::new(1, "high"));
+//! assert_eq!(queue.pop().map(Keyed::into_value), Some("high"));
+//! ```
+
+pub use keyed::{Keyed, MaxKeyed};
+
+/// A min-heap owning its elements, so that nothing else can break the heap invariant between
+/// operations.
+#[derive(Clone, Debug)]
+pub struct BinaryMinHeap<T: Ord> {
+    items: Vec<T>,
+}
+
+impl<T: Ord> BinaryMinHeap<T> {
+    pub fn new() -> Self {
+        BinaryMinHeap { items: Vec::new() }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        BinaryMinHeap {
+            items: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Add `x` to the heap. Takes O(log n) comparisons.
+    pub fn push(&mut self, x: T) {
+        self.items.push(x);
+        sift_up(&mut self.items);
+        self.validate();
+    }
+
+    /// Remove and return the smallest element, or `None` if the heap is empty. Takes O(log n)
+    /// comparisons.
+    ///
+    /// ```
+    /// let mut heap = huff::heap::BinaryMinHeap::new();
+    /// for x in [5, 1, 4] {
+    ///     heap.push(x);
+    /// }
+    /// assert_eq!(heap.pop(), Some(1));
+    /// assert_eq!(heap.pop(), Some(4));
+    /// assert_eq!(heap.pop(), Some(5));
+    /// assert_eq!(heap.pop(), None);
+    /// ```
+    pub fn pop(&mut self) -> Option<T> {
+        let item = pop_from(&mut self.items);
+        self.validate();
+        item
+    }
+
+    /// Remove and return the smallest element, and add `x`, with one sift instead of two: like
+    /// `pop` then `push`. If the heap is empty, only adds `x` and returns `None`. Takes O(log n)
+    /// comparisons.
+    ///
+    /// ```
+    /// let mut heap = huff::heap::BinaryMinHeap::from(vec![1, 3]);
+    /// assert_eq!(heap.replace(2), Some(1));
+    /// assert_eq!(heap.into_vec(), vec![2, 3]);
+    /// ```
+    pub fn replace(&mut self, x: T) -> Option<T> {
+        if self.is_empty() {
+            self.push(x);
+            return None;
+        }
+        let item = std::mem::replace(&mut self.items[0], x);
+        sift_down(&mut self.items, 0);
+        self.validate();
+        Some(item)
+    }
+
+    /// Add `x`, then remove and return the smallest element, with at most one sift: like `push`
+    /// then `pop`, except that `x` itself comes back if it's `<=` the smallest element. Takes
+    /// O(log n) comparisons, and O(1) in that case.
+    ///
+    /// ```
+    /// let mut heap = huff::heap::BinaryMinHeap::from(vec![1, 3]);
+    /// assert_eq!(heap.push_pop(0), 0);
+    /// assert_eq!(heap.push_pop(2), 1);
+    /// assert_eq!(heap.into_vec(), vec![2, 3]);
+    /// ```
+    pub fn push_pop(&mut self, x: T) -> T {
+        match self.items.first() {
+            Some(smallest) if *smallest < x => {
+                let item = std::mem::replace(&mut self.items[0], x);
+                sift_down(&mut self.items, 0);
+                self.validate();
+                item
+            }
+            _ => x,
+        }
+    }
+
+    /// The elements in ascending order. Takes O(n log n) comparisons, and no extra space.
+    ///
+    /// ```
+    /// let heap = huff::heap::BinaryMinHeap::from(vec![5, 1, 4]);
+    /// assert_eq!(heap.into_sorted_vec(), vec![1, 4, 5]);
+    /// ```
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        let mut items = self.items;
+        // Move the smallest element behind the rest of the heap, until all are in descending
+        // order.
+        for end in (1..items.len()).rev() {
+            items.swap(0, end);
+            sift_down(&mut items[..end], 0);
+        }
+        items.reverse();
+        items
+    }
+
+    /// An iterator that pops the elements in ascending order, taking O(log n) comparisons for
+    /// each. Elements that aren't reached stay in the heap.
+    ///
+    /// ```
+    /// let mut heap = huff::heap::BinaryMinHeap::from(vec![5, 1, 4]);
+    /// assert_eq!(heap.drain_sorted().next(), Some(1));
+    /// assert_eq!(heap.len(), 2);
+    /// ```
+    pub fn drain_sorted(&mut self) -> DrainSorted<'_, T> {
+        DrainSorted { heap: self }
+    }
+
+    /// The smallest element, without removing it, or `None` if the heap is empty. Takes O(1).
+    pub fn peek(&self) -> Option<&T> {
+        self.items.first()
+    }
+
+    /// A mutable reference to the smallest element, or `None` if the heap is empty. If the
+    /// element is changed through it, the heap invariant is restored when it's dropped, taking
+    /// O(log n) comparisons.
+    ///
+    /// ```
+    /// let mut heap = huff::heap::BinaryMinHeap::from(vec![1, 5, 4]);
+    /// if let Some(mut smallest) = heap.peek_mut() {
+    ///     *smallest = 7;
+    /// }
+    /// assert_eq!(heap.pop(), Some(4));
+    /// ```
+    pub fn peek_mut(&mut self) -> Option<PeekMut<'_, T>> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(PeekMut {
+                heap: self,
+                changed: false,
+            })
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The elements in heap order, which is not sorted order.
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+
+    /// Iterate over the elements in heap order, which is not sorted order.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.items.iter()
+    }
+
+    /// Check the heap invariant, in debug builds only, since it takes O(n) comparisons.
+    fn validate(&self) {
+        if cfg!(debug_assertions) {
+            for i in 1..self.items.len() {
+                assert!(
+                    self.items[parent(i)] <= self.items[i],
+                    "heap invariant broken between {} and {}",
+                    parent(i),
+                    i
+                );
+            }
+        }
+    }
+}
+
+/// The smallest element of a `BinaryMinHeap`, from `BinaryMinHeap::peek_mut`.
+pub struct PeekMut<'a, T: Ord> {
+    heap: &'a mut BinaryMinHeap<T>,
+    /// Whether the element may have been changed, so it has to be sifted down on drop.
+    changed: bool,
+}
+
+impl<T: Ord> std::ops::Deref for PeekMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.heap.items[0]
+    }
+}
+
+impl<T: Ord> std::ops::DerefMut for PeekMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.changed = true;
+        &mut self.heap.items[0]
+    }
+}
+
+impl<T: Ord> Drop for PeekMut<'_, T> {
+    fn drop(&mut self) {
+        if self.changed {
+            sift_down(&mut self.heap.items, 0);
+            self.heap.validate();
+        }
+    }
+}
+
+/// See `BinaryMinHeap::drain_sorted`.
+pub struct DrainSorted<'a, T: Ord> {
+    heap: &'a mut BinaryMinHeap<T>,
+}
+
+impl<T: Ord> Iterator for DrainSorted<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.len(), Some(self.heap.len()))
+    }
+}
+
+impl<T: Ord> ExactSizeIterator for DrainSorted<'_, T> {}
+
+impl<T: Ord> std::iter::FusedIterator for DrainSorted<'_, T> {}
+
+impl<T: Ord> Default for BinaryMinHeap<T> {
+    fn default() -> Self {
+        BinaryMinHeap::new()
+    }
+}
+
+/// Turn `items` into a heap, in place. Takes O(n) comparisons, compared to O(n log n) for
+/// pushing the items one by one.
+///
+/// The order of the items in the result may differ from the one `push` would give, so equal
+/// elements can also come out of `pop` in a different order.
+///
+/// ```
+/// let mut heap = huff::heap::BinaryMinHeap::from(vec![5, 1, 4]);
+/// assert_eq!(heap.pop(), Some(1));
+/// assert_eq!(heap.pop(), Some(4));
+/// ```
+impl<T: Ord> From<Vec<T>> for BinaryMinHeap<T> {
+    fn from(mut items: Vec<T>) -> Self {
+        // Leaves are heaps already; make each subtree above them a heap, bottom-up.
+        for i in (0..items.len() / 2).rev() {
+            sift_down(&mut items, i);
+        }
+        let heap = BinaryMinHeap { items };
+        heap.validate();
+        heap
+    }
+}
+
+/// Add `x` to the heap. Takes O(log n) comparisons.
+///
+/// `heap` must satisfy the heap invariant, e.g. by only ever being modified with these functions.
+#[deprecated(since = "0.1.0", note = "use `BinaryMinHeap::push`")]
 pub fn insert<T>(heap: &mut Vec<T>, x: T)
 where
     T: Ord,
 {
-    let mut i = heap.len();
     heap.push(x);
+    sift_up(heap);
+}
+
+/// Turn `items` into a heap, in place. Takes O(n) comparisons.
+#[deprecated(since = "0.1.0", note = "use `BinaryMinHeap::from`")]
+pub fn from_vec<T>(items: Vec<T>) -> Vec<T>
+where
+    T: Ord,
+{
+    BinaryMinHeap::from(items).into_vec()
+}
+
+/// Remove and return the smallest element, or `None` if the heap is empty.
+#[deprecated(since = "0.1.0", note = "use `BinaryMinHeap::pop`")]
+pub fn pop<T>(heap: &mut Vec<T>) -> Option<T>
+where
+    T: Ord,
+{
+    pop_from(heap)
+}
+
+/// The smallest element, without removing it, or `None` if the heap is empty.
+#[deprecated(since = "0.1.0", note = "use `BinaryMinHeap::peek`")]
+pub fn peek<T>(heap: &[T]) -> Option<&T>
+where
+    T: Ord,
+{
+    heap.first()
+}
+
+/// Move the last element up until its parent is `<=` it, given that the rest is a heap.
+fn sift_up<T>(heap: &mut [T])
+where
+    T: Ord,
+{
+    let mut i = heap.len().saturating_sub(1);
     while i > 0 {
-        // To maintain heap invariant, enforce heap[parent] <= heap[i].
-        if !(heap[parent(i)] <= heap[i]) {
-            heap.swap(i, parent(i));
+        // To maintain heap invariant, enforce heap[parent] <= heap[i]. Once it holds, it holds
+        // all the way up, since the rest of the heap is unchanged.
+        if heap[parent(i)] <= heap[i] {
+            break;
         }
+        heap.swap(i, parent(i));
         i = parent(i);
     }
 }
 
-pub fn pop<T>(heap: &mut Vec<T>) -> Option<T>
+fn pop_from<T>(heap: &mut Vec<T>) -> Option<T>
 where
     T: Ord,
 {
@@ -26,8 +342,15 @@ where
     }
 
     let item = heap.swap_remove(0);
+    sift_down_to_bottom(heap, 0);
+    Some(item)
+}
 
-    let mut i = 0;
+/// Move `heap[i]` down until it's `<=` its children, given that both of its subtrees are heaps.
+fn sift_down<T>(heap: &mut [T], mut i: usize)
+where
+    T: Ord,
+{
     while left_child(i) < heap.len() {
         let next_index =
             if right_child(i) < heap.len() && heap[right_child(i)] <= heap[left_child(i)] {
@@ -43,8 +366,31 @@ where
         heap.swap(i, next_index);
         i = next_index;
     }
+}
 
-    Some(item)
+/// `sift_down`, for an element that likely belongs near the bottom, like the last leaf that `pop`
+/// moves to the root: move it all the way down first, taking one comparison per level to pick
+/// the smaller child rather than two, then back up past the elements that aren't smaller than it.
+/// That leaves it in the same place `sift_down` would, even among equal elements.
+fn sift_down_to_bottom<T>(heap: &mut [T], mut i: usize)
+where
+    T: Ord,
+{
+    let start = i;
+    while left_child(i) < heap.len() {
+        let next_index =
+            if right_child(i) < heap.len() && heap[right_child(i)] <= heap[left_child(i)] {
+                right_child(i)
+            } else {
+                left_child(i)
+            };
+        heap.swap(i, next_index);
+        i = next_index;
+    }
+    while i > start && !(heap[parent(i)] < heap[i]) {
+        heap.swap(i, parent(i));
+        i = parent(i);
+    }
 }
 
 fn parent(i: usize) -> usize {
@@ -60,6 +406,8 @@ fn right_child(i: usize) -> usize {
 pub mod keyed {
     use std::cmp::Ordering;
 
+    /// A heap element ordered by `key` alone; `value` is carried along and never compared.
+    #[derive(Clone, Debug)]
     pub struct Keyed<K, V> {
         pub key: K,
         pub value: V,
@@ -69,6 +417,18 @@ pub mod keyed {
         pub fn new(key: K, value: V) -> Self {
             Keyed { key, value }
         }
+
+        pub fn key(&self) -> &K {
+            &self.key
+        }
+
+        pub fn value(&self) -> &V {
+            &self.value
+        }
+
+        pub fn into_value(self) -> V {
+            self.value
+        }
     }
 
     impl<K, V> PartialEq for Keyed<K, V>
@@ -99,14 +459,70 @@ pub mod keyed {
             self.key.cmp(&other.key)
         }
     }
+
+    /// Like `Keyed`, but ordered in reverse by key, so that the heap functions build a max-heap.
+    #[derive(Clone, Debug)]
+    pub struct MaxKeyed<K, V> {
+        pub key: K,
+        pub value: V,
+    }
+
+    impl<K, V> MaxKeyed<K, V> {
+        pub fn new(key: K, value: V) -> Self {
+            MaxKeyed { key, value }
+        }
+
+        pub fn key(&self) -> &K {
+            &self.key
+        }
+
+        pub fn value(&self) -> &V {
+            &self.value
+        }
+
+        pub fn into_value(self) -> V {
+            self.value
+        }
+    }
+
+    impl<K, V> PartialEq for MaxKeyed<K, V>
+    where
+        K: PartialEq,
+    {
+        fn eq(&self, other: &Self) -> bool {
+            self.key == other.key
+        }
+    }
+
+    impl<K, V> Eq for MaxKeyed<K, V> where K: Eq {}
+
+    impl<K, V> PartialOrd for MaxKeyed<K, V>
+    where
+        K: PartialOrd,
+    {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            other.key.partial_cmp(&self.key)
+        }
+    }
+
+    impl<K, V> Ord for MaxKeyed<K, V>
+    where
+        K: Ord,
+    {
+        fn cmp(&self, other: &Self) -> Ordering {
+            other.key.cmp(&self.key)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use quickcheck::*;
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
 
-    fn invariant_holds<T>(heap: &Vec<T>) -> Result<(), (usize, &T, usize, &T)>
+    fn invariant_holds<T>(heap: &[T]) -> Result<(), (usize, &T, usize, &T)>
     where
         T: Ord,
     {
@@ -118,49 +534,55 @@ mod tests {
         Ok(())
     }
 
-    fn insert_all<T>(heap: &mut Vec<T>, items: Vec<T>)
+    fn insert_all<T>(heap: &mut BinaryMinHeap<T>, items: Vec<T>)
     where
         T: Ord,
     {
         for item in items.into_iter() {
-            insert(heap, item);
+            heap.push(item);
         }
     }
 
-    fn make_heap<T>(items: Vec<T>) -> Vec<T>
+    fn make_heap<T>(items: Vec<T>) -> BinaryMinHeap<T>
     where
         T: Ord,
     {
-        let mut heap = Vec::with_capacity(items.len());
+        let mut heap = BinaryMinHeap::with_capacity(items.len());
         insert_all(&mut heap, items);
         heap
     }
 
-    fn pop_all<T>(heap: &mut Vec<T>) -> Vec<T>
+    fn pop_all<T>(heap: &mut BinaryMinHeap<T>) -> Vec<T>
     where
         T: Ord,
     {
         let mut result = Vec::with_capacity(heap.len());
-        while let Some(item) = pop(heap) {
+        while let Some(item) = heap.pop() {
             result.push(item);
         }
         result
     }
 
-    fn heapsort<T>(items: Vec<T>) -> Vec<T>
+    /// `pop` as it was before `sift_down_to_bottom`, as a reference.
+    fn pop_with_sift_down<T>(heap: &mut Vec<T>) -> Option<T>
     where
         T: Ord,
     {
-        pop_all(&mut make_heap(items))
+        if heap.is_empty() {
+            return None;
+        }
+        let item = heap.swap_remove(0);
+        sift_down(heap, 0);
+        Some(item)
     }
 
     #[test]
     fn make_heap_examples() {
-        assert_eq!(make_heap(vec![1]), vec![1]);
-        assert_eq!(make_heap(vec![1, 0]), vec![0, 1]);
-        assert_eq!(make_heap(vec![0, 0]), vec![0, 0]);
+        assert_eq!(make_heap(vec![1]).into_vec(), vec![1]);
+        assert_eq!(make_heap(vec![1, 0]).into_vec(), vec![0, 1]);
+        assert_eq!(make_heap(vec![0, 0]).into_vec(), vec![0, 0]);
         assert_eq!(
-            make_heap(vec![1, 9, 8, 2, 7, 6, 3, 4, 5, 0]),
+            make_heap(vec![1, 9, 8, 2, 7, 6, 3, 4, 5, 0]).into_vec(),
             vec![0, 1, 3, 4, 2, 8, 6, 9, 5, 7]
         );
     }
@@ -174,9 +596,113 @@ mod tests {
         sorted
     }
 
+    #[test]
+    fn max_keyed_heap() {
+        use super::keyed::MaxKeyed;
+
+        let mut heap = BinaryMinHeap::new();
+        for &(key, value) in &[(3, 'c'), (1, 'a'), (4, 'd'), (1, 'a'), (5, 'e'), (2, 'b')] {
+            heap.push(MaxKeyed::new(key, value));
+        }
+        let mut popped = vec![];
+        while let Some(item) = heap.pop() {
+            popped.push((item.key, item.value));
+        }
+        assert_eq!(
+            popped,
+            vec![(5, 'e'), (4, 'd'), (3, 'c'), (2, 'b'), (1, 'a'), (1, 'a')]
+        );
+    }
+
+    #[test]
+    fn from_vec_examples() {
+        let from_vec = |items| BinaryMinHeap::from(items).into_vec();
+        assert_eq!(from_vec(Vec::<u8>::new()), vec![]);
+        assert_eq!(from_vec(vec![1]), vec![1]);
+        assert_eq!(from_vec(vec![1, 0]), vec![0, 1]);
+        assert_eq!(
+            from_vec(vec![1, 9, 8, 2, 7, 6, 3, 4, 5, 0]),
+            vec![0, 1, 3, 2, 7, 6, 8, 4, 5, 9]
+        );
+        // Sorted input is a heap already, and is left as it is.
+        assert_eq!(from_vec(vec![1, 2, 3, 4, 5, 6]), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(from_vec(vec![6, 5, 4, 3, 2, 1]), vec![1, 2, 4, 3, 5, 6]);
+        assert_eq!(from_vec(vec![7; 5]), vec![7; 5]);
+    }
+
+    #[test]
+    fn pop_empty() {
+        let mut heap: BinaryMinHeap<u8> = BinaryMinHeap::new();
+        assert!(heap.is_empty());
+        assert_eq!(heap.peek(), None);
+        assert_eq!(heap.pop(), None);
+        assert_eq!(heap.pop(), None);
+    }
+
+    #[test]
+    fn interleaved_insert_pop() {
+        let mut heap = BinaryMinHeap::new();
+        heap.push(5);
+        heap.push(3);
+        assert_eq!(heap.pop(), Some(3));
+        heap.push(4);
+        heap.push(1);
+        assert_eq!(heap.peek(), Some(&1));
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.pop(), Some(1));
+        heap.push(6);
+        assert_eq!(
+            sorted(&heap.iter().cloned().collect::<Vec<_>>()),
+            vec![4, 5, 6]
+        );
+        assert_eq!(pop_all(&mut heap), vec![4, 5, 6]);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn peek_mut_sifts_down() {
+        let mut heap = make_heap(vec![1, 2, 3, 4, 5]);
+        assert!(BinaryMinHeap::<u8>::new().peek_mut().is_none());
+        assert_eq!(heap.peek_mut().map(|smallest| *smallest), Some(1));
+        assert_eq!(heap.clone().into_vec(), vec![1, 2, 3, 4, 5]);
+        *heap.peek_mut().unwrap() = 6;
+        assert_eq!(heap.clone().into_vec(), vec![2, 4, 3, 6, 5]);
+        assert_eq!(pop_all(&mut heap), vec![2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn replace_and_push_pop_on_empty() {
+        let mut heap = BinaryMinHeap::new();
+        assert_eq!(heap.push_pop(1), 1);
+        assert!(heap.is_empty());
+        assert_eq!(heap.replace(2), None);
+        assert_eq!(heap.into_vec(), vec![2]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_functions() {
+        let mut heap = from_vec(vec![5, 3]);
+        insert(&mut heap, 4);
+        insert(&mut heap, 1);
+        assert_eq!(peek(&heap), Some(&1));
+        assert_eq!(pop(&mut heap), Some(1));
+        assert_eq!(heap, make_heap(vec![3, 5, 4]).into_vec());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "heap invariant broken between 1 and 3")]
+    fn validate_catches_broken_invariant() {
+        BinaryMinHeap {
+            items: vec![0, 2, 1, 0],
+        }
+        .push(3);
+    }
+
     quickcheck! {
         fn qc_make_heap(items: Vec<u8>) -> TestResult {
-            let heap = make_heap(items.clone());
+            let heap = make_heap(items.clone()).into_vec();
             if let Err(info) = invariant_holds(&heap) {
                 return TestResult::error(format!("Heap invariant not satisfied at {:?}.\nitems: {:?}\nheap:  {:?}", info, items, heap));
             }
@@ -186,8 +712,35 @@ mod tests {
             return TestResult::passed();
         }
 
+        fn qc_from_vec(items: Vec<u8>) -> TestResult {
+            let heap = BinaryMinHeap::from(items.clone());
+            if let Err(info) = invariant_holds(&heap.items) {
+                return TestResult::error(format!("Heap invariant not satisfied at {:?}.\nitems: {:?}\nheap:  {:?}", info, items, heap));
+            }
+            if pop_all(&mut heap.clone()) != sorted(&items) {
+                return TestResult::error(format!("Not sorted correctly.\nitems: {:?}\nheap:  {:?}", items, heap));
+            }
+            return TestResult::passed();
+        }
+
+        fn qc_from_vec_sorted_reversed_equal(items: Vec<u8>, x: u8) -> bool {
+            let mut reversed = sorted(&items);
+            reversed.reverse();
+            let equal = vec![x; items.len()];
+            [sorted(&items), reversed, equal].iter().all(|items| {
+                let mut heap = BinaryMinHeap::from(items.clone());
+                invariant_holds(&heap.items).is_ok() && pop_all(&mut heap) == sorted(items)
+            })
+        }
+
+        fn qc_from_vec_then_insert(items: Vec<u8>, more: Vec<u8>) -> bool {
+            let mut heap = BinaryMinHeap::from(items.clone());
+            insert_all(&mut heap, more.clone());
+            invariant_holds(&heap.items).is_ok() && pop_all(&mut heap) == sorted(&[items, more].concat())
+        }
+
         fn qc_heapsort(items: Vec<u8>) -> TestResult {
-            let sorted_items = heapsort(items.clone());
+            let sorted_items = make_heap(items.clone()).into_sorted_vec();
             if sorted_items != sorted(&items) {
                 return TestResult::error(format!("Not sorted correctly.\nitems:  {:?}\nsorted: {:?}", items, sorted_items));
             }
@@ -196,13 +749,143 @@ mod tests {
 
         fn qc_pop_preserves_heap_invariant(items: Vec<u8>) -> TestResult {
             let mut heap = make_heap(items.clone());
-            pop(&mut heap);
-            if let Err(info) = invariant_holds(&heap) {
+            heap.pop();
+            if let Err(info) = invariant_holds(&heap.items) {
                 return TestResult::error(format!("Heap invariant not satisfied at {:?}.\nitems: {:?}\nheap:  {:?}", info, items, heap));
             }
             return TestResult::passed();
         }
 
+        fn qc_interleaved_insert_pop(ops: Vec<Option<u8>>) -> TestResult {
+            // `Some(x)` inserts x, `None` pops; compare against a sorted Vec.
+            let mut heap = BinaryMinHeap::new();
+            let mut model: Vec<u8> = vec![];
+            for op in ops {
+                match op {
+                    Some(x) => {
+                        heap.push(x);
+                        model.push(x);
+                        model.sort();
+                    }
+                    None => {
+                        let expected = if model.is_empty() { None } else { Some(model.remove(0)) };
+                        if heap.pop() != expected {
+                            return TestResult::failed();
+                        }
+                    }
+                }
+                if heap.peek() != model.first() || heap.len() != model.len() || invariant_holds(&heap.items).is_err() {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+
+        fn qc_pop_matches_sift_down(items: Vec<u8>, ops: Vec<Option<u8>>) -> bool {
+            // Keyed by the item, with the position of insertion as value: items with equal keys
+            // have to end up in the same places too, not just come out equal. Then popped empty.
+            fn layout(heap: &[Keyed<u8, usize>]) -> Vec<(u8, usize)> {
+                heap.iter().map(|item| (item.key, item.value)).collect()
+            }
+            let mut heap = BinaryMinHeap::new();
+            let mut reference = vec![];
+            let mut binary_heap = BinaryHeap::new();
+            let num_items = items.len();
+            let ops = items.into_iter().map(Some).chain(ops).chain(vec![None; num_items]);
+            for (position, op) in ops.enumerate() {
+                match op {
+                    Some(x) => {
+                        heap.push(Keyed::new(x, position));
+                        reference.push(Keyed::new(x, position));
+                        sift_up(&mut reference);
+                        binary_heap.push(Reverse(x));
+                    }
+                    None => {
+                        let popped = heap.pop().map(|item| (item.key, item.value));
+                        let expected =
+                            pop_with_sift_down(&mut reference).map(|item| (item.key, item.value));
+                        let key = binary_heap.pop().map(|Reverse(x)| x);
+                        if popped != expected || popped.map(|(key, _)| key) != key {
+                            return false;
+                        }
+                    }
+                }
+                if layout(&heap.items) != layout(&reference) {
+                    return false;
+                }
+            }
+            true
+        }
+
+        fn qc_peek_is_next_pop(items: Vec<u8>) -> bool {
+            let mut heap = make_heap(items);
+            loop {
+                let peeked = heap.peek().cloned();
+                if heap.pop() != peeked {
+                    return false;
+                }
+                if peeked.is_none() {
+                    return true;
+                }
+            }
+        }
+
+        fn qc_peek_mut(items: Vec<u8>, x: u8) -> bool {
+            let mut heap = BinaryMinHeap::from(items.clone());
+            let mut expected = sorted(&items);
+            if let Some(mut smallest) = heap.peek_mut() {
+                *smallest = x;
+                expected[0] = x;
+            }
+            invariant_holds(&heap.items).is_ok() && pop_all(&mut heap) == sorted(&expected)
+        }
+
+        fn qc_replace_push_pop_match_pop_and_push(items: Vec<u8>, ops: Vec<(bool, u8)>) -> bool {
+            // `(true, x)` replaces, `(false, x)` push-pops; `naive` does the same with `push`
+            // and `pop`.
+            let mut heap = BinaryMinHeap::from(items.clone());
+            let mut naive = BinaryMinHeap::from(items);
+            for (is_replace, x) in ops {
+                let (got, expected) = if is_replace {
+                    let expected = naive.pop();
+                    naive.push(x);
+                    (heap.replace(x), expected)
+                } else {
+                    naive.push(x);
+                    (Some(heap.push_pop(x)), naive.pop())
+                };
+                if got != expected
+                    || invariant_holds(&heap.items).is_err()
+                    || sorted(&heap.items) != sorted(&naive.items)
+                {
+                    return false;
+                }
+            }
+            true
+        }
+
+        fn qc_into_sorted_vec(items: Vec<u8>) -> bool {
+            BinaryMinHeap::from(items.clone()).into_sorted_vec() == sorted(&items)
+        }
+
+        fn qc_drain_sorted_then_push(items: Vec<u8>, taken: usize, more: Vec<u8>) -> bool {
+            // Drain part of the heap, then push more into what's left.
+            let mut heap = BinaryMinHeap::from(items.clone());
+            let taken = taken % (items.len() + 1);
+            let drained: Vec<u8> = heap.drain_sorted().take(taken).collect();
+            let mut rest = sorted(&items);
+            if drained != rest.drain(..taken).collect::<Vec<_>>()
+                || invariant_holds(&heap.items).is_err()
+            {
+                return false;
+            }
+            insert_all(&mut heap, more.clone());
+            let drain = heap.drain_sorted();
+            drain.len() == rest.len() + more.len()
+                && drain.collect::<Vec<_>>() == sorted(&[rest, more].concat())
+                && heap.is_empty()
+        }
+
         fn qc_left_child_parent_id(i: usize) -> bool {
             parent(left_child(i)) == i
         }
@@ -1,8 +1,1530 @@
+#![cfg_attr(not(feature = "fast-unsafe"), forbid(unsafe_code))]
+// Only the accessors that the feature exists for may opt in.
+#![cfg_attr(feature = "fast-unsafe", deny(unsafe_code))]
+
 #[cfg(test)]
 extern crate quickcheck;
 #[cfg(test)]
 extern crate quickcheck_macros;
 
+pub mod bitstream;
+pub mod coder;
+// Public only for the integration tests and benchmarks, not part of the API.
+#[doc(hidden)]
+pub mod corpus;
+pub mod decode;
 pub mod encode;
+pub mod format;
 pub mod heap;
+pub mod stats;
 pub mod tree;
+
+pub use format::{Error, Mode};
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use bitstream::{read_chunk, with_context, BitReader, Buffers};
+use decode::DecodeError;
+use format::{BlockCoding, CodeTable, Header, Payload};
+use stats::Stats;
+use tree::{Code, Frequencies};
+
+/// Compress the input into the container format described in `format::Header`.
+///
+/// ```
+/// let compressed = huff::compress(b"abracadabra");
+/// assert_eq!(huff::decompress(&compressed).unwrap(), b"abracadabra");
+/// ```
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    compress_with(input, Mode::Plain)
+}
+
+/// Like `compress`, but with the given strategy for building the code.
+pub fn compress_with(input: &[u8], mode: Mode) -> Vec<u8> {
+    compress_with_stats(input, mode).0
+}
+
+/// Like `compress_with`, but also returns statistics about the run.
+pub fn compress_with_stats(input: &[u8], mode: Mode) -> (Vec<u8>, Stats) {
+    compress_impl(input, &Compressor::new().mode(mode), None)
+}
+
+/// Like `compress_with`, but also stores `metadata` in the header, to be read back with
+/// `decompress_with_metadata`.
+///
+/// ```
+/// let compressed = huff::compress_with_metadata(b"abracadabra", huff::Mode::Plain, "magic.txt");
+/// let (data, metadata) = huff::decompress_with_metadata(&compressed).unwrap();
+/// assert_eq!(data, b"abracadabra");
+/// assert_eq!(metadata.as_deref(), Some("magic.txt"));
+/// ```
+pub fn compress_with_metadata(input: &[u8], mode: Mode, metadata: &str) -> Vec<u8> {
+    compress_impl(
+        input,
+        &Compressor::new().mode(mode),
+        Some(metadata.to_string()),
+    )
+    .0
+}
+
+/// Compression with non-default options.
+///
+/// ```
+/// let compressed = huff::Compressor::new()
+///     .mode(huff::Mode::Canonical)
+///     .threads(4)
+///     .compress(b"abracadabra");
+/// assert_eq!(huff::decompress(&compressed).unwrap(), b"abracadabra");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Compressor {
+    mode: Mode,
+    threads: usize,
+    read_chunk_len: usize,
+    block_len: Option<usize>,
+    store_incompressible: bool,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor {
+            mode: Mode::Plain,
+            threads: 1,
+            read_chunk_len: DEFAULT_READ_CHUNK_LEN,
+            block_len: None,
+            store_incompressible: true,
+        }
+    }
+}
+
+/// Default for `Compressor::read_chunk_len`.
+pub const DEFAULT_READ_CHUNK_LEN: usize = 128 << 10;
+
+/// Size of the output buffer the binary writes through, so that the 4 KiB writes of
+/// `encode::write_words` don't each become a system call.
+pub const DEFAULT_OUTPUT_BUF_LEN: usize = 1 << 20;
+
+impl Compressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The strategy for building the code, `Mode::Plain` by default.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Encode on this many threads (see `encode::encode_stream_parallel`), 1 by default. The
+    /// output is the same for any number of threads.
+    ///
+    /// With 2 or more, the counting pass of `compress_two_pass` also counts on another thread
+    /// while reading ahead (see `bitstream::read_chunks_ahead`), as the encoding pass does while
+    /// the workers encode.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Read inputs of `compress_two_pass` in chunks of this many bytes, `DEFAULT_READ_CHUNK_LEN`
+    /// by default. Reads of whole blocks for multiple threads are larger anyway. Panics if `len`
+    /// is zero.
+    pub fn read_chunk_len(mut self, len: usize) -> Self {
+        assert!(len > 0, "read chunk length must not be zero");
+        self.read_chunk_len = len;
+        self
+    }
+
+    /// Split the payload into blocks of this many input bytes, each encoded with either the code
+    /// of the whole input or a code of its own, whichever comes out smaller with its table (see
+    /// `format::BlockHeader`). This pays off for inputs whose statistics change along the way,
+    /// e.g. binary data followed by text. Blocks that no code makes smaller, like random or
+    /// already compressed data, are stored as they are. `None` by default, for a single
+    /// bitstream.
+    ///
+    /// Blocks are encoded on the calling thread, whatever `threads` says. Panics if `len` is zero
+    /// or doesn't fit in a `u32`.
+    pub fn block_len(mut self, len: Option<usize>) -> Self {
+        if let Some(len) = len {
+            assert!(len > 0, "block length must not be zero");
+            assert!(u32::try_from(len).is_ok(), "block length must fit in a u32");
+        }
+        self.block_len = len;
+        self
+    }
+
+    /// Store inputs that encoding would make larger, headers included, as they are (see
+    /// `format::Payload::Stored`), so that the output is never more than a few bytes larger than
+    /// the input. On by default. This only applies to single bitstreams: with `block_len`, blocks
+    /// are stored one by one instead.
+    pub fn store_incompressible(mut self, store: bool) -> Self {
+        self.store_incompressible = store;
+        self
+    }
+
+    /// Compress the input, like `compress`.
+    pub fn compress(&self, input: &[u8]) -> Vec<u8> {
+        compress_impl(input, self, None).0
+    }
+
+    /// Compress everything read from `input`, reading it only once: it's kept in memory in
+    /// between counting the symbols and encoding them. The output is the same as
+    /// `compress_two_pass`'s.
+    pub fn compress_one_pass<R: Read, W: Write>(
+        &self,
+        mut input: R,
+        output: W,
+    ) -> io::Result<Stats> {
+        let mut data = vec![];
+        input
+            .read_to_end(&mut data)
+            .map_err(|err| with_context(err, "reading input"))?;
+        self.compress_two_pass(|| Ok(&data[..]), output)
+    }
+
+    /// Compress an input that can be read more than once, like `compress_two_pass`.
+    pub fn compress_two_pass<R, W, F>(&self, mut open: F, mut output: W) -> io::Result<Stats>
+    where
+        R: Read,
+        W: Write,
+        F: FnMut() -> io::Result<R>,
+    {
+        let input = open().map_err(|err| with_context(err, "opening input"))?;
+        let (frequencies, input_bytes) = self.count_symbols(input)?;
+        let header = self.header(&frequencies, input_bytes);
+        let mut header_bytes = vec![];
+        format::write_header(&mut header_bytes, &header)?;
+        output
+            .write_all(&header_bytes)
+            .map_err(|err| with_context(err, "writing output"))?;
+        let mut output_bytes = header_bytes.len() as u64;
+
+        // The encoder reads in smaller chunks, buffered to `read_chunk_len`.
+        let input = CountingReader {
+            inner: open().map_err(|err| with_context(err, "opening input"))?,
+            counts: [0; tree::NUM_SYMBOLS],
+        };
+        let mut input =
+            io::BufReader::with_capacity(self.read_chunk_len, input).take(header.original_len);
+        if header.payload == Payload::Stored {
+            output_bytes += copy_stored(&mut input, &mut output)?;
+        } else if !header.table.is_empty() {
+            let code = header
+                .table
+                .to_code()
+                .expect("code tables we build are valid");
+            output_bytes += match self.block_len {
+                Some(block_len) => {
+                    write_blocks(&code, self.mode, &mut input, &mut output, block_len)?
+                }
+                None => {
+                    encode::encode_stream_parallel(&code, &mut input, &mut output, self.threads)?
+                }
+            };
+        }
+        let trailing = read_chunk(input.get_mut(), &mut [0])
+            .map_err(|err| with_context(err, "reading input"))?;
+        if input.limit() > 0
+            || trailing > 0
+            || tree::frequencies_from_counts(&input.into_inner().into_inner().counts) != frequencies
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "input changed between passes",
+            ));
+        }
+
+        let stats = Stats::new(&frequencies, input_bytes, output_bytes);
+        Ok(match header.payload {
+            Payload::Stored => stats.stored(),
+            _ => stats,
+        })
+    }
+
+    /// The `Stats` that compressing everything read from `input` would return, without encoding
+    /// it: the symbols are counted and the code built, and the size of the output follows from
+    /// the header and the lengths of the codewords. It's exact, storing incompressible inputs
+    /// included.
+    ///
+    /// Only single bitstreams are predicted: with `block_len`, fails with
+    /// `io::ErrorKind::InvalidInput`.
+    pub fn dry_run<R: Read>(&self, input: R) -> io::Result<Stats> {
+        if self.block_len.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't predict the size of blocks without encoding them",
+            ));
+        }
+        let (frequencies, input_bytes) = self.count_symbols(input)?;
+        let header = self.header(&frequencies, input_bytes);
+        let stats = Stats::new(
+            &frequencies,
+            input_bytes,
+            compressed_len(&header, &frequencies),
+        );
+        Ok(match header.payload {
+            Payload::Stored => stats.stored(),
+            _ => stats,
+        })
+    }
+
+    /// The counting pass of `compress_two_pass`: the frequencies of the symbols of `input`, and
+    /// its length.
+    fn count_symbols<R: Read>(&self, mut input: R) -> io::Result<(Frequencies, u64)> {
+        let mut counts = [0u64; tree::NUM_SYMBOLS];
+        let mut input_bytes: u64 = 0;
+        let mut count = |chunk: &[u8]| {
+            input_bytes += chunk.len() as u64;
+            tree::count_symbols(chunk, &mut counts);
+        };
+        if self.threads >= 2 {
+            // Reading the next chunk while another thread counts.
+            bitstream::read_chunks_ahead(input, self.read_chunk_len, |chunk| {
+                count(chunk);
+                Ok(())
+            })?;
+        } else {
+            let mut input_buf: Vec<u8> = vec![0; self.read_chunk_len];
+            loop {
+                let input_len = read_chunk(&mut input, &mut input_buf)
+                    .map_err(|err| with_context(err, "reading input"))?;
+                if input_len == 0 {
+                    break;
+                }
+                count(&input_buf[..input_len]);
+            }
+        }
+        Ok((tree::frequencies_from_counts(&counts), input_bytes))
+    }
+
+    /// The header for an input with these frequencies.
+    fn header(&self, frequencies: &Frequencies, input_bytes: u64) -> Header {
+        let mut header = Header {
+            original_len: input_bytes,
+            table: CodeTable::new(frequencies, self.mode),
+            metadata: None,
+            payload: match self.block_len {
+                Some(_) => Payload::Blocks,
+                None => Payload::Bitstream,
+            },
+        };
+        if self.store_incompressible {
+            store_if_smaller(&mut header, frequencies);
+        }
+        header
+    }
+}
+
+/// Default limit on the input length for `Strategy::OnePass` in `Strategy::choose`.
+pub const DEFAULT_ONE_PASS_LIMIT: u64 = 64 << 20;
+
+/// How to compress an input that doesn't have to be in memory, such as a file.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Strategy {
+    /// Read it once, keeping it in memory (see `Compressor::compress_one_pass`).
+    OnePass,
+    /// Read it twice, once to count the symbols and once to encode them (see
+    /// `Compressor::compress_two_pass`).
+    TwoPass,
+}
+
+impl Strategy {
+    /// The strategy to use: `forced` if given, otherwise `OnePass` for inputs of up to
+    /// `one_pass_limit` bytes, to save the second read, and `TwoPass` for larger ones, to save
+    /// memory.
+    ///
+    /// An unknown `input_len` means the input isn't a regular file, e.g. a pipe; since it
+    /// probably can't be read twice, it gets `OnePass`.
+    pub fn choose(forced: Option<Strategy>, input_len: Option<u64>, one_pass_limit: u64) -> Self {
+        match (forced, input_len) {
+            (Some(strategy), _) => strategy,
+            (None, Some(len)) if len > one_pass_limit => Strategy::TwoPass,
+            (None, _) => Strategy::OnePass,
+        }
+    }
+}
+
+fn compress_impl(
+    input: &[u8],
+    compressor: &Compressor,
+    metadata: Option<String>,
+) -> (Vec<u8>, Stats) {
+    let frequencies = tree::compute_frequencies(input);
+    let mut header = Header {
+        original_len: input.len() as u64,
+        table: CodeTable::new(&frequencies, compressor.mode),
+        metadata,
+        payload: match compressor.block_len {
+            Some(_) => Payload::Blocks,
+            None => Payload::Bitstream,
+        },
+    };
+    if compressor.store_incompressible {
+        store_if_smaller(&mut header, &frequencies);
+    }
+    let mut output = vec![];
+    format::write_header(&mut output, &header).expect("writing to a Vec can't fail");
+    if header.payload == Payload::Stored {
+        output.extend_from_slice(input);
+    } else if !header.table.is_empty() {
+        let code = header
+            .table
+            .to_code()
+            .expect("code tables we build are valid");
+        match compressor.block_len {
+            Some(block_len) => write_blocks(&code, compressor.mode, input, &mut output, block_len),
+            None => encode::encode_stream_parallel(&code, input, &mut output, compressor.threads),
+        }
+        .expect("writing to a Vec can't fail");
+    }
+
+    let stats = Stats::new(&frequencies, input.len() as u64, output.len() as u64);
+    match header.payload {
+        Payload::Stored => (output, stats.stored()),
+        _ => (output, stats),
+    }
+}
+
+/// Number of bits that encoding symbols with these frequencies under `code` takes.
+fn encoded_bits(frequencies: &Frequencies, code: &Code) -> u64 {
+    frequencies
+        .iter()
+        .map(|&(sym, count)| count * code[sym].bit_len as u64)
+        .sum()
+}
+
+/// Switch a header for a single bitstream to `Payload::Stored` if the data with these frequencies
+/// would take less space as it is than encoded, headers included, so that incompressible inputs
+/// grow by no more than a few bytes. Blocks are left alone, they're stored one by one instead
+/// (see `write_blocks`).
+fn store_if_smaller(header: &mut Header, frequencies: &Frequencies) {
+    if header.payload != Payload::Bitstream {
+        return;
+    }
+    let stored = Header {
+        original_len: header.original_len,
+        table: CodeTable::Frequencies(vec![]),
+        metadata: header.metadata.clone(),
+        payload: Payload::Stored,
+    };
+    if compressed_len(&stored, frequencies) < compressed_len(header, frequencies) {
+        *header = stored;
+    }
+}
+
+/// Length of the output for a header and the frequencies of the data after it, for a single
+/// bitstream or stored data.
+fn compressed_len(header: &Header, frequencies: &Frequencies) -> u64 {
+    let mut bytes = vec![];
+    format::write_header(&mut bytes, header).expect("headers we build are valid");
+    let payload_len = if header.payload == Payload::Stored {
+        header.original_len
+    } else if header.table.is_empty() {
+        0
+    } else {
+        let code = header
+            .table
+            .to_code()
+            .expect("code tables we build are valid");
+        encoded_bits(frequencies, &code).div_ceil(8)
+    };
+    bytes.len() as u64 + payload_len
+}
+
+/// Counts the symbols read through it, for `Compressor::compress_two_pass` to check that the
+/// second pass reads what the first one counted.
+struct CountingReader<R> {
+    inner: R,
+    counts: [u64; tree::NUM_SYMBOLS],
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        tree::count_symbols(&buf[..len], &mut self.counts);
+        Ok(len)
+    }
+}
+
+/// Copy the rest of `input` to `output`, for `Payload::Stored`, and return the number of bytes.
+fn copy_stored<R: Read, W: Write>(input: &mut R, output: &mut W) -> io::Result<u64> {
+    let mut buf = vec![0; 64 << 10];
+    let mut copied = 0;
+    loop {
+        let len = read_chunk(input, &mut buf).map_err(|err| with_context(err, "reading input"))?;
+        if len == 0 {
+            return Ok(copied);
+        }
+        output
+            .write_all(&buf[..len])
+            .map_err(|err| with_context(err, "writing output"))?;
+        copied += len as u64;
+    }
+}
+
+/// Write the blocks of a `format::VERSION_BLOCKS` payload, `block_len` bytes of `input` each, and
+/// return the number of bytes written. Each block is encoded with `code`, the code of the whole
+/// input, unless a code of its own built in `mode` makes it smaller, table included. Blocks that
+/// neither code makes smaller than they are, like random data, are stored as they are.
+fn write_blocks<R: Read, W: Write>(
+    code: &Code,
+    mode: Mode,
+    mut input: R,
+    output: &mut W,
+    block_len: usize,
+) -> io::Result<u64> {
+    let mut block = vec![0; block_len];
+    let mut buffers = Buffers::new();
+    let mut output_bytes = 0;
+    loop {
+        let len =
+            read_chunk(&mut input, &mut block).map_err(|err| with_context(err, "reading input"))?;
+        if len == 0 {
+            return Ok(output_bytes);
+        }
+        let block = &block[..len];
+        let frequencies = tree::compute_frequencies(block);
+        let table = CodeTable::new(&frequencies, mode);
+        let own_code = table.to_code().expect("code tables we build are valid");
+        let header_bytes = |coding| -> io::Result<Vec<u8>> {
+            let mut bytes = vec![];
+            format::write_block_header(
+                &mut bytes,
+                &format::BlockHeader {
+                    len: len as u32,
+                    coding,
+                },
+            )?;
+            Ok(bytes)
+        };
+        let own_header = header_bytes(BlockCoding::Own(table))?;
+        let shared_header = header_bytes(BlockCoding::Shared)?;
+        let stored_header = header_bytes(BlockCoding::Stored)?;
+        let own_bytes = own_header.len() as u64 + encoded_bits(&frequencies, &own_code).div_ceil(8);
+        let shared_bytes =
+            shared_header.len() as u64 + encoded_bits(&frequencies, code).div_ceil(8);
+        let stored_bytes = stored_header.len() as u64 + len as u64;
+        let (header_bytes, code) = if own_bytes < shared_bytes {
+            (own_header, &own_code)
+        } else {
+            (shared_header, code)
+        };
+        if stored_bytes <= own_bytes.min(shared_bytes) {
+            for bytes in &[&stored_header[..], block] {
+                output
+                    .write_all(bytes)
+                    .map_err(|err| with_context(err, "writing output"))?;
+            }
+            output_bytes += stored_bytes;
+            continue;
+        }
+        output
+            .write_all(&header_bytes)
+            .map_err(|err| with_context(err, "writing output"))?;
+        output_bytes += header_bytes.len() as u64
+            + encode::encode_stream_with(code, block, &mut *output, &mut buffers)?;
+    }
+}
+
+/// Decode the blocks of a `format::VERSION_BLOCKS` payload, passing the decoded bytes to `emit`
+/// at most `output_buf.len()` at a time.
+fn decode_blocks<R: Read>(
+    header: &Header,
+    input: &mut R,
+    output_buf: &mut [u8],
+    mut emit: impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    // Built on first use, blocks may all have codes of their own.
+    let mut shared_decoder = None;
+    let mut remaining = header.original_len;
+    while remaining > 0 {
+        let block = format::read_block_header(input, remaining)?;
+        let mut own_decoder;
+        let decoder = match &block.coding {
+            BlockCoding::Shared => match &mut shared_decoder {
+                Some(decoder) => decoder,
+                None => shared_decoder.insert(header.table.to_decoder()?),
+            },
+            BlockCoding::Own(table) => {
+                own_decoder = table.to_decoder()?;
+                &mut own_decoder
+            }
+            BlockCoding::Stored => {
+                read_stored(input, block.len as u64, output_buf, &mut emit)?;
+                remaining -= block.len as u64;
+                continue;
+            }
+        };
+        // Dropped at the end of the block, with the padding bits of its last byte.
+        let mut reader = BitReader::new(&mut *input);
+        let mut block_remaining = block.len as usize;
+        while block_remaining > 0 {
+            let output_len = block_remaining.min(output_buf.len());
+            for byte in &mut output_buf[..output_len] {
+                *byte = decoder.decode_symbol(&mut reader)?;
+            }
+            emit(&output_buf[..output_len])?;
+            block_remaining -= output_len;
+        }
+        remaining -= block.len as u64;
+    }
+    Ok(())
+}
+
+/// Read `len` bytes of stored data, passing them to `emit` at most `output_buf.len()` at a time.
+fn read_stored<R: Read>(
+    input: &mut R,
+    len: u64,
+    output_buf: &mut [u8],
+    mut emit: impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let output_len = remaining.min(output_buf.len() as u64) as usize;
+        input
+            .read_exact(&mut output_buf[..output_len])
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => DecodeError::UnexpectedEof,
+                _ => DecodeError::Io(err),
+            })?;
+        emit(&output_buf[..output_len])?;
+        remaining -= output_len as u64;
+    }
+    Ok(())
+}
+
+/// Compress an input that can be read more than once, without holding it in memory: one pass
+/// counts the symbols, and another encodes them. `open` is called at the start of each pass.
+///
+/// The symbols are counted again during the second pass. If the counts differ from the first
+/// pass (e.g. a file was appended to, truncated or edited in between), fails with
+/// `io::ErrorKind::InvalidData`, since the header written by then doesn't match the data anymore.
+/// Symbols missing from the code are caught by the encoder as soon as they're read, other changes
+/// at the end. What was already written to `output` should be discarded. Data that changed but
+/// kept the same counts, like two swapped bytes, still decompresses to what the second pass read.
+///
+/// I/O errors say which operation failed: opening, reading, or writing output.
+pub fn compress_two_pass<R, W, F>(open: F, mode: Mode, output: W) -> io::Result<Stats>
+where
+    R: Read,
+    W: Write,
+    F: FnMut() -> io::Result<R>,
+{
+    Compressor::new().mode(mode).compress_two_pass(open, output)
+}
+
+/// Default for `Decompressor::max_output_size`, which also applies to `decompress`.
+pub const DEFAULT_MAX_OUTPUT_SIZE: u64 = 1 << 32;
+
+/// Decompress data produced by `compress`.
+///
+/// Fails with `Error::LimitExceeded` if the data claims to decompress to more than
+/// `DEFAULT_MAX_OUTPUT_SIZE` bytes; use `Decompressor` to change the limit.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(decompress_with_metadata(data)?.0)
+}
+
+/// Like `decompress`, but with a limit of `max_output_bytes` instead of
+/// `DEFAULT_MAX_OUTPUT_SIZE`, for untrusted input: data claiming to decompress to more fails with
+/// `Error::LimitExceeded` before anything is allocated for it.
+///
+/// ```
+/// let compressed = huff::compress(b"abracadabra");
+/// assert_eq!(huff::decompress_with_limit(&compressed, 11).unwrap(), b"abracadabra");
+/// assert!(huff::decompress_with_limit(&compressed, 10).is_err());
+/// ```
+pub fn decompress_with_limit(data: &[u8], max_output_bytes: u64) -> Result<Vec<u8>, Error> {
+    Ok(Decompressor::new()
+        .max_output_size(Some(max_output_bytes))
+        .decompress(data)?
+        .0)
+}
+
+/// Like `decompress`, but also returns the metadata stored in the header, if any.
+pub fn decompress_with_metadata(data: &[u8]) -> Result<(Vec<u8>, Option<String>), Error> {
+    Decompressor::new().decompress(data)
+}
+
+/// Like `decompress`, but reads the compressed data from `input`, and writes the decompressed
+/// data to `output` as it's decoded, so that neither has to fit in memory.
+///
+/// Errors can come after some of the output was written already, which should be discarded
+/// then. Failures to write the output are reported as `Error::Write`.
+///
+/// ```
+/// let compressed = huff::compress(b"abracadabra");
+/// let mut output = vec![];
+/// huff::decompress_stream(&mut &compressed[..], &mut output).unwrap();
+/// assert_eq!(output, b"abracadabra");
+/// ```
+pub fn decompress_stream<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<(), Error> {
+    Decompressor::new().decompress_stream(input, output)?;
+    Ok(())
+}
+
+/// Decompression with non-default options.
+///
+/// ```
+/// let compressed = huff::compress(b"abracadabra");
+/// let result = huff::Decompressor::new().max_output_size(Some(10)).decompress(&compressed);
+/// assert!(matches!(result, Err(huff::Error::LimitExceeded { limit: 10, .. })));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Decompressor {
+    max_output_size: Option<u64>,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Decompressor {
+            max_output_size: Some(DEFAULT_MAX_OUTPUT_SIZE),
+        }
+    }
+}
+
+impl Decompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuse to decompress data whose header declares more than this many bytes, or set to
+    /// `None` to accept any length. The length is checked before decoding anything, so that a
+    /// small hostile input can't make us allocate or produce a huge output.
+    pub fn max_output_size(mut self, max_output_size: Option<u64>) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    /// Decompress data produced by `compress`, returning it together with the metadata stored in
+    /// the header, if any.
+    pub fn decompress(&self, data: &[u8]) -> Result<(Vec<u8>, Option<String>), Error> {
+        let mut input = data;
+        let header = format::read_header(&mut input)?;
+        self.check_limit(&header)?;
+        // Each symbol takes at least a bit, so a declared length that the payload can't possibly
+        // hold is rejected up front, before allocating for it.
+        if header.original_len > input.len() as u64 * 8 {
+            return Err(Error::Decode(DecodeError::UnexpectedEof));
+        }
+        let mut output = Vec::with_capacity(header.original_len as usize);
+        decode_payload(&header, &mut input, &mut [0; 8192], |chunk| {
+            output.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok((output, header.metadata))
+    }
+
+    /// Like `decompress`, but streaming, see `decompress_stream`. Returns the metadata stored in
+    /// the header, if any.
+    pub fn decompress_stream<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<Option<String>, Error> {
+        self.decompress_stream_with(input, output, &mut Buffers::new())
+    }
+
+    /// `decompress_stream`, with scratch buffers kept from earlier streams.
+    pub fn decompress_stream_with<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        buffers: &mut Buffers,
+    ) -> Result<Option<String>, Error> {
+        // The bit reader and the header reader take a few bytes at a time.
+        let mut input = io::BufReader::new(input);
+        let header = format::read_header(&mut input)?;
+        self.check_limit(&header)?;
+        decode_payload(&header, &mut input, buffers.output(8192), |chunk| {
+            output.write_all(chunk).map_err(Error::Write)
+        })?;
+        Ok(header.metadata)
+    }
+
+    fn check_limit(&self, header: &Header) -> Result<(), Error> {
+        match self.max_output_size {
+            Some(limit) if header.original_len > limit => Err(Error::LimitExceeded {
+                limit,
+                declared: header.original_len,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decode the payload that follows `header`, passing the decoded bytes to `emit` at most
+/// `output_buf.len()` at a time.
+fn decode_payload<R: Read>(
+    header: &Header,
+    input: &mut R,
+    output_buf: &mut [u8],
+    mut emit: impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    match header.payload {
+        Payload::Stored => return read_stored(input, header.original_len, output_buf, emit),
+        _ if header.table.is_empty() => return Ok(()),
+        Payload::Blocks => return decode_blocks(header, input, output_buf, emit),
+        Payload::Bitstream => {}
+    }
+    let mut decoder = header.table.to_decoder()?;
+    let mut reader = BitReader::new(input);
+    let mut remaining = header.original_len;
+    while remaining > 0 {
+        let output_len = remaining.min(output_buf.len() as u64) as usize;
+        for byte in &mut output_buf[..output_len] {
+            *byte = decoder.decode_symbol(&mut reader)?;
+        }
+        emit(&output_buf[..output_len])?;
+        remaining -= output_len as u64;
+    }
+    Ok(())
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_compress_round_trip(input: Vec<u8>) -> bool {
+    decompress(&compress(&input)).unwrap() == input
+}
+
+#[test]
+fn test_payload_size_is_minimal() {
+    // `a` gets a one-bit codeword, `b` and `c` two-bit ones.
+    for &(input, payload_len) in &[
+        (&b"abc"[..], 1),
+        (b"aaaabc", 1),
+        (b"aaaaabc", 2),
+        (b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaabc", 5),
+    ] {
+        let compressed = Compressor::new()
+            .store_incompressible(false)
+            .compress(input);
+        let header = format::read_header(&mut &compressed[..]).unwrap();
+        let mut header_bytes = vec![];
+        format::write_header(&mut header_bytes, &header).unwrap();
+        assert_eq!(compressed.len() - header_bytes.len(), payload_len);
+        assert_eq!(decompress(&compressed).unwrap(), input);
+    }
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_compress_round_trip_all_modes(input: Vec<u8>) -> bool {
+    [Mode::Plain, Mode::Canonical, Mode::LengthLimited]
+        .iter()
+        .all(|&mode| decompress(&compress_with(&input, mode)).unwrap() == input)
+}
+
+#[test]
+fn test_single_symbol_round_trip() {
+    let input = vec![b'x'; 10000];
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let compressed = compress_with(&input, mode);
+        let mut header = vec![];
+        format::write_header(
+            &mut header,
+            &format::read_header(&mut &compressed[..]).unwrap(),
+        )
+        .unwrap();
+        // One bit per symbol, in 1250 bytes.
+        assert_eq!(compressed.len() - header.len(), 1250);
+        assert_eq!(decompress(&compressed).unwrap(), input);
+        // With one more symbol, the 7 padding bits at the end would decode as more `x`s if the
+        // decoder didn't stop after `original_len` symbols.
+        let mut longer_input = input.clone();
+        longer_input.push(b'x');
+        let longer = compress_with(&longer_input, mode);
+        assert_eq!(longer.len(), compressed.len() + 1);
+        assert_eq!(decompress(&longer).unwrap(), longer_input);
+
+        // Missing payload is still detected, even though any bit is a valid codeword.
+        assert!(matches!(
+            decompress(&compressed[..compressed.len() - 1]),
+            Err(Error::Decode(decode::DecodeError::UnexpectedEof))
+        ));
+    }
+}
+
+#[test]
+fn test_compress_two_pass() {
+    let input = b"abracadabra".repeat(100);
+    let mut output = vec![];
+    let stats = compress_two_pass(|| Ok(&input[..]), Mode::Canonical, &mut output).unwrap();
+    assert_eq!(output, compress_with(&input, Mode::Canonical));
+    assert_eq!(stats.output_bytes, output.len() as u64);
+}
+
+#[test]
+fn test_compress_two_pass_errors() {
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk on fire"))
+        }
+    }
+    let err = compress_two_pass(|| Ok(Failing), Mode::Plain, vec![]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(err.to_string(), "reading input: disk on fire");
+
+    let err = compress_two_pass(
+        || Err::<&[u8], _>(io::ErrorKind::NotFound.into()),
+        Mode::Plain,
+        vec![],
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(err.to_string().starts_with("opening input: "));
+
+    // Interruptions aren't errors.
+    let input = b"abracadabra".repeat(1000);
+    let mut output = vec![];
+    compress_two_pass(
+        || Ok(bitstream::Flaky::new(&input[..], 100, &[0, 3])),
+        Mode::Plain,
+        &mut output,
+    )
+    .unwrap();
+    assert_eq!(output, compress(&input));
+}
+
+#[test]
+fn test_compress_two_pass_input_changed() {
+    let input = b"abracadabra".repeat(100);
+    let compress_changed = |second: &[u8]| {
+        let mut passes = vec![second, &input[..]];
+        compress_two_pass(|| Ok(passes.pop().unwrap()), Mode::Plain, vec![])
+            .map(|_| ())
+            .map_err(|err| err.kind())
+    };
+    let mut grown = input.clone();
+    grown.extend_from_slice(b"abra");
+    assert_eq!(compress_changed(&grown), Err(io::ErrorKind::InvalidData));
+    let mut grown_new_symbol = input.clone();
+    grown_new_symbol.extend_from_slice(b"xyz");
+    assert_eq!(
+        compress_changed(&grown_new_symbol),
+        Err(io::ErrorKind::InvalidData)
+    );
+    let mut replaced = input.clone();
+    replaced[500] = b'z';
+    assert_eq!(compress_changed(&replaced), Err(io::ErrorKind::InvalidData));
+    let mut passes = vec![&replaced[..], &input[..]];
+    let err = compress_two_pass(|| Ok(passes.pop().unwrap()), Mode::Plain, vec![]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "symbol 122 at input offset 500 has no codeword"
+    );
+    assert_eq!(
+        compress_changed(&input[..input.len() - 1]),
+        Err(io::ErrorKind::InvalidData)
+    );
+    assert_eq!(compress_changed(&input), Ok(()));
+
+    // The same length and symbols, but other counts.
+    let mut edited = input.clone();
+    edited[0] = b'b';
+    assert_eq!(compress_changed(&edited), Err(io::ErrorKind::InvalidData));
+    // The same counts: the header still matches the data read the second time.
+    let mut swapped = input.clone();
+    swapped.swap(0, 1);
+    let mut passes = vec![&swapped[..], &input[..]];
+    let mut output = vec![];
+    compress_two_pass(|| Ok(passes.pop().unwrap()), Mode::Plain, &mut output).unwrap();
+    assert_eq!(decompress(&output).unwrap(), swapped);
+}
+
+#[test]
+fn test_metadata_round_trip() {
+    for input in &[&b""[..], b"x", b"abracadabra"] {
+        let compressed = compress_with_metadata(input, Mode::Canonical, "file.txt 2026-10-16");
+        let (data, metadata) = decompress_with_metadata(&compressed).unwrap();
+        assert_eq!(&data[..], *input);
+        assert_eq!(metadata.as_deref(), Some("file.txt 2026-10-16"));
+        assert_eq!(decompress(&compressed).unwrap(), *input);
+    }
+    assert_eq!(decompress_with_metadata(&compress(b"abc")).unwrap().1, None);
+}
+
+#[test]
+fn test_container_with_coder_trait_objects() {
+    use bitstream::BitWriter;
+    use coder::{SymbolDecoder, SymbolEncoder};
+
+    let input = b"abracadabra".repeat(10);
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let header = Header {
+            original_len: input.len() as u64,
+            table: CodeTable::new(&tree::compute_frequencies(&input), mode),
+            metadata: None,
+            payload: Payload::Bitstream,
+        };
+        let code = header.table.to_code().unwrap();
+
+        let mut data = vec![];
+        format::write_header(&mut data, &header).unwrap();
+        let mut writer = BitWriter::new(data);
+        let mut encoder: Box<dyn SymbolEncoder> = Box::new(encode::Encoder::new(&code));
+        coder::encode_all(&mut *encoder, &input, &mut writer).unwrap();
+        let data = writer.finish().unwrap();
+        assert_eq!(decompress(&data).unwrap(), input);
+
+        let mut payload = &data[..];
+        let header = format::read_header(&mut payload).unwrap();
+        let mut decoder: Box<dyn SymbolDecoder> =
+            Box::new(decode::Decoder::new(&header.table.to_code().unwrap()));
+        let mut reader = BitReader::new(payload);
+        let output = coder::decode_all(&mut *decoder, header.original_len, &mut reader).unwrap();
+        assert_eq!(output, input);
+    }
+
+    // Uncoded symbols are rejected through the trait too.
+    let code = tree::tree_to_code(&tree::build_tree(&vec![(b'a', 1), (b'b', 1)]));
+    let mut encoder = encode::Encoder::new(&code);
+    let err = encoder
+        .encode_symbol(b'x', &mut BitWriter::new(vec![]))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_decompress_limit() {
+    // A header claiming a terabyte, followed by a few bytes of payload.
+    let mut data = vec![];
+    format::write_header(
+        &mut data,
+        &Header {
+            original_len: 1 << 40,
+            table: CodeTable::Frequencies(vec![(b'a', 1 << 39), (b'b', 1 << 39)]),
+            metadata: None,
+            payload: Payload::Bitstream,
+        },
+    )
+    .unwrap();
+    data.extend_from_slice(&[0x55; 16]);
+    assert!(matches!(
+        decompress(&data),
+        Err(Error::LimitExceeded {
+            limit: DEFAULT_MAX_OUTPUT_SIZE,
+            declared: 0x100_0000_0000,
+        })
+    ));
+    // Without the limit, decoding runs into the end of the payload; the buffer is sized by the
+    // payload, not by the declared length.
+    assert!(matches!(
+        Decompressor::new().max_output_size(None).decompress(&data),
+        Err(Error::Decode(decode::DecodeError::UnexpectedEof))
+    ));
+
+    let input = b"abracadabra";
+    let compressed = compress(input);
+    for &(limit, ok) in &[(Some(10), false), (Some(11), true), (None, true)] {
+        let result = Decompressor::new()
+            .max_output_size(limit)
+            .decompress(&compressed);
+        assert_eq!(result.is_ok(), ok, "limit {:?}", limit);
+    }
+}
+
+#[test]
+fn test_decompress_with_limit() {
+    // Headers claiming the largest possible length, with a single symbol so that any payload
+    // decodes, in both container formats.
+    for &payload in &[Payload::Bitstream, Payload::Blocks, Payload::Stored] {
+        let mut data = vec![];
+        format::write_header(
+            &mut data,
+            &Header {
+                original_len: u64::MAX,
+                table: CodeTable::Frequencies(vec![(b'a', u64::MAX)]),
+                metadata: None,
+                payload,
+            },
+        )
+        .unwrap();
+        if payload == Payload::Blocks {
+            format::write_block_header(
+                &mut data,
+                &format::BlockHeader {
+                    len: u32::MAX,
+                    coding: BlockCoding::Stored,
+                },
+            )
+            .unwrap();
+        }
+        data.extend_from_slice(&[0; 64]);
+        for &limit in &[0, 1 << 20, u64::MAX - 1] {
+            assert!(matches!(
+                decompress_with_limit(&data, limit),
+                Err(Error::LimitExceeded {
+                    limit: l,
+                    declared: u64::MAX,
+                }) if l == limit
+            ));
+        }
+    }
+
+    let compressed = Compressor::new()
+        .block_len(Some(4))
+        .compress(b"abracadabra");
+    assert_eq!(
+        decompress_with_limit(&compressed, 11).unwrap(),
+        b"abracadabra"
+    );
+    assert!(matches!(
+        decompress_with_limit(&compressed, 10),
+        Err(Error::LimitExceeded {
+            limit: 10,
+            declared: 11,
+        })
+    ));
+    assert_eq!(decompress_with_limit(&compress(b""), 0).unwrap(), b"");
+}
+
+#[test]
+fn test_decompress_invalid_code_table() {
+    // Three one-bit codewords, which `write_header` refuses to write.
+    let mut data = b"HUFF\x01\x03\0\0\0\0\0\0\0\x01\x03\0A\x01B\x01C\x01".to_vec();
+    data.extend_from_slice(&[0; 8]);
+    assert!(matches!(
+        decompress(&data),
+        Err(Error::InvalidCodeTable(format::TableError::Lengths(
+            tree::CanonicalError::Oversubscribed
+        )))
+    ));
+}
+
+#[test]
+fn test_decompress_legacy_frequencies() {
+    // Written before ties were broken explicitly, with `a: 00, b: 11, c: 10, d: 01`. The current
+    // `tree::build_tree` would give `b: 01, d: 11`, and decode this as "adcb".
+    let legacy = b"HUFF\x01\x04\0\0\0\0\0\0\0\0\x04\0\
+        a\x01\0\0\0\0\0\0\0b\x01\0\0\0\0\0\0\0c\x01\0\0\0\0\0\0\0d\x01\0\0\0\0\0\0\0\x9c";
+    assert_eq!(decompress(legacy).unwrap(), b"abcd");
+
+    let current = Compressor::new()
+        .store_incompressible(false)
+        .compress(b"abcd");
+    assert_eq!(current[13], 2);
+    assert_eq!(decompress(&current).unwrap(), b"abcd");
+}
+
+// Fuzzing: decompressing arbitrary data must fail cleanly, never panic.
+
+#[quickcheck_macros::quickcheck]
+fn qc_decompress_arbitrary(data: Vec<u8>, version: bool) -> bool {
+    let mut input = format::MAGIC.to_vec();
+    input.push(if version { 1 } else { 2 });
+    input.extend_from_slice(&data);
+    let _ = decompress(&input);
+    let _ = decompress(&data);
+    true
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_decompress_mutated(input: Vec<u8>, mode: u8, mutations: Vec<(usize, u8)>) -> bool {
+    let mode = [Mode::Plain, Mode::Canonical, Mode::LengthLimited][mode as usize % 3];
+    let blocks = Compressor::new().mode(mode).block_len(Some(7));
+    for data in &mut [
+        compress_with_metadata(&input, mode, "meta"),
+        blocks.compress(&input),
+    ] {
+        for &(index, byte) in &mutations {
+            let index = index % data.len();
+            data[index] ^= byte;
+        }
+        let _ = decompress(data);
+    }
+    true
+}
+
+#[test]
+fn test_compress_threads() {
+    // Several blocks of the parallel encoder, with a code that leaves them at odd bit offsets.
+    let input: Vec<u8> = (0..(5 * encode::PARALLEL_BLOCK_LEN as u32 / 2))
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8 % 37)
+        .collect();
+    for &mode in &[Mode::Plain, Mode::LengthLimited] {
+        let expected = compress_with(&input, mode);
+        for &threads in &[2, 3, 8] {
+            let compressor = Compressor::new().mode(mode).threads(threads);
+            assert!(compressor.compress(&input) == expected);
+            let mut output = vec![];
+            let stats = compressor
+                .compress_two_pass(|| Ok(&input[..]), &mut output)
+                .unwrap();
+            assert!(output == expected);
+            assert_eq!(stats.output_bytes, expected.len() as u64);
+        }
+    }
+}
+
+#[test]
+fn test_compress_blocks() {
+    // High bytes without structure, then text: each half has half of the symbols to itself, so
+    // one code for both spends about a bit more per symbol.
+    let len = 1 << 16;
+    let mut input: Vec<u8> = corpus::random(len, 1).iter().map(|b| b | 0x80).collect();
+    input.extend(corpus::text_like(len, 1));
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let single = compress_with(&input, mode);
+        let compressor = Compressor::new().mode(mode).block_len(Some(len / 2));
+        let blocks = compressor.compress(&input);
+        assert!(blocks.len() < single.len() * 9 / 10);
+        assert_eq!(decompress(&blocks).unwrap(), input);
+        let mut output = vec![];
+        decompress_stream(&mut &blocks[..], &mut output).unwrap();
+        assert_eq!(output, input);
+
+        let mut output = vec![];
+        let stats = compressor
+            .compress_two_pass(|| Ok(&input[..]), &mut output)
+            .unwrap();
+        assert!(output == blocks);
+        assert_eq!(stats.output_bytes, blocks.len() as u64);
+    }
+
+    // With the same statistics throughout, the blocks share the code of the header.
+    let input = corpus::text_like(len, 2);
+    let blocks = Compressor::new().block_len(Some(len / 4)).compress(&input);
+    let mut data = &blocks[..];
+    let header = format::read_header(&mut data).unwrap();
+    assert_eq!(header.payload, Payload::Blocks);
+    let block = format::read_block_header(&mut data, len as u64).unwrap();
+    assert_eq!(block.len as usize, len / 4);
+    assert_eq!(block.coding, BlockCoding::Shared);
+    assert_eq!(decompress(&blocks).unwrap(), input);
+
+    let mut output = vec![];
+    decompress_stream(
+        &mut &Compressor::new().block_len(Some(10)).compress(b"")[..],
+        &mut output,
+    )
+    .unwrap();
+    assert_eq!(output, b"");
+}
+
+#[test]
+fn test_compress_stored_blocks() {
+    // Random bytes, which no code makes smaller, then text.
+    let len = 1 << 16;
+    let mut input = corpus::random(len, 1);
+    input.extend(corpus::text_like(len, 1));
+    let blocks = Compressor::new().block_len(Some(len)).compress(&input);
+    let mut data = &blocks[..];
+    let header = format::read_header(&mut data).unwrap();
+    let block = format::read_block_header(&mut data, header.original_len).unwrap();
+    assert_eq!(block.coding, BlockCoding::Stored);
+    assert_eq!(&data[..len], &input[..len]);
+    let stored_end = blocks.len() - data.len() + len;
+    let mut data = &blocks[stored_end..];
+    let block = format::read_block_header(&mut data, len as u64).unwrap();
+    assert!(matches!(block.coding, BlockCoding::Own(_)));
+    assert!(data.len() < len * 5 / 8);
+    assert_eq!(decompress(&blocks).unwrap(), input);
+    let mut output = vec![];
+    decompress_stream(&mut &blocks[..], &mut output).unwrap();
+    assert_eq!(output, input);
+    assert!(matches!(
+        decompress(&blocks[..stored_end - 1]),
+        Err(Error::Decode(DecodeError::UnexpectedEof))
+    ));
+
+    // Only random bytes: the input and the headers.
+    let input = corpus::random(len, 2);
+    let blocks = Compressor::new().block_len(Some(len / 4)).compress(&input);
+    let mut header_bytes = vec![];
+    let header = format::read_header(&mut &blocks[..]).unwrap();
+    format::write_header(&mut header_bytes, &header).unwrap();
+    assert_eq!(blocks.len(), header_bytes.len() + 4 * (4 + 1) + len);
+    assert_eq!(decompress(&blocks).unwrap(), input);
+}
+
+#[test]
+fn test_compress_stored() {
+    let len = 1 << 16;
+    let input = corpus::random(len, 1);
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let (compressed, stats) = compress_with_stats(&input, mode);
+        // The magic, the version, the length and the metadata flag.
+        assert_eq!(compressed.len(), len + 14);
+        assert_eq!(&compressed[14..], &input[..]);
+        let header = format::read_header(&mut &compressed[..]).unwrap();
+        assert_eq!(header.payload, Payload::Stored);
+        assert_eq!(stats.warnings, vec![stats::Warning::Stored]);
+        assert_eq!(decompress(&compressed).unwrap(), input);
+        let mut output = vec![];
+        decompress_stream(&mut &compressed[..], &mut output).unwrap();
+        assert_eq!(output, input);
+
+        let mut output = vec![];
+        let stats = Compressor::new()
+            .mode(mode)
+            .compress_two_pass(|| Ok(&input[..]), &mut output)
+            .unwrap();
+        assert!(output == compressed);
+        assert_eq!(stats.warnings, vec![stats::Warning::Stored]);
+    }
+    assert!(matches!(
+        decompress(&compress(&input)[..len]),
+        Err(Error::Decode(DecodeError::UnexpectedEof))
+    ));
+    let (data, metadata) =
+        decompress_with_metadata(&compress_with_metadata(&input, Mode::Plain, "meta")).unwrap();
+    assert_eq!(data, input);
+    assert_eq!(metadata.as_deref(), Some("meta"));
+
+    // Text still gets coded.
+    let input = corpus::text_like(len, 1);
+    let compressed = compress(&input);
+    let header = format::read_header(&mut &compressed[..]).unwrap();
+    assert_eq!(header.payload, Payload::Bitstream);
+    assert!(compressed.len() < len * 5 / 8);
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_compress_blocks_round_trip(input: Vec<u8>, block_len: u8, mode: u8) -> bool {
+    let mode = [Mode::Plain, Mode::Canonical, Mode::LengthLimited][mode as usize % 3];
+    let compressed = Compressor::new()
+        .mode(mode)
+        .block_len(Some(block_len.max(1) as usize))
+        .compress(&input);
+    let mut output = vec![];
+    decompress_stream(&mut &compressed[..], &mut output).unwrap();
+    decompress(&compressed).unwrap() == input && output == input
+}
+
+#[test]
+fn test_dry_run() {
+    let inputs = [
+        vec![],
+        vec![b'a'; 100],
+        corpus::text_like(10000, 4),
+        corpus::random(10000, 4),
+    ];
+    for input in &inputs {
+        for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+            for &store in &[false, true] {
+                let compressor = Compressor::new().mode(mode).store_incompressible(store);
+                let stats = compressor.dry_run(&input[..]).unwrap();
+                let mut output = vec![];
+                let expected = compressor
+                    .compress_two_pass(|| Ok(&input[..]), &mut output)
+                    .unwrap();
+                assert_eq!(stats, expected);
+                assert_eq!(stats.output_bytes, compressor.compress(input).len() as u64);
+            }
+        }
+    }
+
+    let err = Compressor::new()
+        .block_len(Some(10))
+        .dry_run(&inputs[2][..])
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_dry_run_matches_compress(input: Vec<u8>, mode: u8, store: bool) -> bool {
+    let mode = [Mode::Plain, Mode::Canonical, Mode::LengthLimited][mode as usize % 3];
+    let compressor = Compressor::new().mode(mode).store_incompressible(store);
+    let stats = compressor.dry_run(&input[..]).unwrap();
+    stats.output_bytes == compressor.compress(&input).len() as u64
+}
+
+#[test]
+fn test_choose_strategy() {
+    use Strategy::*;
+    assert_eq!(Strategy::choose(None, Some(0), 100), OnePass);
+    assert_eq!(Strategy::choose(None, Some(100), 100), OnePass);
+    assert_eq!(Strategy::choose(None, Some(101), 100), TwoPass);
+    assert_eq!(Strategy::choose(None, None, 100), OnePass);
+    assert_eq!(Strategy::choose(Some(TwoPass), Some(1), 100), TwoPass);
+    assert_eq!(Strategy::choose(Some(OnePass), Some(1 << 40), 100), OnePass);
+}
+
+#[test]
+fn test_compress_one_pass() {
+    /// Counts the bytes read through it.
+    struct Counting<'a> {
+        inner: &'a [u8],
+        bytes_read: &'a std::cell::Cell<usize>,
+    }
+    impl Read for Counting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.inner.read(buf)?;
+            self.bytes_read.set(self.bytes_read.get() + len);
+            Ok(len)
+        }
+    }
+
+    let input = b"appends_a_given_slice".repeat(1000);
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let compressor = Compressor::new().mode(mode);
+        let bytes_read = std::cell::Cell::new(0);
+        let mut two_pass = vec![];
+        compressor
+            .compress_two_pass(
+                || {
+                    Ok(Counting {
+                        inner: &input,
+                        bytes_read: &bytes_read,
+                    })
+                },
+                &mut two_pass,
+            )
+            .unwrap();
+        assert_eq!(bytes_read.get(), 2 * input.len());
+
+        let bytes_read = std::cell::Cell::new(0);
+        let mut one_pass = vec![];
+        let stats = compressor
+            .compress_one_pass(
+                Counting {
+                    inner: &input,
+                    bytes_read: &bytes_read,
+                },
+                &mut one_pass,
+            )
+            .unwrap();
+        assert_eq!(bytes_read.get(), input.len());
+        assert!(one_pass == two_pass);
+        assert_eq!(stats.output_bytes, one_pass.len() as u64);
+    }
+}
+
+#[test]
+fn test_compress_io_calls() {
+    /// Counts the calls to `read` or `write`.
+    struct Counting<'a, T> {
+        inner: T,
+        calls: &'a std::cell::Cell<usize>,
+    }
+    impl<T: Read> Read for Counting<'_, T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.calls.set(self.calls.get() + 1);
+            self.inner.read(buf)
+        }
+    }
+    impl<T: Write> Write for Counting<'_, T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls.set(self.calls.get() + 1);
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    let input = b"appends_a_given_slice".repeat(200_000);
+    let compress = |compressor: &Compressor, buffered: bool| {
+        let (reads, writes) = (std::cell::Cell::new(0), std::cell::Cell::new(0));
+        let mut output = Counting {
+            inner: vec![],
+            calls: &writes,
+        };
+        let open = || {
+            Ok(Counting {
+                inner: &input[..],
+                calls: &reads,
+            })
+        };
+        if buffered {
+            let mut output = io::BufWriter::with_capacity(DEFAULT_OUTPUT_BUF_LEN, &mut output);
+            compressor.compress_two_pass(open, &mut output).unwrap();
+            output.flush().unwrap();
+        } else {
+            compressor.compress_two_pass(open, &mut output).unwrap();
+        }
+        assert!(output.inner == compressor.compress(&input));
+        (reads.get(), writes.get(), output.inner.len())
+    };
+
+    // Two passes over 4.2 MB, plus a few reads at the end of each.
+    let (reads, _, _) = compress(&Compressor::new().read_chunk_len(8192), false);
+    assert!(reads >= 2 * input.len() / 8192);
+    let (reads, writes, output_len) = compress(&Compressor::new(), false);
+    assert!(reads <= 2 * (input.len() / DEFAULT_READ_CHUNK_LEN + 3));
+    assert!(writes >= output_len / 4096);
+    let (_, writes, _) = compress(&Compressor::new(), true);
+    assert!(writes <= output_len / DEFAULT_OUTPUT_BUF_LEN + 1);
+}
+
+#[test]
+fn test_decompress_stream() {
+    let input = b"appends_a_given_slice".repeat(1000);
+    for &mode in &[Mode::Plain, Mode::Canonical, Mode::LengthLimited] {
+        let compressed = compress_with_metadata(&input, mode, "meta");
+        let mut output = vec![];
+        let metadata = Decompressor::new()
+            .decompress_stream(&mut io::Cursor::new(&compressed), &mut output)
+            .unwrap();
+        assert_eq!(output, input);
+        assert_eq!(metadata.as_deref(), Some("meta"));
+    }
+
+    let mut output = vec![];
+    decompress_stream(&mut io::Cursor::new(compress(b"")), &mut output).unwrap();
+    assert_eq!(output, b"");
+
+    let compressed = compress(&input);
+    assert!(matches!(
+        decompress_stream(&mut &compressed[..compressed.len() - 1], &mut io::sink()),
+        Err(Error::Decode(decode::DecodeError::UnexpectedEof))
+    ));
+    assert!(matches!(
+        Decompressor::new()
+            .max_output_size(Some(100))
+            .decompress_stream(&mut &compressed[..], &mut io::sink()),
+        Err(Error::LimitExceeded { limit: 100, .. })
+    ));
+
+    struct FailingWriter;
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    match decompress_stream(&mut &compressed[..], &mut FailingWriter) {
+        Err(Error::Write(err)) => assert_eq!(err.kind(), io::ErrorKind::BrokenPipe),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_buffers_reuse() {
+    // A large input fills the buffers, then smaller ones must not pick up any of it.
+    let inputs = [
+        corpus::random(100_000, 1),
+        corpus::text_like(5000, 1),
+        b"abracadabra".to_vec(),
+        corpus::random(10, 2),
+    ];
+    let mut buffers = Buffers::new();
+    for input in &inputs {
+        let code = CodeTable::new(&tree::compute_frequencies(input), Mode::Plain)
+            .to_code()
+            .unwrap();
+        let mut expected = vec![];
+        encode::encode_stream(&code, &input[..], &mut expected).unwrap();
+        let mut output = vec![];
+        encode::encode_stream_with(&code, &input[..], &mut output, &mut buffers).unwrap();
+        assert_eq!(output, expected);
+    }
+    for input in &inputs {
+        let mut output = vec![];
+        Decompressor::new()
+            .decompress_stream_with(&mut &compress(input)[..], &mut output, &mut buffers)
+            .unwrap();
+        assert_eq!(&output, input);
+    }
+}
+
+#[test]
+fn test_decompress_truncated() {
+    let compressed = compress(b"abracadabra");
+    assert!(matches!(
+        decompress(&compressed[..compressed.len() - 1]),
+        Err(Error::Decode(decode::DecodeError::UnexpectedEof))
+    ));
+}
@@ -1,68 +1,255 @@
-use std::io::{Read, Write};
+use std::io::Write;
 use std::time::Instant;
 use std::{env, fs, io};
 
-use huff::tree;
+use huff::bitstream::with_context;
+use huff::{Mode, Strategy};
 
-fn main() -> io::Result<()> {
-    let args: Vec<_> = env::args().collect();
+mod mmap;
 
-    let start = Instant::now();
+const USAGE: &str = "usage: huff [--quiet] [--mmap] [--one-pass|--two-pass] [--threads N]
+            [--level plain|canonical|limited] [--benchmark RUNS|--dry-run] FILE
+       huff --decompress [--max-size BYTES|unlimited] FILE";
 
-    let mut input_buf: Vec<u8> = (0..8192).map(|_| 0).collect();
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    std::process::exit(2);
+}
 
-    let mut table: Vec<usize> = (0..256).map(|_| 0).collect();
-    let mut infile = fs::File::open(&args[1])?;
-    loop {
-        let input_len = infile.read(&mut input_buf)?;
-        if input_len == 0 {
-            break;
+fn main() -> io::Result<()> {
+    let mut quiet = false;
+    let mut use_mmap = false;
+    let mut strategy = None;
+    let mut threads = 1;
+    let mut benchmark_runs = None;
+    let mut decompress = false;
+    let mut dry_run = false;
+    let mut mode = Mode::Plain;
+    let mut max_size = Some(huff::DEFAULT_MAX_OUTPUT_SIZE);
+    let mut filename = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-q" | "--quiet" => quiet = true,
+            "--mmap" => use_mmap = true,
+            "--one-pass" => strategy = Some(Strategy::OnePass),
+            "--two-pass" => strategy = Some(Strategy::TwoPass),
+            "-d" | "--decompress" => decompress = true,
+            "-n" | "--dry-run" => dry_run = true,
+            "--level" | "--mode" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg)));
+                mode = value
+                    .parse()
+                    .unwrap_or_else(|err: String| usage_error(&err));
+            }
+            "--threads" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg)));
+                threads = match value.parse() {
+                    Ok(threads) if threads > 0 => threads,
+                    _ => usage_error(&format!("invalid value for {}: {}", arg, value)),
+                };
+            }
+            "--benchmark" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg)));
+                benchmark_runs = match value.parse() {
+                    Ok(runs) if runs > 0 => Some(runs),
+                    _ => usage_error(&format!("invalid value for {}: {}", arg, value)),
+                };
+            }
+            "--max-size" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg)));
+                max_size = match value.as_str() {
+                    "unlimited" => None,
+                    _ => Some(value.parse().unwrap_or_else(|_| {
+                        usage_error(&format!("invalid value for {}: {}", arg, value))
+                    })),
+                };
+            }
+            _ => filename = Some(arg),
         }
-        for &s in input_buf[0..input_len].iter() {
-            table[s as usize] += 1;
+    }
+    let filename = filename.unwrap_or_else(|| usage_error("missing FILE"));
+    check_input(&filename)?;
+
+    if decompress && dry_run {
+        usage_error("--dry-run only applies to compression");
+    }
+    if dry_run && benchmark_runs.is_some() {
+        usage_error("--dry-run can't be combined with --benchmark");
+    }
+    if decompress {
+        decompress_file(&filename, max_size)
+    } else {
+        let compressor = huff::Compressor::new().mode(mode).threads(threads);
+        match benchmark_runs {
+            Some(runs) => benchmark_file(&filename, &compressor, runs),
+            None if dry_run => dry_run_file(&filename, &compressor, quiet),
+            None => compress_file(&filename, &compressor, quiet, use_mmap, strategy),
         }
     }
-    let frequencies = table
-        .into_iter()
-        .enumerate()
-        .filter(|(_, freq)| *freq > 0)
-        .map(|(s, freq)| (s as u8, freq))
-        .collect();
+}
 
-    eprintln!("T: counting:      {:?}", start.elapsed());
+/// Fail early for inputs that can't be read as a stream of bytes, like directories and devices,
+/// which would otherwise fail with a less clear error while reading, or not fail at all. Pipes
+/// are fine, they're compressed in one pass (see `Strategy::choose`).
+fn check_input(filename: &str) -> io::Result<()> {
+    let file_type = fs::metadata(filename)
+        .map_err(|err| with_context(err, format_args!("reading {}", filename)))?
+        .file_type();
+    #[cfg(unix)]
+    let is_device = {
+        use std::os::unix::fs::FileTypeExt;
+        file_type.is_block_device() || file_type.is_char_device()
+    };
+    #[cfg(not(unix))]
+    let is_device = false;
+    if file_type.is_dir() || is_device {
+        return Err(with_context(
+            io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"),
+            format_args!("reading {}", filename),
+        ));
+    }
+    Ok(())
+}
 
-    let start = Instant::now();
-    let code = tree::tree_to_code(&tree::build_tree(&frequencies));
-    eprintln!("T: building code: {:?}", start.elapsed());
+fn decompress_file(filename: &str, max_size: Option<u64>) -> io::Result<()> {
+    let mut input = fs::File::open(filename)
+        .map_err(|err| with_context(err, format_args!("reading {}", filename)))?;
+    let stdout = io::stdout();
+    let mut output = io::BufWriter::new(stdout.lock());
+    huff::Decompressor::new()
+        .max_output_size(max_size)
+        .decompress_stream(&mut input, &mut output)
+        .map_err(|err| match err {
+            huff::Error::Write(err) => with_context(err, "writing output"),
+            err => with_context(
+                io::Error::new(io::ErrorKind::InvalidData, err),
+                format_args!("decompressing {}", filename),
+            ),
+        })?;
+    output
+        .flush()
+        .map_err(|err| with_context(err, "writing output"))
+}
 
+/// With `use_mmap`, both passes run over a mapping of the file, if it can be mapped (see
+/// `mmap::Mmap::map`). Otherwise, the file is read once or twice, depending on `strategy` or,
+/// if it's not given, on the size of the file (see `Strategy::choose`).
+fn compress_file(
+    filename: &str,
+    compressor: &huff::Compressor,
+    quiet: bool,
+    use_mmap: bool,
+    strategy: Option<Strategy>,
+) -> io::Result<()> {
     let start = Instant::now();
-
-    let mut output_buf: Vec<u64> = (0..8192).map(|_| 0).collect();
-    let mut encoder = huff::encode::Encoder::new(&code);
     let stdout = io::stdout();
-    let mut output = stdout.lock();
-    let mut infile = fs::File::open(&args[1])?;
-    loop {
-        let input_len = infile.read(&mut input_buf)?;
-        if input_len == 0 {
-            break;
+    let mut output = io::BufWriter::with_capacity(huff::DEFAULT_OUTPUT_BUF_LEN, stdout.lock());
+    let mapping = if use_mmap {
+        fs::File::open(filename)
+            .ok()
+            // SAFETY: we can't stop other processes from changing the file while it's mapped,
+            // and accept that, like other command-line tools using `mmap`: at worst the encoder
+            // reports symbols missing from the code, or a truncation kills the process with
+            // `SIGBUS`. This process doesn't write to it.
+            .and_then(|file| unsafe { mmap::Mmap::map(&file) })
+    } else {
+        None
+    };
+    let stats = match &mapping {
+        Some(mapping) => compressor.compress_two_pass(|| Ok(&mapping[..]), &mut output),
+        None => {
+            let input_len = fs::metadata(filename)
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len());
+            match Strategy::choose(strategy, input_len, huff::DEFAULT_ONE_PASS_LIMIT) {
+                Strategy::OnePass => fs::File::open(filename)
+                    .map_err(|err| with_context(err, "opening input"))
+                    .and_then(|file| compressor.compress_one_pass(file, &mut output)),
+                Strategy::TwoPass => {
+                    compressor.compress_two_pass(|| fs::File::open(filename), &mut output)
+                }
+            }
         }
-        let mut input_off = 0;
-        while input_off < input_len {
-            let (input_consumed, output_len) =
-                encoder.encode(&input_buf[input_off..input_len], &mut output_buf);
-            //            eprintln!("input len: {} output len: {}", input_consumed, output_len * 8);
-            input_off += input_consumed;
-            output.write_all(as_raw_u8_slice(&output_buf[0..output_len]))?;
+    }
+    .map_err(|err| with_context(err, format_args!("compressing {}", filename)))?;
+    // Flushed before the time is taken, so that it includes writing everything out.
+    output
+        .flush()
+        .map_err(|err| with_context(err, "writing output"))?;
+    eprintln!("T: compressing:   {:?}", start.elapsed());
+
+    if !quiet {
+        for warning in stats.warnings {
+            eprintln!("warning: {}", warning);
         }
     }
 
-    eprintln!("T: encoding:      {:?}", start.elapsed());
+    Ok(())
+}
+
+/// Count the symbols of the file and build the code, and print the size that compressing it
+/// would give to stdout, without encoding it (see `huff::Compressor::dry_run`).
+fn dry_run_file(filename: &str, compressor: &huff::Compressor, quiet: bool) -> io::Result<()> {
+    let input = fs::File::open(filename)
+        .map_err(|err| with_context(err, format_args!("reading {}", filename)))?;
+    let stats = compressor
+        .dry_run(input)
+        .map_err(|err| with_context(err, format_args!("compressing {}", filename)))?;
+    let ratio = match stats.input_bytes {
+        0 => "n/a".to_string(),
+        input_bytes => format!("{:.3}", stats.output_bytes as f64 / input_bytes as f64),
+    };
+    println!(
+        "{} bytes predicted for {} bytes of input (ratio {})",
+        stats.output_bytes, stats.input_bytes, ratio
+    );
+
+    if !quiet {
+        for warning in stats.warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
 
     Ok(())
 }
 
-#[allow(clippy::needless_lifetimes)]
-fn as_raw_u8_slice<'a>(words: &'a [u64]) -> &'a [u8] {
-    unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) }
+/// Compress an in-memory copy of the file `runs` times, without writing the output, and print
+/// the throughput of the runs to stderr.
+fn benchmark_file(filename: &str, compressor: &huff::Compressor, runs: usize) -> io::Result<()> {
+    let input = fs::read(filename)
+        .map_err(|err| with_context(err, format_args!("reading {}", filename)))?;
+    // There's no throughput to report for no input: it would come out as `0.0 / 0.0`.
+    if input.is_empty() {
+        return Err(with_context(
+            io::Error::new(io::ErrorKind::InvalidInput, "empty input"),
+            format_args!("benchmarking {}", filename),
+        ));
+    }
+    let mut throughputs: Vec<f64> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            let _output = compressor.compress(&input);
+            input.len() as f64 / 1e6 / start.elapsed().as_secs_f64()
+        })
+        .collect();
+    throughputs.sort_by(f64::total_cmp);
+    eprintln!("{} runs over {} bytes:", runs, input.len());
+    for (name, throughput) in &[
+        ("min", throughputs[0]),
+        ("median", throughputs[runs / 2]),
+        ("max", throughputs[runs - 1]),
+    ] {
+        eprintln!("  {:<7}{:>10.1} MB/s", name, throughput);
+    }
+    Ok(())
 }
@@ -0,0 +1,95 @@
+//! Read-only memory mapping of input files, for the `--mmap` flag of the CLI.
+//!
+//! This lives in the binary rather than the library, which doesn't allow unsafe code.
+
+use std::fs::File;
+use std::ops::Deref;
+
+/// A read-only mapping of a whole file.
+///
+/// Modifications of the file while it's mapped would show up in the mapped data, making the
+/// counting and the encoding pass see different bytes; symbols missing from the code are then
+/// reported by the encoder. Truncation makes reads past the new end fail with `SIGBUS`, killing
+/// the process. Hence `map` is unsafe.
+pub struct Mmap {
+    #[cfg(unix)]
+    ptr: *mut libc::c_void,
+    #[cfg(unix)]
+    len: usize,
+    #[cfg(not(unix))]
+    never: std::convert::Infallible,
+}
+
+impl Mmap {
+    /// Map `file`, or return `None` if it can't be mapped: if it's not a regular file (e.g. a
+    /// pipe), if it's empty, if mapping fails, or if the platform isn't supported. Callers fall
+    /// back to reading the file then.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may modify or truncate the file while the mapping lives, including other processes.
+    #[cfg(unix)]
+    pub unsafe fn map(file: &File) -> Option<Mmap> {
+        use std::convert::TryFrom;
+        use std::os::unix::io::AsRawFd;
+
+        let metadata = file.metadata().ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        let len = usize::try_from(metadata.len()).ok()?;
+        // `mmap` rejects empty mappings.
+        if len == 0 {
+            return None;
+        }
+        // SAFETY: a fresh private read-only mapping doesn't alias any Rust memory. Reading from
+        // it is sound as long as the file isn't changed, which the caller guarantees.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Mmap { ptr, len })
+    }
+
+    /// # Safety
+    ///
+    /// See the Unix version.
+    #[cfg(not(unix))]
+    pub unsafe fn map(_file: &File) -> Option<Mmap> {
+        None
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` readable bytes until `drop`, see `Mmap`.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        match self.never {}
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created in `map`, and no slices of it outlive `self`.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
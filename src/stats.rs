@@ -0,0 +1,144 @@
+use crate::tree::Frequencies;
+
+/// A condition that doesn't prevent compression, but that the user should probably hear about.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Warning {
+    /// The input consists of a single distinct byte value, so the code degenerates to one bit per
+    /// symbol.
+    SingleSymbol(u8),
+    /// The encoded output is larger than the input.
+    Incompressible { input_bytes: u64, output_bytes: u64 },
+    /// Encoding would have made the output larger than the input, so it was stored as it is (see
+    /// `format::Payload::Stored`).
+    Stored,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Warning::SingleSymbol(sym) => write!(
+                f,
+                "input consists of a single distinct byte (0x{:02x}), using a degenerate 1-bit code",
+                sym
+            ),
+            Warning::Incompressible {
+                input_bytes,
+                output_bytes,
+            } => write!(
+                f,
+                "input is incompressible: output is {} bytes, input was {} bytes",
+                output_bytes, input_bytes
+            ),
+            Warning::Stored => f.write_str("input is incompressible, storing it as it is"),
+        }
+    }
+}
+
+/// Summary of a compression run.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Stats {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub distinct_symbols: usize,
+    pub warnings: Vec<Warning>,
+}
+
+impl Stats {
+    /// Collect statistics (and warnings) for an input with the given frequencies, which was
+    /// encoded to `output_bytes` bytes.
+    pub fn new(frequencies: &Frequencies, input_bytes: u64, output_bytes: u64) -> Self {
+        let mut warnings = vec![];
+        if let [(sym, _)] = frequencies[..] {
+            warnings.push(Warning::SingleSymbol(sym));
+        }
+        if output_bytes > input_bytes {
+            warnings.push(Warning::Incompressible {
+                input_bytes,
+                output_bytes,
+            });
+        }
+        Stats {
+            input_bytes,
+            output_bytes,
+            distinct_symbols: frequencies.len(),
+            warnings,
+        }
+    }
+
+    /// The statistics for an input that was stored as it is: it only warns about that, since the
+    /// output is larger than the input by design.
+    pub fn stored(mut self) -> Self {
+        self.warnings
+            .retain(|warning| !matches!(warning, Warning::Incompressible { .. }));
+        self.warnings.push(Warning::Stored);
+        self
+    }
+}
+
+#[cfg(test)]
+fn count_warnings(stats: &Stats, pred: impl Fn(&Warning) -> bool) -> usize {
+    stats.warnings.iter().filter(|w| pred(w)).count()
+}
+
+#[test]
+fn test_no_warnings() {
+    let (_, stats) = crate::compress_with_stats(&b"abracadabra".repeat(10), crate::Mode::Plain);
+    assert_eq!(stats.warnings, vec![]);
+}
+
+#[test]
+fn test_single_symbol_warning() {
+    let (_, stats) = crate::compress_with_stats(&[b'x'; 1000], crate::Mode::Plain);
+    assert_eq!(
+        count_warnings(&stats, |w| *w == Warning::SingleSymbol(b'x')),
+        1
+    );
+}
+
+#[test]
+fn test_stored_warning() {
+    let (output, stats) = crate::compress_with_stats(b"ab", crate::Mode::Plain);
+    assert!(output.len() > 2);
+    assert_eq!(stats.output_bytes, output.len() as u64);
+    assert_eq!(stats.warnings, vec![Warning::Stored]);
+}
+
+#[test]
+fn test_incompressible_warning() {
+    let mut output = vec![];
+    let stats = crate::Compressor::new()
+        .store_incompressible(false)
+        .compress_two_pass(|| Ok(&b"ab"[..]), &mut output)
+        .unwrap();
+    assert_eq!(
+        count_warnings(&stats, |w| match w {
+            Warning::Incompressible {
+                input_bytes,
+                output_bytes,
+            } => *input_bytes == 2 && *output_bytes == output.len() as u64,
+            _ => false,
+        }),
+        1
+    );
+}
+
+#[test]
+fn test_stats_past_4_gib() {
+    let five_gib = 5 << 30;
+    let stats = Stats::new(&vec![(b'a', five_gib)], five_gib, five_gib / 8 + 100);
+    assert_eq!(stats.input_bytes, five_gib);
+    assert_eq!(stats.warnings, vec![Warning::SingleSymbol(b'a')]);
+
+    let stats = Stats::new(
+        &vec![(b'a', 1 << 32), (b'b', 1)],
+        (1 << 32) + 1,
+        (1 << 32) + 2,
+    );
+    assert_eq!(
+        stats.warnings,
+        vec![Warning::Incompressible {
+            input_bytes: (1 << 32) + 1,
+            output_bytes: (1 << 32) + 2,
+        }]
+    );
+}
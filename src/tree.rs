@@ -1,9 +1,18 @@
 #![allow(clippy::ptr_arg)]
 
-use crate::heap;
-use crate::heap::keyed::Keyed;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
 
-pub type Frequencies = Vec<(u8, usize)>;
+use crate::bitstream::read_chunk;
+
+use crate::heap::{BinaryMinHeap, Keyed};
+
+#[cfg(test)]
+use crate::corpus;
+
+/// Number of occurrences of each symbol, as (symbol, count) pairs. Counts are `u64` rather
+/// than `usize`, so that inputs over 4 GiB can be counted on 32-bit hosts too.
+pub type Frequencies = Vec<(u8, u64)>;
 
 /// Count the number of occurences of each character in the input.
 ///
@@ -16,42 +25,309 @@ pub type Frequencies = Vec<(u8, usize)>;
 /// );
 /// ```
 pub fn compute_frequencies(input: &[u8]) -> Frequencies {
-    let mut table: Vec<usize> = (0..NUM_SYMBOLS).map(|_| 0).collect();
-    for s in input {
-        table[*s as usize] += 1;
+    frequencies_from_counts(&count_table(input))
+}
+
+/// Count the number of occurrences of each byte in the input, as a table indexed by symbol.
+///
+/// For callers that want the dense form as well as `Frequencies`, which can be made from it
+/// with `frequencies_from_counts`.
+pub fn count_table(input: &[u8]) -> [u64; NUM_SYMBOLS] {
+    let mut counts = [0; NUM_SYMBOLS];
+    count_symbols(input, &mut counts);
+    counts
+}
+
+/// Add the number of occurrences of each byte in the input to `counts`, for counting in chunks.
+pub fn count_symbols(input: &[u8], counts: &mut [u64; NUM_SYMBOLS]) {
+    // Runs of the same byte would make each increment wait for the store of the previous one.
+    // Spreading consecutive bytes over four tables lets the increments overlap. The tables only
+    // hold `u32`s to stay small, so they're flushed before they could overflow.
+    //
+    // Setting up and summing the tables costs more than that saves for small inputs, which are
+    // counted directly.
+    const SMALL_INPUT_LEN: usize = 128;
+    const BLOCK_LEN: usize = 1 << 30;
+    if input.len() < SMALL_INPUT_LEN {
+        for &sym in input {
+            counts[sym as usize] += 1;
+        }
+        return;
     }
-    table
-        .into_iter()
+    for block in input.chunks(BLOCK_LEN) {
+        let mut tables = [[0u32; NUM_SYMBOLS]; 4];
+        let mut quads = block.chunks_exact(4);
+        for quad in &mut quads {
+            tables[0][quad[0] as usize] += 1;
+            tables[1][quad[1] as usize] += 1;
+            tables[2][quad[2] as usize] += 1;
+            tables[3][quad[3] as usize] += 1;
+        }
+        for &sym in quads.remainder() {
+            tables[0][sym as usize] += 1;
+        }
+        for (sym, count) in counts.iter_mut().enumerate() {
+            *count += tables.iter().map(|table| table[sym] as u64).sum::<u64>();
+        }
+    }
+}
+
+/// The non-zero counts, indexed by symbol, as frequencies.
+pub fn frequencies_from_counts(counts: &[u64; NUM_SYMBOLS]) -> Frequencies {
+    counts
+        .iter()
         .enumerate()
-        .filter(|(_, freq)| *freq > 0)
-        .map(|(s, freq)| (s as u8, freq))
+        .filter(|&(_, &count)| count > 0)
+        .map(|(sym, &count)| (sym as u8, count))
         .collect()
 }
 
+/// The entries of `frequencies` with a non-zero count, in the same order.
+pub(crate) fn nonzero_frequencies(
+    frequencies: &Frequencies,
+) -> impl Iterator<Item = (u8, u64)> + '_ {
+    frequencies.iter().filter(|&&(_, freq)| freq > 0).cloned()
+}
+
+/// The non-zero counts of a map from symbols, as frequencies sorted by symbol.
+pub fn frequencies_from_map(map: &HashMap<u8, usize>) -> Frequencies {
+    let mut counts = [0; NUM_SYMBOLS];
+    for (&sym, &count) in map {
+        counts[sym as usize] = count as u64;
+    }
+    frequencies_from_counts(&counts)
+}
+
 type Symbol = u8;
 
-#[derive(PartialEq, Eq, Debug)]
+/// A code tree. Nothing here recurses on the tree structure (except for `Debug`), so even
+/// trees much deeper than a code can be, e.g. built by hand, are safe to compare and drop.
+#[derive(Eq, Debug)]
 pub enum Tree {
     Branch(Box<Tree>, Box<Tree>),
     Leaf(Symbol),
 }
 
-const NUM_SYMBOLS: usize = 256;
+impl PartialEq for Tree {
+    fn eq(&self, other: &Tree) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some(pair) = stack.pop() {
+            match pair {
+                (Tree::Branch(left1, right1), Tree::Branch(left2, right2)) => {
+                    stack.push((right1, right2));
+                    stack.push((left1, left2));
+                }
+                (Tree::Leaf(sym1), Tree::Leaf(sym2)) => {
+                    if sym1 != sym2 {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        // The default drop recurses to the depth of the tree. Instead, detach the subtrees that
+        // are branches, so that each node is dropped with only leaves below it.
+        let mut stack = vec![];
+        detach_branches(self, &mut stack);
+        while let Some(mut tree) = stack.pop() {
+            detach_branches(&mut tree, &mut stack);
+        }
+    }
+}
+
+fn detach_branches(tree: &mut Tree, stack: &mut Vec<Tree>) {
+    if let Tree::Branch(left, right) = tree {
+        for child in [left, right] {
+            if let Tree::Branch(..) = **child {
+                stack.push(std::mem::replace(&mut **child, Tree::Leaf(0)));
+            }
+        }
+    }
+}
+
+pub(crate) const NUM_SYMBOLS: usize = 256;
 
+/// Build a Huffman tree for the frequencies.
+///
+/// Symbols with a count of zero are left out, rather than getting codewords that would never be
+/// used. Panics if no symbol has a non-zero count.
+///
+/// Weights of subtrees saturate at `u64::MAX`, which can only happen for made-up counts (e.g.
+/// from an untrusted header); the tree is still deterministic then, just not optimal.
+///
+/// Ties between equal weights are broken by age: leaves come first, in order of symbol, then
+/// merged subtrees in the order they were created. The lighter of the two nodes merged goes on
+/// the left. So the tree only depends on the counts, not on the order of `frequencies`.
 pub fn build_tree(frequencies: &Frequencies) -> Tree {
-    let mut heap: Vec<Keyed<usize, Tree>> = Vec::with_capacity(frequencies.len());
-    for (symbol, freq) in frequencies {
-        heap::insert(&mut heap, Keyed::new(*freq, Tree::Leaf(*symbol)));
-    }
-    while let Some(left) = heap::pop(&mut heap) {
-        if let Some(right) = heap::pop(&mut heap) {
-            heap::insert(
-                &mut heap,
-                Keyed::new(
-                    left.key + right.key,
-                    Tree::Branch(Box::new(left.value), Box::new(right.value)),
-                ),
-            );
+    build_tree_arena(frequencies).to_tree()
+}
+
+/// `build_tree`, without allocating each node separately.
+pub fn build_tree_arena(frequencies: &Frequencies) -> TreeArena {
+    two_queue_arena(&sorted_leaves(frequencies), |_, _, _| {})
+}
+
+/// `build_tree`, also returning each merge in the order they happen, as `(left_weight,
+/// right_weight, combined_weight)`, e.g. to show how the tree is built step by step.
+///
+/// ```
+/// let (_, merges) = huff::tree::build_tree_traced(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+/// assert_eq!(merges, vec![(2, 4, 6), (6, 6, 12)]);
+/// ```
+pub fn build_tree_traced(frequencies: &Frequencies) -> (Tree, Vec<(u64, u64, u64)>) {
+    let mut merges = vec![];
+    let arena = two_queue_arena(&sorted_leaves(frequencies), |left, right, combined| {
+        merges.push((left, right, combined))
+    });
+    (arena.to_tree(), merges)
+}
+
+/// `build_tree` for the frequencies of the first `sample_bytes` bytes of `input` only, to save a
+/// counting pass over the whole of a huge input. The tree is only optimal for the rest of the
+/// input if it looks like the sample, and symbols that don't occur in the sample get no codeword
+/// at all, so the caller has to deal with them. Returns `None` if the sample is empty.
+///
+/// Reads no further than the sample.
+pub fn build_tree_sampled<R: Read>(input: R, sample_bytes: u64) -> io::Result<Option<Tree>> {
+    let mut input = input.take(sample_bytes);
+    let mut counts = [0; NUM_SYMBOLS];
+    let mut buf = vec![0; sample_bytes.min(64 << 10) as usize];
+    loop {
+        let len = read_chunk(&mut input, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+        count_symbols(&buf[..len], &mut counts);
+    }
+    let frequencies = frequencies_from_counts(&counts);
+    Ok(if frequencies.is_empty() {
+        None
+    } else {
+        Some(build_tree(&frequencies))
+    })
+}
+
+fn sorted_leaves(frequencies: &Frequencies) -> Frequencies {
+    // Sized up front, since `collect` can't tell how many symbols the filter leaves.
+    let mut leaves = Vec::with_capacity(frequencies.len());
+    leaves.extend(nonzero_frequencies(frequencies));
+    leaves.sort_by_key(|&(symbol, freq)| (freq, symbol));
+    leaves
+}
+
+/// Build a Huffman tree for frequencies sorted by count, in linear time: the leaves are taken
+/// in order from one queue, and merged subtrees, which are created in order of weight, are
+/// appended to another. Symbols with a count of zero are left out. Panics if the frequencies are
+/// not sorted by count, or no symbol has a non-zero count.
+///
+/// Ties between equal weights go to leaves first, in the order given, then to merged subtrees
+/// in the order they were created. Any order of ties gives an optimal code, but the shape of
+/// the tree depends on it; for frequencies sorted by count and then by symbol, the tree is the
+/// one `build_tree` returns.
+pub fn build_tree_two_queue(frequencies_sorted: &Frequencies) -> Tree {
+    two_queue_arena(frequencies_sorted, |_, _, _| {}).to_tree()
+}
+
+/// Calls `on_merge` with the weights of the two subtrees and of the result, for each merge.
+fn two_queue_arena(
+    frequencies_sorted: &Frequencies,
+    mut on_merge: impl FnMut(u64, u64, u64),
+) -> TreeArena {
+    assert!(
+        frequencies_sorted
+            .windows(2)
+            .all(|pair| pair[0].1 <= pair[1].1),
+        "frequencies are not sorted by count"
+    );
+    let mut arena = TreeArena {
+        nodes: Vec::with_capacity(frequencies_sorted.len() * 2),
+        root: 0,
+    };
+    let mut leaves = VecDeque::with_capacity(frequencies_sorted.len());
+    leaves.extend(
+        nonzero_frequencies(frequencies_sorted)
+            .map(|(symbol, freq)| (freq, arena.push(Node::Leaf(symbol)))),
+    );
+    let mut merged = VecDeque::with_capacity(leaves.len());
+    while let Some(left) = pop_lightest(&mut leaves, &mut merged) {
+        if let Some(right) = pop_lightest(&mut leaves, &mut merged) {
+            let branch = arena.push(Node::Branch(left.1, right.1));
+            let weight = left.0.saturating_add(right.0);
+            on_merge(left.0, right.0, weight);
+            merged.push_back((weight, branch));
+        } else {
+            arena.root = left.1;
+            return arena;
+        }
+    }
+    panic!("No symbols with non-zero frequency in input");
+}
+
+/// The lighter of the fronts of the two queues of `build_tree_two_queue`, the leaf on a tie.
+fn pop_lightest(
+    leaves: &mut VecDeque<(u64, NodeId)>,
+    merged: &mut VecDeque<(u64, NodeId)>,
+) -> Option<(u64, NodeId)> {
+    match (leaves.front(), merged.front()) {
+        (Some(leaf), Some(subtree)) if leaf.0 > subtree.0 => merged.pop_front(),
+        (Some(_), _) => leaves.pop_front(),
+        (None, _) => merged.pop_front(),
+    }
+}
+
+/// `build_tree` with a heap, whose keys carry the age of each node to break ties the same way.
+#[cfg(test)]
+fn build_tree_heap(frequencies: &Frequencies) -> Tree {
+    let mut leaves: Frequencies = nonzero_frequencies(frequencies).collect();
+    leaves.sort_by_key(|&(symbol, _)| symbol);
+    let mut seq = 0;
+    let mut next_key = |weight: u64| {
+        seq += 1;
+        (weight, seq)
+    };
+    let leaves: Vec<_> = leaves
+        .into_iter()
+        .map(|(symbol, freq)| Keyed::new(next_key(freq), Tree::Leaf(symbol)))
+        .collect();
+    let mut heap = BinaryMinHeap::from(leaves);
+    let mut left = heap
+        .pop()
+        .expect("No symbols with non-zero frequency in input");
+    while let Some(right) = heap.pop() {
+        let key = next_key(left.key.0.saturating_add(right.key.0));
+        // Keys are unique, so pushing the merged node and popping the next one in one sift
+        // gives the same node as doing them one after the other.
+        left = heap.push_pop(Keyed::new(
+            key,
+            Tree::Branch(Box::new(left.value), Box::new(right.value)),
+        ));
+    }
+    left.value
+}
+
+/// `build_tree` as it was before ties were broken explicitly, where they depended on the layout
+/// of the heap. Frequency tables of kind 0 (`format::CodeTable::LegacyFrequencies`) have to be
+/// decoded with it.
+pub fn build_tree_legacy(frequencies: &Frequencies) -> Tree {
+    // The leaves have to be pushed one by one: `BinaryMinHeap::from` lays out equal weights
+    // differently, which would change which of them are merged first, and so the tree. For the
+    // same reason, merged nodes are pushed and popped separately, not with `push_pop`.
+    let mut heap: BinaryMinHeap<Keyed<u64, Tree>> = BinaryMinHeap::with_capacity(frequencies.len());
+    for (symbol, freq) in nonzero_frequencies(frequencies) {
+        heap.push(Keyed::new(freq, Tree::Leaf(symbol)));
+    }
+    while let Some(left) = heap.pop() {
+        if let Some(right) = heap.pop() {
+            heap.push(Keyed::new(
+                left.key.saturating_add(right.key),
+                Tree::Branch(Box::new(left.value), Box::new(right.value)),
+            ));
         } else {
             return left.value;
         }
@@ -59,6 +335,80 @@ pub fn build_tree(frequencies: &Frequencies) -> Tree {
     panic!("No symbols with non-zero frequency in input");
 }
 
+/// Number of bits of precision used by `huffman_from_probabilities`.
+pub const DEFAULT_PROBABILITY_PRECISION: u32 = 24;
+
+#[derive(PartialEq, Debug)]
+pub enum ProbabilityError {
+    /// The weight of the given symbol was negative.
+    Negative(u8),
+    /// The weight of the given symbol was NaN or infinite.
+    NotFinite(u8),
+    /// No symbol had a positive weight.
+    Empty,
+}
+
+impl std::fmt::Display for ProbabilityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProbabilityError::Negative(sym) => write!(f, "negative weight for symbol {}", sym),
+            ProbabilityError::NotFinite(sym) => write!(f, "non-finite weight for symbol {}", sym),
+            ProbabilityError::Empty => f.write_str("no symbols with positive weight"),
+        }
+    }
+}
+
+impl std::error::Error for ProbabilityError {}
+
+/// Build a tree from floating-point weights (e.g. probabilities from a model) instead of
+/// integer counts.
+///
+/// Equivalent to `huffman_from_probabilities_with_precision(probabilities,
+/// DEFAULT_PROBABILITY_PRECISION)`.
+pub fn huffman_from_probabilities(probabilities: &[(u8, f64)]) -> Result<Tree, ProbabilityError> {
+    huffman_from_probabilities_with_precision(probabilities, DEFAULT_PROBABILITY_PRECISION)
+}
+
+/// Build a tree from floating-point weights, scaled to integer frequencies summing to roughly
+/// `2^precision`.
+///
+/// The weights don't have to sum to 1.0, they are normalized first. Symbols with zero weight
+/// are left out of the tree; positive weights too small to be represented at the given
+/// precision are rounded up to 1, so that every such symbol still gets a codeword.
+///
+/// Panics if `precision` is not in `1..=32`.
+pub fn huffman_from_probabilities_with_precision(
+    probabilities: &[(u8, f64)],
+    precision: u32,
+) -> Result<Tree, ProbabilityError> {
+    assert!(
+        (1..=32).contains(&precision),
+        "precision must be between 1 and 32 bits, got {}",
+        precision
+    );
+    let mut total = 0.0;
+    for &(sym, p) in probabilities {
+        if !p.is_finite() {
+            return Err(ProbabilityError::NotFinite(sym));
+        }
+        if p < 0.0 {
+            return Err(ProbabilityError::Negative(sym));
+        }
+        total += p;
+    }
+    if total <= 0.0 {
+        return Err(ProbabilityError::Empty);
+    }
+
+    let scale = (1u64 << precision) as f64 / total;
+    let frequencies: Frequencies = probabilities
+        .iter()
+        .filter(|(_, p)| *p > 0.0)
+        .map(|&(sym, p)| (sym, ((p * scale).round() as u64).max(1)))
+        .collect();
+    Ok(build_tree(&frequencies))
+}
+
 #[test]
 fn test_build_tree() {
     assert_eq!(
@@ -73,12 +423,183 @@ fn test_build_tree() {
     );
 }
 
+#[test]
+fn test_build_tree_traced() {
+    let frequencies = vec![(b'A', 6), (b'B', 4), (b'C', 2)];
+    let (tree, merges) = build_tree_traced(&frequencies);
+    assert_eq!(tree, build_tree(&frequencies));
+    // C and B first, then A with the new subtree, which is younger and so goes on the right.
+    assert_eq!(merges, vec![(2, 4, 6), (6, 6, 12)]);
+
+    let (tree, merges) = build_tree_traced(&vec![(b'x', 3)]);
+    assert_eq!(tree, Tree::Leaf(b'x'));
+    assert!(merges.is_empty());
+}
+
+#[test]
+fn test_build_tree_ties() {
+    // A and B are merged first, as the oldest of the equal weights, and C is lighter than AB.
+    let expected = Tree::Branch(
+        Box::new(Tree::Leaf(b'C')),
+        Box::new(Tree::Branch(
+            Box::new(Tree::Leaf(b'A')),
+            Box::new(Tree::Leaf(b'B')),
+        )),
+    );
+    assert_eq!(build_tree(&vec![(b'A', 1), (b'B', 1), (b'C', 1)]), expected);
+    assert_eq!(build_tree(&vec![(b'C', 1), (b'B', 1), (b'A', 1)]), expected);
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_build_tree_ignores_order(input: Vec<u8>, rotation: usize) -> bool {
+    // Small alphabets of short inputs give lots of equal counts.
+    let frequencies = compute_frequencies(&input);
+    if frequencies.is_empty() {
+        return true;
+    }
+    let mut permuted = frequencies.clone();
+    permuted.reverse();
+    permuted.rotate_left(rotation % frequencies.len());
+    let tree = build_tree(&frequencies);
+    tree == build_tree(&permuted) && tree == build_tree(&frequencies)
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_build_tree_matches_heap(input: Vec<u8>) -> bool {
+    let frequencies = compute_frequencies(&input);
+    frequencies.is_empty() || build_tree(&frequencies) == build_tree_heap(&frequencies)
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_build_tree_two_queue_optimal(counts: Vec<(u8, u16)>) -> bool {
+    // Ties in reverse order of symbol, unlike `build_tree`.
+    let mut frequencies: Frequencies = counts
+        .iter()
+        .map(|&(sym, count)| (sym, count as u64 + 1))
+        .collect::<std::collections::BTreeMap<_, _>>()
+        .into_iter()
+        .rev()
+        .collect();
+    if frequencies.is_empty() {
+        return true;
+    }
+    frequencies.sort_by_key(|&(_, count)| count);
+    let path_length = |tree: &Tree| {
+        let code = tree_to_code(tree);
+        frequencies
+            .iter()
+            .map(|&(sym, count)| count * code[sym].bit_len as u64)
+            .sum::<u64>()
+    };
+    path_length(&build_tree_two_queue(&frequencies))
+        == path_length(&build_tree_legacy(&frequencies))
+}
+
+#[test]
+#[should_panic(expected = "frequencies are not sorted by count")]
+fn test_build_tree_two_queue_unsorted() {
+    build_tree_two_queue(&vec![(b'A', 2), (b'B', 1)]);
+}
+
+#[test]
+fn test_tree_arena() {
+    let tree = build_tree(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+    let arena = TreeArena::from_tree(&tree);
+    assert_eq!(
+        arena.nodes,
+        vec![
+            Node::Leaf(b'A'),
+            Node::Leaf(b'C'),
+            Node::Leaf(b'B'),
+            Node::Branch(1, 2),
+            Node::Branch(0, 3),
+        ]
+    );
+    assert_eq!(arena.root(), 4);
+    assert!(arena.to_tree() == tree);
+
+    // `build_tree_arena` adds the leaves first, lightest first: C, B, A.
+    let built = build_tree_arena(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+    assert_eq!(built.node(built.root()), Node::Branch(2, 3));
+    assert!(built.to_tree() == tree);
+
+    let leaf = TreeArena::from_tree(&Tree::Leaf(b'x'));
+    assert_eq!((leaf.len(), leaf.node(leaf.root())), (1, Node::Leaf(b'x')));
+    assert_eq!(
+        format!("{}", leaf.to_code_with(SingleLeaf::OneBit)),
+        "x: 0\n"
+    );
+    assert!(leaf.to_code_with(SingleLeaf::ZeroLength)[b'x'].is_empty());
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_tree_arena_conversions(input: Vec<u8>) -> bool {
+    let frequencies = compute_frequencies(&input);
+    if frequencies.is_empty() {
+        return true;
+    }
+    let tree = build_tree(&frequencies);
+    let arena = build_tree_arena(&frequencies);
+    let leaves: Vec<_> = tree.iter_leaves_with_codewords().collect();
+    TreeArena::from_tree(&tree).to_tree() == tree
+        && TreeArena::from_tree(&arena.to_tree()).leaves_with_codewords() == leaves
+        && arena.leaves_with_codewords() == leaves
+        && format!("{}", arena.to_code_with(SingleLeaf::OneBit))
+            == format!("{}", tree_to_code(&tree))
+}
+
 pub struct Code(Vec<Codeword>);
 
 impl Code {
     pub fn empty() -> Self {
         Code((0..NUM_SYMBOLS).map(|_| Codeword::empty()).collect())
     }
+
+    /// Length of the longest codeword, in bits.
+    pub fn max_len(&self) -> usize {
+        self.0.iter().map(|cw| cw.bit_len).max().unwrap_or(0)
+    }
+
+    /// Whether every sequence of bits starts with a codeword, i.e. the Kraft sum of the
+    /// codeword lengths is exactly 1, assuming the code is prefix-free. An incomplete code leaves
+    /// bit patterns which no codeword starts with, and which `Decoder` rejects as
+    /// `DecodeError::InvalidCodeword`. A code without codewords isn't complete.
+    pub fn is_complete(&self) -> bool {
+        let mut counts = [0usize; MAX_CODEWORD_BITS + 1];
+        for (_, cw) in self {
+            counts[cw.bit_len] += 1;
+        }
+        // Pair up the codewords of each length into prefixes one bit shorter, which only works
+        // out if there's never one left over.
+        let mut pending = 0;
+        for &count in counts[1..].iter().rev() {
+            pending += count;
+            if pending % 2 != 0 {
+                return false;
+            }
+            pending /= 2;
+        }
+        pending == 1
+    }
+
+    /// The smallest Hamming distance between two distinct codewords of the same length, or `None`
+    /// if no two codewords have the same length.
+    ///
+    /// A distance of 1 means that a single flipped bit can turn one symbol into another without
+    /// desynchronizing the decoder. (Flips that change the length of the decoded codeword
+    /// aren't covered.)
+    pub fn min_distance(&self) -> Option<usize> {
+        let codewords: Vec<&Codeword> = self.into_iter().map(|(_, cw)| cw).collect();
+        let mut min = None;
+        for (i, a) in codewords.iter().enumerate() {
+            for b in &codewords[i + 1..] {
+                if let Some(distance) = a.hamming_distance(b) {
+                    min = Some(min.map_or(distance, |min: usize| min.min(distance)));
+                }
+            }
+        }
+        min
+    }
 }
 
 impl std::ops::Index<u8> for Code {
@@ -95,20 +616,110 @@ impl std::ops::IndexMut<u8> for Code {
     }
 }
 
+/// Iterates over the symbols with non-empty codewords, in order of symbol.
+impl<'a> IntoIterator for &'a Code {
+    type Item = (u8, &'a Codeword);
+    type IntoIter = CodeIter<'a>;
+
+    fn into_iter(self) -> CodeIter<'a> {
+        CodeIter(self.0.iter().enumerate())
+    }
+}
+
+/// See `IntoIterator for &Code`.
+pub struct CodeIter<'a>(std::iter::Enumerate<std::slice::Iter<'a, Codeword>>);
+
+impl<'a> Iterator for CodeIter<'a> {
+    type Item = (u8, &'a Codeword);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0
+            .find(|(_, cw)| !cw.is_empty())
+            .map(|(sym, cw)| (sym as u8, cw))
+    }
+}
+
 impl std::fmt::Display for Code {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        for sym in 0..NUM_SYMBOLS {
-            let cw = &self[sym as u8];
-            if !cw.is_empty() {
-                writeln!(f, "{}: {}", sym as u8 as char, cw)?;
-            }
+        for (sym, cw) in self {
+            writeln!(f, "{}: {}", sym as char, cw)?;
         }
         Ok(())
     }
 }
 
+/// The codewords of a `Code`, without padding each one to `MAX_CODEWORD_BITS` bits: about 1 KiB
+/// plus the words the codewords actually take, rather than 10 KiB. For keeping many codes around,
+/// e.g. in encoders; `Code` is the one to build and inspect codes with.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CompactCode {
+    /// Length of each symbol's codeword, in bits.
+    lengths: [u16; NUM_SYMBOLS],
+    /// Index in `words` of each symbol's first word.
+    starts: [u16; NUM_SYMBOLS],
+    /// The words of all codewords, one codeword after another, in the layout of
+    /// `Codeword::bits`.
+    words: Vec<u64>,
+}
+
+impl CompactCode {
+    pub fn new(code: &Code) -> Self {
+        let mut lengths = [0; NUM_SYMBOLS];
+        let mut starts = [0; NUM_SYMBOLS];
+        let mut words = vec![];
+        for (sym, cw) in code.0.iter().enumerate() {
+            lengths[sym] = cw.bit_len as u16;
+            starts[sym] = words.len() as u16;
+            words.extend_from_slice(&cw.bits[..cw.bit_len.div_ceil(64)]);
+        }
+        CompactCode {
+            lengths,
+            starts,
+            words,
+        }
+    }
+
+    /// Length of the codeword of `sym`, in bits; 0 if it has none.
+    pub fn bit_len(&self, sym: u8) -> usize {
+        self.lengths[sym as usize] as usize
+    }
+
+    /// The bits of the codeword of `sym`, like `Codeword::bits` but only as many words as it
+    /// takes.
+    pub fn words(&self, sym: u8) -> &[u64] {
+        let start = self.starts[sym as usize] as usize;
+        &self.words[start..start + self.bit_len(sym).div_ceil(64)]
+    }
+
+    /// Bit `index` of the codeword of `sym`.
+    pub fn get_bit(&self, sym: u8, index: usize) -> bool {
+        assert!(index < self.bit_len(sym), "index out of range");
+        self.words(sym)[index / 64] >> (index % 64) & 1 != 0
+    }
+
+    /// Length of the longest codeword, in bits.
+    pub fn max_len(&self) -> usize {
+        *self.lengths.iter().max().unwrap() as usize
+    }
+
+    /// Number of bytes taken, including the heap.
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.words.len() * std::mem::size_of::<u64>()
+    }
+
+    pub fn to_code(&self) -> Code {
+        let mut code = Code::empty();
+        for sym in 0..NUM_SYMBOLS {
+            let sym = sym as u8;
+            code[sym].bit_len = self.bit_len(sym);
+            code[sym].bits[..self.words(sym).len()].copy_from_slice(self.words(sym));
+        }
+        code
+    }
+}
+
 pub const MAX_CODEWORD_BITS: usize = NUM_SYMBOLS;
-const NUM_CODEWORD_WORDS: usize = MAX_CODEWORD_BITS / 64;
+pub const NUM_CODEWORD_WORDS: usize = MAX_CODEWORD_BITS / 64;
 
 pub const B0: bool = false;
 pub const B1: bool = true;
@@ -127,17 +738,52 @@ pub struct Codeword {
 }
 
 impl Codeword {
-    pub fn empty() -> Self {
+    pub const fn empty() -> Self {
         Codeword {
             bit_len: 0,
             bits: [0; NUM_CODEWORD_WORDS],
         }
     }
 
+    /// The codeword of the first `bit_len` bits of `bits`, in the layout of `Codeword::bits`.
+    /// Usable in constants, e.g. for codes fixed at compile time:
+    ///
+    /// ```
+    /// use huff::tree::Codeword;
+    ///
+    /// static CODE: [Codeword; 2] = [
+    ///     Codeword::from_raw(1, [0b0, 0, 0, 0]),
+    ///     Codeword::from_raw(3, [0b011, 0, 0, 0]),
+    /// ];
+    /// assert_eq!(CODE[1].to_string(), "110");
+    /// ```
+    ///
+    /// `bit_len` must be at most `MAX_CODEWORD_BITS`, and the bits past it zero. This is only
+    /// checked in debug builds.
+    pub const fn from_raw(bit_len: usize, bits: [u64; NUM_CODEWORD_WORDS]) -> Self {
+        debug_assert!(bit_len <= MAX_CODEWORD_BITS, "codeword is too long");
+        if cfg!(debug_assertions) {
+            let mut i = 0;
+            while i < NUM_CODEWORD_WORDS {
+                let used = bit_len.saturating_sub(64 * i);
+                debug_assert!(used >= 64 || bits[i] >> used == 0, "bits past the length");
+                i += 1;
+            }
+        }
+        Codeword { bit_len, bits }
+    }
+
+    /// Panics if there are more than `MAX_CODEWORD_BITS` bits.
     pub fn from_bits(bits: &[bool]) -> Self {
         let mut cw = Self::empty();
         for &bit in bits {
-            cw.push_bit(bit);
+            if cw.try_push_bit(bit).is_err() {
+                panic!(
+                    "Codeword::from_bits: {} bits given, the maximum is {}",
+                    bits.len(),
+                    MAX_CODEWORD_BITS
+                );
+            }
         }
         cw
     }
@@ -146,20 +792,164 @@ impl Codeword {
         self.bit_len == 0
     }
 
+    /// Returns the bit at the given position.
+    pub fn get_bit(&self, index: usize) -> bool {
+        (self.bits[index / 64] >> (index % 64)) & 1 != 0
+    }
+
     /// Adds a bit to the end to the sequence.
+    ///
+    /// Panics if the sequence already has `MAX_CODEWORD_BITS` bits.
     pub fn push_bit(&mut self, bit: bool) {
+        if self.try_push_bit(bit).is_err() {
+            panic!(
+                "Codeword::push_bit: codeword already has the maximum of {} bits",
+                MAX_CODEWORD_BITS
+            );
+        }
+    }
+
+    /// Adds a bit to the end to the sequence, or returns an error if the sequence already has
+    /// `MAX_CODEWORD_BITS` bits.
+    pub fn try_push_bit(&mut self, bit: bool) -> Result<(), CodewordFull> {
         let index = self.bit_len;
+        if index == MAX_CODEWORD_BITS {
+            return Err(CodewordFull);
+        }
         self.bits[index / 64] |= (bit as u64) << (index % 64);
         self.bit_len += 1;
+        Ok(())
     }
 
     /// Removes a bit from the end of the sequence, without returning it.
     ///
     /// Panics if the sequence is empty.
     pub fn pop_bit(&mut self) {
+        if self.try_pop_bit().is_none() {
+            panic!("Codeword::pop_bit: codeword is empty");
+        }
+    }
+
+    /// Removes a bit from the end of the sequence and returns it, or returns `None` if the
+    /// sequence is empty.
+    pub fn try_pop_bit(&mut self) -> Option<bool> {
+        if self.bit_len == 0 {
+            return None;
+        }
         let index = self.bit_len - 1;
+        let bit = self.get_bit(index);
         self.bits[index / 64] &= !(1 << (index % 64));
         self.bit_len -= 1;
+        Some(bit)
+    }
+
+    /// Whether the stream starts with this codeword, given the next `available` bits of the
+    /// stream in `stream_bits`, in the same order as in codewords (the next bit at `1 << 0`).
+    /// Bits of `stream_bits` past `available` are ignored.
+    ///
+    /// Codewords longer than the available bits don't match, so neither do ones longer than 64
+    /// bits.
+    ///
+    /// ```
+    /// # use huff::tree::Codeword;
+    /// let cw: Codeword = "110".parse().unwrap();
+    /// assert!(cw.matches_prefix_of(0b1011, 4));
+    /// assert!(!cw.matches_prefix_of(0b1011, 2));
+    /// assert!(!cw.matches_prefix_of(0b1111, 4));
+    /// ```
+    pub fn matches_prefix_of(&self, stream_bits: u64, available: usize) -> bool {
+        if self.bit_len > available.min(64) {
+            return false;
+        }
+        // Bits past `bit_len` are 0 in the codeword, so only the stream's need masking.
+        let mask = u64::MAX.checked_shr(64 - self.bit_len as u32).unwrap_or(0);
+        stream_bits & mask == self.bits[0]
+    }
+
+    /// The codeword as a `bit_len`-bit number read MSB-first, i.e. with its first bit as the most
+    /// significant one, as used to index decode tables of canonical codes (e.g. in DEFLATE).
+    /// `None` for codewords longer than 64 bits.
+    ///
+    /// ```
+    /// # use huff::tree::Codeword;
+    /// let cw: Codeword = "110".parse().unwrap();
+    /// assert_eq!(cw.bits[0], 0b011);
+    /// assert_eq!(cw.mirrored_u64(), Some(0b110));
+    /// ```
+    pub fn mirrored_u64(&self) -> Option<u64> {
+        match self.bit_len {
+            0 => Some(0),
+            1..=64 => Some(self.bits[0].reverse_bits() >> (64 - self.bit_len)),
+            _ => None,
+        }
+    }
+
+    /// The number of positions at which the codewords differ, or `None` if their lengths differ.
+    pub fn hamming_distance(&self, other: &Codeword) -> Option<usize> {
+        if self.bit_len != other.bit_len {
+            return None;
+        }
+        // Bits past `bit_len` are 0 in both, so they don't contribute.
+        Some(
+            self.bits
+                .iter()
+                .zip(other.bits.iter())
+                .map(|(a, b)| (a ^ b).count_ones() as usize)
+                .sum(),
+        )
+    }
+}
+
+/// Returned when adding a bit to a codeword which already has `MAX_CODEWORD_BITS` bits.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct CodewordFull;
+
+impl std::fmt::Display for CodewordFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "codeword longer than {} bits", MAX_CODEWORD_BITS)
+    }
+}
+
+impl std::error::Error for CodewordFull {}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ParseCodewordError {
+    /// A character other than `0` or `1`.
+    InvalidBit(char),
+    TooLong(CodewordFull),
+}
+
+impl std::fmt::Display for ParseCodewordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseCodewordError::InvalidBit(c) => write!(f, "invalid bit {:?}", c),
+            ParseCodewordError::TooLong(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParseCodewordError {}
+
+/// Codeword is parsed from a sequence of `0` and `1` characters, the same as it is formatted.
+///
+/// ```
+/// # use huff::tree::*;
+/// assert_eq!("0101".parse(), Ok(Codeword::from_bits(&vec![B0, B1, B0, B1])));
+/// ```
+impl std::str::FromStr for Codeword {
+    type Err = ParseCodewordError;
+
+    fn from_str(s: &str) -> Result<Self, ParseCodewordError> {
+        let mut cw = Codeword::empty();
+        for c in s.chars() {
+            let bit = match c {
+                '0' => B0,
+                '1' => B1,
+                _ => return Err(ParseCodewordError::InvalidBit(c)),
+            };
+            cw.try_push_bit(bit).map_err(ParseCodewordError::TooLong)?;
+        }
+        Ok(cw)
     }
 }
 
@@ -173,51 +963,714 @@ impl std::fmt::Display for Codeword {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let bit_values = ["0", "1"];
         for i in 0..self.bit_len {
-            f.write_str(bit_values[((self.bits[i / 64] >> (i % 64)) & 1) as usize])?;
+            f.write_str(bit_values[self.get_bit(i) as usize])?;
         }
         Ok(())
     }
 }
 
+/// How to assign a codeword to the symbol of a tree consisting of a single leaf, where the path
+/// from the root is empty.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SingleLeaf {
+    /// The lone symbol gets the codeword `0`.
+    ///
+    /// This is the default: an empty codeword in a `Code` means that the symbol doesn't occur,
+    /// and a decoder which knows only the code could not tell which symbol to produce.
+    /// It also keeps every symbol taking up at least one bit in the stream.
+    OneBit,
+    /// The lone symbol gets an empty codeword, and takes up no space in the stream. The decoder
+    /// has to know the symbol and the number of symbols from elsewhere (see
+    /// `Decoder::single_symbol`).
+    ZeroLength,
+}
+
+/// Build the code corresponding to a tree, using `SingleLeaf::OneBit` for single-leaf trees.
 pub fn tree_to_code(tree: &Tree) -> Code {
-    let mut code = Code::empty();
-    explore_tree(&mut code, &mut Codeword::empty(), tree);
-    code
+    tree_to_code_with(tree, SingleLeaf::OneBit)
 }
 
-fn explore_tree(code: &mut Code, prefix: &mut Codeword, tree: &Tree) {
-    match tree {
-        Tree::Branch(left, right) => {
-            prefix.push_bit(false);
-            explore_tree(code, prefix, left);
-            prefix.pop_bit();
-            prefix.push_bit(true);
-            explore_tree(code, prefix, right);
-            prefix.pop_bit();
-        }
-        Tree::Leaf(symbol) => {
-            code[*symbol] = if prefix.is_empty() {
-                let mut cw = Codeword::empty();
-                cw.push_bit(false);
-                cw
-            } else {
-                prefix.clone()
-            };
-        }
-    }
+pub fn tree_to_code_with(tree: &Tree, single_leaf: SingleLeaf) -> Code {
+    code_from_leaves(tree.iter_leaves_with_codewords(), single_leaf)
 }
 
-#[test]
-fn test_tree_to_code() {
-    let code = tree_to_code(&Tree::Branch(
-        Box::new(Tree::Leaf(b'A')),
-        Box::new(Tree::Branch(
-            Box::new(Tree::Branch(
-                Box::new(Tree::Leaf(b'C')),
-                Box::new(Tree::Leaf(b'E')),
-            )),
-            Box::new(Tree::Leaf(b'B')),
-        )),
+/// Codes built from frequencies, so that each distinct distribution's code is built only once,
+/// e.g. when compressing many files that look alike.
+///
+/// Frequencies are told apart by their symbols with non-zero counts, like `build_tree` sees
+/// them: the order of the pairs and zero counts don't matter. Codes are kept until `clear`, at
+/// about 10 KiB each.
+#[derive(Default)]
+pub struct CodeCache {
+    codes: HashMap<Frequencies, Code>,
+    builds: u64,
+}
+
+impl CodeCache {
+    pub fn new() -> Self {
+        CodeCache::default()
+    }
+
+    /// The code `tree_to_code(&build_tree(frequencies))` gives, built only if it isn't cached
+    /// yet. Panics if no symbol has a non-zero count.
+    pub fn get_or_build(&mut self, frequencies: &Frequencies) -> &Code {
+        let mut key: Frequencies = nonzero_frequencies(frequencies).collect();
+        key.sort_unstable();
+        let builds = &mut self.builds;
+        self.codes.entry(key).or_insert_with_key(|key| {
+            *builds += 1;
+            build_tree_arena(key).to_code_with(SingleLeaf::OneBit)
+        })
+    }
+
+    /// Number of codes built so far, i.e. of calls to `get_or_build` that missed the cache.
+    pub fn builds(&self) -> u64 {
+        self.builds
+    }
+
+    /// Number of cached codes.
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.codes.clear();
+    }
+}
+
+#[test]
+fn test_code_cache() {
+    let mut cache = CodeCache::new();
+    assert!(cache.is_empty());
+    let frequencies = vec![(b'A', 6), (b'B', 4), (b'C', 2)];
+    let code = cache.get_or_build(&frequencies).to_string();
+    assert_eq!(code, tree_to_code(&build_tree(&frequencies)).to_string());
+    assert_eq!(cache.builds(), 1);
+
+    // The same counts, in another order and with a zero count, hit the cache.
+    let reordered = vec![(b'C', 2), (b'D', 0), (b'A', 6), (b'B', 4)];
+    assert_eq!(cache.get_or_build(&reordered).to_string(), code);
+    assert_eq!(cache.builds(), 1);
+
+    let other = vec![(b'A', 1), (b'B', 4), (b'C', 2)];
+    assert_eq!(
+        cache.get_or_build(&other).to_string(),
+        tree_to_code(&build_tree(&other)).to_string()
+    );
+    assert_eq!((cache.builds(), cache.len()), (2, 2));
+
+    cache.clear();
+    cache.get_or_build(&frequencies);
+    assert_eq!((cache.builds(), cache.len()), (3, 1));
+}
+
+fn code_from_leaves<I>(leaves: I, single_leaf: SingleLeaf) -> Code
+where
+    I: IntoIterator<Item = (u8, Codeword)>,
+{
+    let mut code = Code::empty();
+    for (symbol, cw) in leaves {
+        code[symbol] = if cw.is_empty() && single_leaf == SingleLeaf::OneBit {
+            Codeword::from_bits(&[B0])
+        } else {
+            cw
+        };
+    }
+    code
+}
+
+/// The symbols whose codewords differ between `a` and `b`, in order of symbol, with the codeword
+/// in each, or `None` if the symbol has none there.
+pub fn diff_codes(a: &Code, b: &Code) -> Vec<(u8, Option<Codeword>, Option<Codeword>)> {
+    let present = |cw: &Codeword| Some(cw.clone()).filter(|cw| !cw.is_empty());
+    (0..NUM_SYMBOLS)
+        .map(|sym| sym as u8)
+        .filter(|&sym| a[sym] != b[sym])
+        .map(|sym| (sym, present(&a[sym]), present(&b[sym])))
+        .collect()
+}
+
+/// Render the tree in Graphviz DOT, e.g. to view it with `dot -Tsvg`.
+///
+/// Nodes are numbered in pre-order, from `n0` at the root. Leaves are labeled with their symbol,
+/// as a character if it's printable ASCII and in hex otherwise. Edges are labeled with the bit
+/// they stand for.
+pub fn to_dot(tree: &Tree) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::from("digraph huffman {\n");
+    let mut next_id = 0;
+    // Nodes to visit, with the id of their parent and the bit leading to them.
+    let mut stack = vec![(tree, None)];
+    while let Some((node, parent)) = stack.pop() {
+        let id = next_id;
+        next_id += 1;
+        match node {
+            Tree::Branch(left, right) => {
+                writeln!(out, "  n{} [label=\"\", shape=circle];", id).unwrap();
+                stack.push((right, Some((id, 1))));
+                stack.push((left, Some((id, 0))));
+            }
+            Tree::Leaf(sym) => {
+                let label = match *sym {
+                    b'"' | b'\\' => format!("\\{}", *sym as char),
+                    b' '..=b'~' => (*sym as char).to_string(),
+                    _ => format!("0x{:02x}", sym),
+                };
+                writeln!(out, "  n{} [label=\"{}\", shape=box];", id, label).unwrap();
+            }
+        }
+        if let Some((parent_id, bit)) = parent {
+            writeln!(out, "  n{} -> n{} [label=\"{}\"];", parent_id, id, bit).unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// A table of the symbols in `frequencies`, most frequent first: each one's count, its share of
+/// the input, its codeword in `code`, and the number of bits it contributes to the output. Ends
+/// with a line of totals.
+///
+/// Symbols are shown as characters if they're printable ASCII and in hex otherwise.
+pub fn format_code_report(code: &Code, frequencies: &Frequencies) -> String {
+    use std::fmt::Write;
+
+    let mut rows: Vec<(u8, u64)> = nonzero_frequencies(frequencies).collect();
+    rows.sort_by_key(|&(sym, freq)| (std::cmp::Reverse(freq), sym));
+    let total: u64 = rows.iter().map(|&(_, freq)| freq).sum();
+    let codeword_width = rows
+        .iter()
+        .map(|&(sym, _)| code[sym].bit_len)
+        .max()
+        .unwrap_or(0)
+        .max("codeword".len());
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "symbol {:>12} {:>7}  {:<width$} {:>14}",
+        "count",
+        "%",
+        "codeword",
+        "bits",
+        width = codeword_width
+    )
+    .unwrap();
+    let mut total_bits = 0;
+    for (sym, freq) in rows {
+        let label = match sym {
+            b' '..=b'~' => (sym as char).to_string(),
+            _ => format!("0x{:02x}", sym),
+        };
+        let bits = freq.saturating_mul(code[sym].bit_len as u64);
+        total_bits += bits;
+        writeln!(
+            out,
+            "{:<6} {:>12} {:>7.2}  {:<width$} {:>14}",
+            label,
+            freq,
+            100.0 * freq as f64 / total as f64,
+            code[sym].to_string(),
+            bits,
+            width = codeword_width
+        )
+        .unwrap();
+    }
+    if total > 0 {
+        writeln!(
+            out,
+            "{:<6} {:>12} {:>7.2}  {:<width$} {:>14}",
+            "total",
+            total,
+            100.0,
+            "",
+            total_bits,
+            width = codeword_width
+        )
+        .unwrap();
+    }
+    out
+}
+
+impl Tree {
+    /// Iterate over the leaves from left to right, together with their codewords (the path from
+    /// the root, `0` for left and `1` for right).
+    ///
+    /// Unlike `tree_to_code`, this doesn't allocate a full `Code`. The leaf of a single-leaf tree
+    /// gets an empty codeword.
+    ///
+    /// ```
+    /// # use huff::tree::*;
+    /// let tree = build_tree(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+    /// let leaves: Vec<_> = tree
+    ///     .iter_leaves_with_codewords()
+    ///     .map(|(sym, cw)| format!("{}: {}", sym as char, cw))
+    ///     .collect();
+    /// assert_eq!(leaves, vec!["A: 0", "C: 10", "B: 11"]);
+    /// ```
+    pub fn iter_leaves_with_codewords(&self) -> LeavesWithCodewords<'_> {
+        LeavesWithCodewords {
+            stack: vec![(self, Codeword::empty())],
+        }
+    }
+
+    /// Follow the bits of `cw` from the root, `0` to the left and `1` to the right, and report
+    /// where they lead. This is the step a decoder takes for each bit.
+    ///
+    /// ```
+    /// # use huff::tree::*;
+    /// let tree = build_tree(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+    /// assert_eq!(tree.follow(&"11".parse().unwrap()), FollowResult::Leaf(b'B'));
+    /// assert_eq!(tree.follow(&"1".parse().unwrap()), FollowResult::Internal);
+    /// assert_eq!(tree.follow(&"01".parse().unwrap()), FollowResult::Invalid);
+    /// ```
+    pub fn follow(&self, cw: &Codeword) -> FollowResult {
+        let mut tree = self;
+        for i in 0..cw.bit_len {
+            tree = match tree {
+                Tree::Branch(left, right) => {
+                    if cw.get_bit(i) {
+                        right
+                    } else {
+                        left
+                    }
+                }
+                Tree::Leaf(_) => return FollowResult::Invalid,
+            };
+        }
+        match tree {
+            Tree::Branch(..) => FollowResult::Internal,
+            Tree::Leaf(sym) => FollowResult::Leaf(*sym),
+        }
+    }
+}
+
+/// Where a sequence of bits leads in a tree, see `Tree::follow`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum FollowResult {
+    /// The bits are exactly the codeword of the symbol.
+    Leaf(u8),
+    /// The bits are a proper prefix of some codewords, so more bits are needed.
+    Internal,
+    /// The bits continue past a leaf: no codeword starts with them.
+    Invalid,
+}
+
+/// Iterator returned by `Tree::iter_leaves_with_codewords`.
+pub struct LeavesWithCodewords<'a> {
+    /// Subtrees still to visit, with the paths leading to them. The next one is on top.
+    stack: Vec<(&'a Tree, Codeword)>,
+}
+
+impl<'a> Iterator for LeavesWithCodewords<'a> {
+    type Item = (u8, Codeword);
+
+    fn next(&mut self) -> Option<(u8, Codeword)> {
+        loop {
+            match self.stack.pop()? {
+                (Tree::Branch(left, right), prefix) => {
+                    let mut right_prefix = prefix.clone();
+                    right_prefix.push_bit(B1);
+                    self.stack.push((right, right_prefix));
+                    let mut left_prefix = prefix;
+                    left_prefix.push_bit(B0);
+                    self.stack.push((left, left_prefix));
+                }
+                (Tree::Leaf(symbol), prefix) => return Some((*symbol, prefix)),
+            }
+        }
+    }
+}
+
+/// Index of a node in a `TreeArena`.
+pub type NodeId = usize;
+
+/// A node of a `TreeArena`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Node {
+    Branch(NodeId, NodeId),
+    Leaf(Symbol),
+}
+
+/// A code tree with all nodes in one vector, referring to each other by index, rather than
+/// boxed separately like in `Tree`.
+///
+/// Children always come before their parent, and every node but the root has exactly one
+/// parent, so the root is the last node.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TreeArena {
+    nodes: Vec<Node>,
+    root: NodeId,
+}
+
+impl TreeArena {
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn node(&self, id: NodeId) -> Node {
+        self.nodes[id]
+    }
+
+    /// Number of nodes, leaves and branches.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn push(&mut self, node: Node) -> NodeId {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// The nodes of the tree, numbered in post-order.
+    pub fn from_tree(tree: &Tree) -> Self {
+        let mut arena = TreeArena {
+            nodes: vec![],
+            root: 0,
+        };
+        // Subtrees to visit, and whether their children have been added already, in which case
+        // their ids are on top of `ids`, the right one last.
+        let mut stack = vec![(tree, false)];
+        let mut ids = vec![];
+        while let Some((tree, children_added)) = stack.pop() {
+            match tree {
+                Tree::Branch(..) if children_added => {
+                    let right = ids.pop().unwrap();
+                    let left = ids.pop().unwrap();
+                    ids.push(arena.push(Node::Branch(left, right)));
+                }
+                Tree::Branch(left, right) => {
+                    stack.push((tree, true));
+                    stack.push((right, false));
+                    stack.push((left, false));
+                }
+                Tree::Leaf(sym) => ids.push(arena.push(Node::Leaf(*sym))),
+            }
+        }
+        arena.root = ids.pop().unwrap();
+        arena
+    }
+
+    pub fn to_tree(&self) -> Tree {
+        // Children come first, so each node can take its subtrees when it's reached.
+        let mut built: Vec<Option<Tree>> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let tree = match *node {
+                Node::Branch(left, right) => Tree::Branch(
+                    Box::new(built[left].take().unwrap()),
+                    Box::new(built[right].take().unwrap()),
+                ),
+                Node::Leaf(sym) => Tree::Leaf(sym),
+            };
+            built.push(Some(tree));
+        }
+        built[self.root].take().unwrap()
+    }
+
+    /// Like `Tree::iter_leaves_with_codewords`, in the same order.
+    pub fn leaves_with_codewords(&self) -> Vec<(u8, Codeword)> {
+        let mut leaves = vec![];
+        let mut stack = vec![(self.root, Codeword::empty())];
+        while let Some((id, prefix)) = stack.pop() {
+            match self.nodes[id] {
+                Node::Branch(left, right) => {
+                    let mut right_prefix = prefix.clone();
+                    right_prefix.push_bit(B1);
+                    stack.push((right, right_prefix));
+                    let mut left_prefix = prefix;
+                    left_prefix.push_bit(B0);
+                    stack.push((left, left_prefix));
+                }
+                Node::Leaf(symbol) => leaves.push((symbol, prefix)),
+            }
+        }
+        leaves
+    }
+
+    /// Like `tree_to_code_with`.
+    pub fn to_code_with(&self, single_leaf: SingleLeaf) -> Code {
+        code_from_leaves(self.leaves_with_codewords(), single_leaf)
+    }
+}
+
+/// Codeword lengths of a code, as (symbol, length) pairs ordered by symbol.
+pub type Lengths = Vec<(u8, usize)>;
+
+/// Maximum codeword length used by DEFLATE, a common choice for `length_limited_lengths`.
+pub const DEFLATE_MAX_CODE_LENGTH: usize = 15;
+
+/// Lengths of the non-empty codewords in the code.
+pub fn code_lengths(code: &Code) -> Lengths {
+    code.into_iter()
+        .map(|(sym, cw)| (sym, cw.bit_len))
+        .collect()
+}
+
+/// Depths of the leaves of the tree, sorted: its shape, without which symbol is where. Trees
+/// giving the same codeword lengths compare equal, e.g. for checking tree-building algorithms
+/// that may place symbols of equal frequency differently. A lone leaf has depth 0.
+pub fn depth_multiset(tree: &Tree) -> Vec<usize> {
+    let mut depths = vec![];
+    let mut stack = vec![(tree, 0)];
+    while let Some((tree, depth)) = stack.pop() {
+        match tree {
+            Tree::Branch(left, right) => {
+                stack.push((left, depth + 1));
+                stack.push((right, depth + 1));
+            }
+            Tree::Leaf(_) => depths.push(depth),
+        }
+    }
+    depths.sort_unstable();
+    depths
+}
+
+/// Variance of the codeword lengths in the tree, weighting each symbol by its frequency. Trees
+/// with the same average length can differ in it; a low variance means more uniform codeword
+/// lengths, which suits decoders that work in fixed steps.
+///
+/// Symbols missing from `frequencies` have a weight of zero. Returns 0 if all weights are zero.
+pub fn code_length_variance(tree: &Tree, frequencies: &Frequencies) -> f64 {
+    let mut weights = [0u64; NUM_SYMBOLS];
+    for &(sym, freq) in frequencies {
+        weights[sym as usize] = freq;
+    }
+    let leaves: Vec<(f64, f64)> = tree
+        .iter_leaves_with_codewords()
+        .map(|(sym, cw)| (weights[sym as usize] as f64, cw.bit_len as f64))
+        .collect();
+    let total: f64 = leaves.iter().map(|&(weight, _)| weight).sum();
+    if total == 0.0 {
+        return 0.0;
+    }
+    let mean = leaves
+        .iter()
+        .map(|&(weight, len)| weight * len)
+        .sum::<f64>()
+        / total;
+    leaves
+        .iter()
+        .map(|&(weight, len)| weight * (len - mean) * (len - mean))
+        .sum::<f64>()
+        / total
+}
+
+/// Compute optimal codeword lengths for the frequencies, subject to the constraint that no
+/// codeword is longer than `max_len` bits, using the package-merge algorithm.
+///
+/// A lone symbol gets length 1, like in `tree_to_code`. Symbols with a count of zero are left
+/// out, like in `build_tree`.
+///
+/// Panics if `max_len` bits are not enough to give every symbol a distinct codeword.
+pub fn length_limited_lengths(frequencies: &Frequencies, max_len: usize) -> Lengths {
+    let frequencies: &Frequencies = &nonzero_frequencies(frequencies).collect();
+    let n = frequencies.len();
+    assert!(
+        max_len >= 1 && (max_len >= usize::BITS as usize || n <= 1 << max_len),
+        "{} symbols don't fit in codewords of at most {} bits",
+        n,
+        max_len
+    );
+    if n <= 1 {
+        return frequencies.iter().map(|&(sym, _)| (sym, 1)).collect();
+    }
+
+    // Each item is a weight, and the number of times each symbol occurs in it.
+    let mut leaves: Vec<(u64, Vec<u8>)> = frequencies
+        .iter()
+        .enumerate()
+        .map(|(i, &(_, freq))| {
+            let mut counts = vec![0; n];
+            counts[i] = 1;
+            (freq, counts)
+        })
+        .collect();
+    leaves.sort_by_key(|(weight, _)| *weight);
+
+    let mut items = leaves.clone();
+    for _ in 1..max_len {
+        let packages = items.chunks_exact(2).map(|pair| {
+            let counts = pair[0].1.iter().zip(&pair[1].1).map(|(a, b)| a + b);
+            (pair[0].0.saturating_add(pair[1].0), counts.collect())
+        });
+        items = merge_by_weight(leaves.clone(), packages.collect());
+    }
+
+    // Each of the 2n - 2 cheapest items adds one bit to the codeword of each symbol in it.
+    let mut lengths: Lengths = frequencies.iter().map(|&(sym, _)| (sym, 0)).collect();
+    for (_, counts) in &items[..2 * n - 2] {
+        for (length, &count) in lengths.iter_mut().zip(counts) {
+            length.1 += count as usize;
+        }
+    }
+    lengths
+}
+
+/// Merge two lists sorted by weight, preferring items from `a` on ties.
+fn merge_by_weight<T>(a: Vec<(u64, T)>, b: Vec<(u64, T)>) -> Vec<(u64, T)> {
+    let mut result = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+    loop {
+        let take_a = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => x.0 <= y.0,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return result,
+        };
+        result.extend(if take_a { a.next() } else { b.next() });
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CanonicalError {
+    /// No symbol has a non-zero length.
+    Empty,
+    /// The lengths violate the Kraft inequality: there are not enough codewords of these lengths
+    /// to go around.
+    Oversubscribed,
+    /// A length is greater than `MAX_CODEWORD_BITS`.
+    TooLong(u8),
+    /// The symbol appears more than once.
+    DuplicateSymbol(u8),
+}
+
+impl std::fmt::Display for CanonicalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CanonicalError::Empty => f.write_str("no symbols in length table"),
+            CanonicalError::Oversubscribed => {
+                f.write_str("codeword lengths violate the Kraft inequality")
+            }
+            CanonicalError::TooLong(sym) => write!(
+                f,
+                "codeword length of symbol {} exceeds {} bits",
+                sym, MAX_CODEWORD_BITS
+            ),
+            CanonicalError::DuplicateSymbol(sym) => {
+                write!(f, "duplicate symbol {} in length table", sym)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CanonicalError {}
+
+/// Build the canonical code with the given codeword lengths.
+///
+/// Symbols are assigned consecutive codewords in order of (length, symbol), so that the code
+/// is fully determined by the lengths. This is what lets a header store only the lengths.
+/// Symbols with length 0 are left out of the code.
+///
+/// The lengths may come from untrusted input, so they're validated first.
+pub fn canonical_code(lengths: &Lengths) -> Result<Code, CanonicalError> {
+    let mut sorted: Lengths = lengths
+        .iter()
+        .filter(|&&(_, len)| len > 0)
+        .cloned()
+        .collect();
+    validate_lengths(&sorted)?;
+    sorted.sort_by_key(|&(sym, len)| (len, sym));
+
+    let mut code = Code::empty();
+    let mut cw = Codeword::empty();
+    for (i, &(sym, len)) in sorted.iter().enumerate() {
+        if i > 0 {
+            increment(&mut cw);
+        }
+        while cw.bit_len < len {
+            cw.push_bit(B0);
+        }
+        code[sym] = cw.clone();
+    }
+    Ok(code)
+}
+
+/// `canonical_code` for lengths known to be valid, e.g. to set up a specific code in a test
+/// without building a tree. Panics if they're not.
+///
+/// ```
+/// use huff::tree::code_from_lengths;
+///
+/// let code = code_from_lengths(&[(b'a', 1), (b'b', 2), (b'c', 2)]);
+/// assert_eq!(code[b'a'].to_string(), "0");
+/// assert_eq!(code[b'b'].to_string(), "10");
+/// assert_eq!(code[b'c'].to_string(), "11");
+/// ```
+pub fn code_from_lengths(lengths: &[(u8, u8)]) -> Code {
+    let lengths: Lengths = lengths
+        .iter()
+        .map(|&(sym, len)| (sym, len as usize))
+        .collect();
+    canonical_code(&lengths).unwrap_or_else(|err| panic!("invalid code lengths: {}", err))
+}
+
+/// Check that the non-zero lengths describe a valid prefix code.
+fn validate_lengths(lengths: &Lengths) -> Result<(), CanonicalError> {
+    if lengths.is_empty() {
+        return Err(CanonicalError::Empty);
+    }
+    let mut seen = [false; NUM_SYMBOLS];
+    let mut count_by_length = vec![0usize; MAX_CODEWORD_BITS + 1];
+    for &(sym, len) in lengths {
+        if seen[sym as usize] {
+            return Err(CanonicalError::DuplicateSymbol(sym));
+        }
+        seen[sym as usize] = true;
+        if len > MAX_CODEWORD_BITS {
+            return Err(CanonicalError::TooLong(sym));
+        }
+        count_by_length[len] += 1;
+    }
+    // Number of codewords of the current length not yet taken, going down the levels of a
+    // complete binary tree. It can't grow beyond what's needed by the at most 256 symbols, so
+    // cap it to avoid overflow.
+    let mut available: usize = 1;
+    for &count in &count_by_length[1..] {
+        available = (available * 2).min(2 * NUM_SYMBOLS);
+        if count > available {
+            return Err(CanonicalError::Oversubscribed);
+        }
+        available -= count;
+    }
+    Ok(())
+}
+
+/// Increment the codeword, treating it as a binary number with the last bit least significant.
+fn increment(cw: &mut Codeword) {
+    let mut trailing_ones = 0;
+    while cw.bit_len > 0 && cw.get_bit(cw.bit_len - 1) {
+        cw.pop_bit();
+        trailing_ones += 1;
+    }
+    // Overflowing past all ones would mean the lengths violate the Kraft inequality.
+    cw.pop_bit();
+    cw.push_bit(B1);
+    for _ in 0..trailing_ones {
+        cw.push_bit(B0);
+    }
+}
+
+#[test]
+fn test_tree_to_code() {
+    let code = tree_to_code(&Tree::Branch(
+        Box::new(Tree::Leaf(b'A')),
+        Box::new(Tree::Branch(
+            Box::new(Tree::Branch(
+                Box::new(Tree::Leaf(b'C')),
+                Box::new(Tree::Leaf(b'E')),
+            )),
+            Box::new(Tree::Leaf(b'B')),
+        )),
     ));
     assert_eq!(format!("{}", code[b'A']), "0");
     assert_eq!(format!("{}", code[b'B']), "11");
@@ -225,8 +1678,690 @@ fn test_tree_to_code() {
     assert_eq!(format!("{}", code[b'E']), "101");
 }
 
+#[test]
+fn test_iter_leaves_with_codewords() {
+    let tree = build_tree(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+    assert_eq!(
+        tree.iter_leaves_with_codewords().collect::<Vec<_>>(),
+        vec![
+            (b'A', "0".parse().unwrap()),
+            (b'C', "10".parse().unwrap()),
+            (b'B', "11".parse().unwrap()),
+        ]
+    );
+    assert_eq!(
+        Tree::Leaf(b'A')
+            .iter_leaves_with_codewords()
+            .collect::<Vec<_>>(),
+        vec![(b'A', Codeword::empty())]
+    );
+}
+
 #[test]
 fn test_tree_to_code_one_symbol() {
     let code = tree_to_code(&Tree::Leaf(b'A'));
     assert_eq!(format!("{}", code[b'A']), "0");
 }
+
+#[test]
+fn test_single_symbol_frequencies() {
+    let frequencies = compute_frequencies(&[b'x'; 10000]);
+    assert_eq!(frequencies, vec![(b'x', 10000)]);
+    let tree = build_tree(&frequencies);
+    assert_eq!(tree, Tree::Leaf(b'x'));
+    assert_eq!(format!("{}", tree_to_code(&tree)), "x: 0\n");
+}
+
+#[test]
+fn test_tree_to_code_one_symbol_zero_length() {
+    let code = tree_to_code_with(&Tree::Leaf(b'A'), SingleLeaf::ZeroLength);
+    assert!(code[b'A'].is_empty());
+    assert_eq!(format!("{}", code), "");
+}
+
+#[test]
+fn test_huffman_from_probabilities() {
+    let code = tree_to_code(
+        &huffman_from_probabilities(&[(b'A', 0.5), (b'B', 0.25), (b'C', 0.25)]).unwrap(),
+    );
+    assert_eq!(
+        vec![code[b'A'].bit_len, code[b'B'].bit_len, code[b'C'].bit_len],
+        vec![1, 2, 2]
+    );
+}
+
+#[test]
+fn test_huffman_from_probabilities_invalid() {
+    assert_eq!(
+        huffman_from_probabilities(&[(b'A', 0.5), (b'B', -0.25)]),
+        Err(ProbabilityError::Negative(b'B'))
+    );
+    assert_eq!(
+        huffman_from_probabilities(&[(b'A', f64::NAN)]),
+        Err(ProbabilityError::NotFinite(b'A'))
+    );
+    assert_eq!(
+        huffman_from_probabilities(&[(b'A', 0.0)]),
+        Err(ProbabilityError::Empty)
+    );
+}
+
+#[test]
+fn test_codeword_push_bit_boundary() {
+    let mut cw = Codeword::from_bits(&[B1; MAX_CODEWORD_BITS - 1]);
+    assert_eq!(cw.try_push_bit(B1), Ok(()));
+    assert_eq!(cw.bit_len, MAX_CODEWORD_BITS);
+    assert_eq!(cw.try_push_bit(B1), Err(CodewordFull));
+    assert_eq!(cw, Codeword::from_bits(&[B1; MAX_CODEWORD_BITS]));
+}
+
+#[test]
+#[should_panic(expected = "maximum of 256 bits")]
+fn test_codeword_push_bit_full_panics() {
+    Codeword::from_bits(&[B1; MAX_CODEWORD_BITS]).push_bit(B0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "bits past the length")]
+fn test_codeword_from_raw_checks_padding() {
+    Codeword::from_raw(65, [0, 0b10, 0, 0]);
+}
+
+#[test]
+fn test_codeword_pop_bit_empty() {
+    let mut cw = Codeword::from_bits(&[B1]);
+    assert_eq!(cw.try_pop_bit(), Some(B1));
+    assert_eq!(cw.try_pop_bit(), None);
+    assert_eq!(cw, Codeword::empty());
+}
+
+#[test]
+#[should_panic(expected = "codeword is empty")]
+fn test_codeword_pop_bit_empty_panics() {
+    Codeword::empty().pop_bit();
+}
+
+#[test]
+fn test_codeword_from_str() {
+    assert_eq!("".parse(), Ok(Codeword::empty()));
+    assert_eq!(
+        "012".parse::<Codeword>(),
+        Err(ParseCodewordError::InvalidBit('2'))
+    );
+    assert!("1".repeat(MAX_CODEWORD_BITS).parse::<Codeword>().is_ok());
+    assert_eq!(
+        "1".repeat(MAX_CODEWORD_BITS + 1).parse::<Codeword>(),
+        Err(ParseCodewordError::TooLong(CodewordFull))
+    );
+}
+
+#[test]
+fn test_canonical_code() {
+    let code = canonical_code(&vec![(b'A', 2), (b'B', 1), (b'C', 3), (b'D', 3)]).unwrap();
+    assert_eq!(format!("{}", code), "A: 10\nB: 0\nC: 110\nD: 111\n");
+}
+
+#[test]
+fn test_canonical_code_preserves_lengths() {
+    let frequencies = compute_frequencies(b"appends_a_given_slice");
+    let lengths = code_lengths(&tree_to_code(&build_tree(&frequencies)));
+    assert_eq!(code_lengths(&canonical_code(&lengths).unwrap()), lengths);
+}
+
+#[test]
+fn test_canonical_code_invalid_lengths() {
+    assert_eq!(canonical_code(&vec![]).err(), Some(CanonicalError::Empty));
+    assert_eq!(
+        canonical_code(&vec![(b'A', 0), (b'B', 0)]).err(),
+        Some(CanonicalError::Empty)
+    );
+    assert_eq!(
+        canonical_code(&vec![(b'A', 1), (b'B', 1), (b'C', 1)]).err(),
+        Some(CanonicalError::Oversubscribed)
+    );
+    assert_eq!(
+        canonical_code(&vec![(b'A', 1), (b'B', 2), (b'C', 3), (b'D', 3), (b'E', 3)]).err(),
+        Some(CanonicalError::Oversubscribed)
+    );
+    assert_eq!(
+        canonical_code(&vec![(b'A', 1), (b'B', 257)]).err(),
+        Some(CanonicalError::TooLong(b'B'))
+    );
+    assert_eq!(
+        canonical_code(&vec![(b'A', 1), (b'A', 2)]).err(),
+        Some(CanonicalError::DuplicateSymbol(b'A'))
+    );
+    // 256 one-bit-per-level codewords, like in a degenerate tree, are fine: the Kraft sum is
+    // exactly 1.
+    let mut lengths: Lengths = (0..255).map(|sym| (sym as u8, sym + 1)).collect();
+    lengths.push((255, 255));
+    assert!(canonical_code(&lengths).is_ok());
+    // Incomplete codes are valid too.
+    assert!(canonical_code(&vec![(b'A', 2), (b'B', 2), (b'C', 2)]).is_ok());
+}
+
+#[cfg(test)]
+fn kraft_sum(lengths: &Lengths) -> f64 {
+    lengths
+        .iter()
+        .map(|&(_, len)| 0.5f64.powi(len as i32))
+        .sum()
+}
+
+#[cfg(test)]
+fn cost(frequencies: &Frequencies, lengths: &Lengths) -> u64 {
+    frequencies
+        .iter()
+        .zip(lengths)
+        .map(|(&(_, freq), &(_, len))| freq * len as u64)
+        .sum()
+}
+
+#[test]
+fn test_length_limited_lengths() {
+    // Unconstrained, the lengths would be 1, 2, ..., 9, 9.
+    let frequencies: Frequencies = (0..10).map(|i| (i as u8, 1 << (10 - i))).collect();
+    let lengths = length_limited_lengths(&frequencies, 4);
+    assert!(lengths.iter().all(|&(_, len)| len <= 4));
+    assert_eq!(kraft_sum(&lengths), 1.0);
+
+    // With a loose enough limit, the result is as good as plain Huffman.
+    let frequencies = compute_frequencies(b"appends_a_given_slice");
+    let huffman = code_lengths(&tree_to_code(&build_tree(&frequencies)));
+    assert_eq!(
+        cost(&frequencies, &length_limited_lengths(&frequencies, 15)),
+        cost(&frequencies, &huffman)
+    );
+
+    assert_eq!(
+        length_limited_lengths(&vec![(b'x', 5)], 15),
+        vec![(b'x', 1)]
+    );
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_length_limited_lengths(input: Vec<u8>) -> bool {
+    let frequencies = compute_frequencies(&input);
+    let lengths = length_limited_lengths(&frequencies, 8);
+    lengths.iter().all(|&(_, len)| (1..=8).contains(&len)) && kraft_sum(&lengths) <= 1.0
+}
+
+#[test]
+fn test_hamming_distance() {
+    let cw = |s: &str| s.parse::<Codeword>().unwrap();
+    assert_eq!(cw("").hamming_distance(&cw("")), Some(0));
+    assert_eq!(cw("0110").hamming_distance(&cw("0110")), Some(0));
+    assert_eq!(cw("0110").hamming_distance(&cw("0111")), Some(1));
+    assert_eq!(cw("0110").hamming_distance(&cw("1001")), Some(4));
+    assert_eq!(cw("0110").hamming_distance(&cw("011")), None);
+    assert_eq!(cw("0").hamming_distance(&cw("")), None);
+
+    // Differences in different words of a long codeword.
+    let a = Codeword::from_bits(&[B0; 200]);
+    let mut bits = vec![B0; 200];
+    bits[3] = B1;
+    bits[100] = B1;
+    bits[199] = B1;
+    assert_eq!(a.hamming_distance(&Codeword::from_bits(&bits)), Some(3));
+}
+
+#[test]
+fn test_min_distance() {
+    // A: 10, B: 0, C: 110, D: 111
+    let code = canonical_code(&vec![(b'A', 2), (b'B', 1), (b'C', 3), (b'D', 3)]).unwrap();
+    assert_eq!(code.min_distance(), Some(1));
+    // No two codewords of the same length.
+    let code = canonical_code(&vec![(b'A', 1), (b'B', 2)]).unwrap();
+    assert_eq!(code.min_distance(), None);
+    assert_eq!(Code::empty().min_distance(), None);
+
+    let mut code = Code::empty();
+    code[b'A'] = "0011".parse().unwrap();
+    code[b'B'] = "0101".parse().unwrap();
+    code[b'C'] = "1000".parse().unwrap();
+    assert_eq!(code.min_distance(), Some(2));
+}
+
+#[test]
+fn test_is_complete() {
+    assert!(code_from_lengths(&[(b'a', 1), (b'b', 2), (b'c', 2)]).is_complete());
+    assert!(!code_from_lengths(&[(b'a', 1), (b'b', 2)]).is_complete());
+    assert!(!code_from_lengths(&[(b'a', 2), (b'b', 2), (b'c', 2)]).is_complete());
+    assert!(tree_to_code(&build_tree(&(0..=255).map(|sym| (sym, 1)).collect())).is_complete());
+    assert!(tree_to_code(&build_tree(&corpus::fibonacci_frequencies(90))).is_complete());
+    assert!(!tree_to_code_with(&Tree::Leaf(b'a'), SingleLeaf::OneBit).is_complete());
+    assert!(!Code::empty().is_complete());
+}
+
+#[test]
+fn test_build_tree_sampled() {
+    let input = corpus::text_like(200_000, 1);
+    let exact = build_tree(&compute_frequencies(&input));
+    for &sample_bytes in &[input.len() as u64, input.len() as u64 + 1, u64::MAX] {
+        let sampled = build_tree_sampled(&input[..], sample_bytes).unwrap();
+        assert!(sampled.unwrap() == exact);
+    }
+
+    let mut reader = &input[..];
+    let sampled = build_tree_sampled(&mut reader, 1000).unwrap().unwrap();
+    assert!(sampled == build_tree(&compute_frequencies(&input[..1000])));
+    assert_eq!(reader.len(), input.len() - 1000);
+
+    assert!(build_tree_sampled(&input[..], 0).unwrap().is_none());
+    assert!(build_tree_sampled(&b""[..], 1000).unwrap().is_none());
+}
+
+#[test]
+fn test_code_iter() {
+    // A: 0, B: 10, C: 11
+    let code = canonical_code(&vec![(b'C', 2), (b'A', 1), (b'B', 2)]).unwrap();
+    let mut entries = vec![];
+    for (sym, cw) in &code {
+        entries.push((sym, cw.to_string()));
+    }
+    assert_eq!(
+        entries,
+        vec![(b'A', "0".into()), (b'B', "10".into()), (b'C', "11".into())]
+    );
+    assert_eq!(Code::empty().into_iter().count(), 0);
+}
+
+#[test]
+fn test_compact_code() {
+    let code = build_tree_arena(&compute_frequencies(b"appends_a_given_slice"))
+        .to_code_with(SingleLeaf::OneBit);
+    let compact = CompactCode::new(&code);
+    assert_eq!(compact.to_code().to_string(), code.to_string());
+    for (sym, cw) in &code {
+        assert_eq!(compact.bit_len(sym), cw.bit_len);
+        for i in 0..cw.bit_len {
+            assert_eq!(compact.get_bit(sym, i), cw.get_bit(i));
+        }
+    }
+    assert_eq!(compact.bit_len(b'z'), 0);
+    assert_eq!(compact.words(b'z'), &[] as &[u64]);
+
+    // A code for all bytes, of about 8 bits each: a word per codeword, instead of four plus
+    // the length.
+    let code = tree_to_code(&build_tree(&(0..=255).map(|sym| (sym, 1)).collect()));
+    let code_bytes = NUM_SYMBOLS * std::mem::size_of::<Codeword>();
+    assert_eq!(code_bytes, 10 << 10);
+    let compact = CompactCode::new(&code);
+    assert_eq!(compact.size_bytes(), 3 << 10 | 24);
+    assert_eq!(compact.to_code().to_string(), code.to_string());
+    assert_eq!(compact.max_len(), 8);
+
+    // Codewords of many words.
+    let mut code = Code::empty();
+    code[0] = "0".parse().unwrap();
+    code[1] = Codeword::from_bits(&[B1; MAX_CODEWORD_BITS]);
+    code[2] = Codeword::from_bits(&[B1; 65]);
+    let compact = CompactCode::new(&code);
+    assert_eq!(compact.words(1), &[u64::MAX; 4]);
+    assert_eq!(compact.words(2), &[u64::MAX, 1]);
+    assert_eq!(compact.to_code().to_string(), code.to_string());
+    assert_eq!(compact.max_len(), MAX_CODEWORD_BITS);
+}
+
+#[test]
+fn test_diff_codes() {
+    // A: 0, B: 10, C: 11
+    let a = canonical_code(&vec![(b'A', 1), (b'B', 2), (b'C', 2)]).unwrap();
+    // A: 0, C: 10, D: 110, E: 111
+    let b = canonical_code(&vec![(b'A', 1), (b'C', 2), (b'D', 3), (b'E', 3)]).unwrap();
+    let cw = |s: &str| Some(s.parse::<Codeword>().unwrap());
+    assert_eq!(
+        diff_codes(&a, &b),
+        vec![
+            (b'B', cw("10"), None),
+            (b'C', cw("11"), cw("10")),
+            (b'D', None, cw("110")),
+            (b'E', None, cw("111")),
+        ]
+    );
+    assert_eq!(diff_codes(&b, &b), vec![]);
+    assert_eq!(diff_codes(&Code::empty(), &a).len(), 3);
+}
+
+#[test]
+fn test_deep_tree() {
+    // Far deeper than any code (whose codewords are limited to `MAX_CODEWORD_BITS`), and deep
+    // enough to overflow the stack when walked recursively.
+    fn deep_tree(depth: usize, last: u8) -> Tree {
+        let mut tree = Tree::Leaf(last);
+        for i in 0..depth {
+            tree = Tree::Branch(Box::new(Tree::Leaf(i as u8)), Box::new(tree));
+        }
+        tree
+    }
+    let depth = 200_000;
+    assert!(deep_tree(depth, 0) == deep_tree(depth, 0));
+    assert!(deep_tree(depth, 0) != deep_tree(depth, 1));
+    assert!(deep_tree(depth, 0) != deep_tree(depth - 1, 0));
+    let arena = TreeArena::from_tree(&deep_tree(depth, 0));
+    assert_eq!(arena.len(), 2 * depth + 1);
+    assert!(arena.to_tree() == deep_tree(depth, 0));
+
+    // The deepest tree a code can have still converts fine.
+    let tree = deep_tree(NUM_SYMBOLS - 1, 255);
+    let code = tree_to_code(&tree);
+    assert_eq!(code[255].bit_len, 255);
+    assert_eq!(code[254].bit_len, 1);
+}
+
+#[test]
+fn test_counts_past_32_bits() {
+    // Counts that would overflow a 32-bit `usize`, alone or when added up.
+    let big = u32::MAX as u64;
+    let frequencies = vec![
+        (b'A', big + 1),
+        (b'B', big),
+        (b'C', big / 2),
+        (b'D', big / 2),
+    ];
+    let code = tree_to_code(&build_tree(&frequencies));
+    assert_eq!(
+        code_lengths(&code),
+        vec![(b'A', 1), (b'B', 2), (b'C', 3), (b'D', 3)]
+    );
+    assert_eq!(
+        length_limited_lengths(&frequencies, 2),
+        vec![(b'A', 2), (b'B', 2), (b'C', 2), (b'D', 2)]
+    );
+
+    // Made-up counts close to the limit of `u64` saturate rather than overflow.
+    let frequencies = vec![(b'A', u64::MAX), (b'B', u64::MAX), (b'C', u64::MAX - 1)];
+    assert_eq!(
+        code_lengths(&tree_to_code(&build_tree(&frequencies))).len(),
+        3
+    );
+    assert_eq!(length_limited_lengths(&frequencies, 15).len(), 3);
+}
+
+#[test]
+fn test_zero_counts_excluded() {
+    let frequencies = vec![(b'A', 3), (b'B', 0), (b'C', 1), (b'D', 1)];
+    let code = tree_to_code(&build_tree(&frequencies));
+    assert!(code[b'B'].is_empty());
+    assert_eq!(code_lengths(&code), vec![(b'A', 1), (b'C', 2), (b'D', 2)]);
+    assert_eq!(
+        length_limited_lengths(&frequencies, 15),
+        vec![(b'A', 1), (b'C', 2), (b'D', 2)]
+    );
+
+    // A zero count doesn't turn a single symbol into two.
+    let code = tree_to_code(&build_tree(&vec![(b'A', 0), (b'B', 5)]));
+    assert_eq!(code_lengths(&code), vec![(b'B', 1)]);
+}
+
+#[test]
+fn test_follow() {
+    // The tree from `test_build_tree`: A: 0, C: 10, B: 11.
+    let tree = build_tree(&vec![(b'A', 6), (b'B', 4), (b'C', 2)]);
+    let follow = |bits: &str| tree.follow(&bits.parse().unwrap());
+    assert_eq!(follow("0"), FollowResult::Leaf(b'A'));
+    assert_eq!(follow("10"), FollowResult::Leaf(b'C'));
+    assert_eq!(follow("11"), FollowResult::Leaf(b'B'));
+    assert_eq!(follow(""), FollowResult::Internal);
+    assert_eq!(follow("1"), FollowResult::Internal);
+    assert_eq!(follow("00"), FollowResult::Invalid);
+    assert_eq!(follow("110"), FollowResult::Invalid);
+    assert_eq!(follow("1011"), FollowResult::Invalid);
+
+    // Every codeword of the code leads to its leaf.
+    let tree = build_tree(&crate::encode::fibonacci_frequencies(30));
+    for (sym, cw) in tree.iter_leaves_with_codewords() {
+        assert_eq!(tree.follow(&cw), FollowResult::Leaf(sym));
+    }
+
+    let leaf = Tree::Leaf(b'x');
+    assert_eq!(leaf.follow(&Codeword::empty()), FollowResult::Leaf(b'x'));
+    assert_eq!(leaf.follow(&"0".parse().unwrap()), FollowResult::Invalid);
+}
+
+#[quickcheck_macros::quickcheck]
+fn qc_compute_frequencies(input: Vec<u8>, repeat: u8, split: usize) -> bool {
+    // Repeated, to also get past the size below which symbols are counted directly.
+    let input = input.repeat(repeat as usize % 32 + 1);
+    let mut expected = [0u64; NUM_SYMBOLS];
+    for &sym in &input {
+        expected[sym as usize] += 1;
+    }
+    // Counting in two chunks gives the same result.
+    let split = split % (input.len() + 1);
+    let mut counts = [0; NUM_SYMBOLS];
+    count_symbols(&input[..split], &mut counts);
+    count_symbols(&input[split..], &mut counts);
+    counts == expected && compute_frequencies(&input) == frequencies_from_counts(&expected)
+}
+
+#[test]
+fn test_count_table() {
+    let input = b"ABCAAABABABC".repeat(20);
+    let table = count_table(&input);
+    assert_eq!((table[b'A' as usize], table[b'C' as usize]), (120, 40));
+    let sparse = compute_frequencies(&input);
+    assert_eq!(sparse, vec![(b'A', 120), (b'B', 80), (b'C', 40)]);
+    for (sym, &count) in table.iter().enumerate() {
+        let in_sparse = sparse.iter().find(|&&(s, _)| s as usize == sym);
+        assert_eq!(in_sparse.map_or(0, |&(_, count)| count), count);
+    }
+}
+
+#[test]
+fn test_depth_multiset() {
+    let leaf = |sym| Box::new(Tree::Leaf(sym));
+    let right_heavy = Tree::Branch(leaf(b'a'), Box::new(Tree::Branch(leaf(b'b'), leaf(b'c'))));
+    let left_heavy = Tree::Branch(Box::new(Tree::Branch(leaf(b'z'), leaf(b'a'))), leaf(b'y'));
+    assert_eq!(depth_multiset(&right_heavy), vec![1, 2, 2]);
+    assert_eq!(depth_multiset(&right_heavy), depth_multiset(&left_heavy));
+
+    let balanced = Tree::Branch(
+        Box::new(Tree::Branch(leaf(b'a'), leaf(b'b'))),
+        Box::new(Tree::Branch(leaf(b'c'), leaf(b'd'))),
+    );
+    assert_eq!(depth_multiset(&balanced), vec![2, 2, 2, 2]);
+    assert_eq!(depth_multiset(&Tree::Leaf(b'a')), vec![0]);
+
+    // Equal frequencies, assigned to other symbols.
+    let tree = build_tree(&vec![(b'a', 1), (b'b', 1), (b'c', 2), (b'd', 4)]);
+    let swapped = build_tree(&vec![(b'a', 4), (b'b', 2), (b'c', 1), (b'd', 1)]);
+    assert_eq!(depth_multiset(&tree), vec![1, 2, 3, 3]);
+    assert_eq!(depth_multiset(&tree), depth_multiset(&swapped));
+}
+
+#[test]
+fn test_frequencies_from_map() {
+    let map: HashMap<u8, usize> = [(b'c', 2), (b'a', 6), (b'z', 0), (b'b', 4)]
+        .iter()
+        .cloned()
+        .collect();
+    assert_eq!(
+        frequencies_from_map(&map),
+        vec![(b'a', 6), (b'b', 4), (b'c', 2)]
+    );
+    assert_eq!(frequencies_from_map(&HashMap::new()), vec![]);
+}
+
+#[test]
+fn test_compute_frequencies_runs() {
+    // Long runs, and lengths around multiples of the four tables.
+    for len in 0..10 {
+        let mut input = vec![b'a'; 1000 + len];
+        input.extend(vec![b'b'; len]);
+        let mut expected = vec![(b'a', 1000 + len as u64)];
+        if len > 0 {
+            expected.push((b'b', len as u64));
+        }
+        assert_eq!(compute_frequencies(&input), expected);
+    }
+    // Around the size below which symbols are counted directly.
+    for len in 120..135 {
+        assert_eq!(compute_frequencies(&vec![0; len]), vec![(0, len as u64)]);
+    }
+}
+
+#[test]
+fn test_format_code_report() {
+    let frequencies = vec![(b'C', 2), (b'A', 6), (b'B', 4), (b'\n', 0)];
+    let code = tree_to_code(&build_tree(&frequencies));
+    assert_eq!(
+        format_code_report(&code, &frequencies),
+        [
+            "symbol        count       %  codeword           bits",
+            "A                 6   50.00  0                     6",
+            "B                 4   33.33  11                    8",
+            "C                 2   16.67  10                    4",
+            "total            12  100.00                       18",
+            "",
+        ]
+        .join("\n")
+    );
+}
+
+#[test]
+fn test_format_code_report_empty() {
+    assert_eq!(
+        format_code_report(&Code::empty(), &vec![]),
+        "symbol        count       %  codeword           bits\n"
+    );
+}
+
+#[test]
+fn test_to_dot() {
+    let tree = Tree::Branch(
+        Box::new(Tree::Leaf(b'a')),
+        Box::new(Tree::Branch(
+            Box::new(Tree::Leaf(b'"')),
+            Box::new(Tree::Leaf(b'\n')),
+        )),
+    );
+    let dot = to_dot(&tree);
+    let lines: Vec<_> = dot.lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "digraph huffman {",
+            "  n0 [label=\"\", shape=circle];",
+            "  n1 [label=\"a\", shape=box];",
+            "  n0 -> n1 [label=\"0\"];",
+            "  n2 [label=\"\", shape=circle];",
+            "  n0 -> n2 [label=\"1\"];",
+            "  n3 [label=\"\\\"\", shape=box];",
+            "  n2 -> n3 [label=\"0\"];",
+            "  n4 [label=\"0x0a\", shape=box];",
+            "  n2 -> n4 [label=\"1\"];",
+            "}",
+        ]
+    );
+
+    // A single leaf has no edges.
+    assert_eq!(
+        to_dot(&Tree::Leaf(0)),
+        "digraph huffman {\n  n0 [label=\"0x00\", shape=box];\n}\n"
+    );
+}
+
+#[test]
+fn test_code_length_variance() {
+    fn leaf(sym: u8) -> Box<Tree> {
+        Box::new(Tree::Leaf(sym))
+    }
+    fn branch(left: Box<Tree>, right: Box<Tree>) -> Box<Tree> {
+        Box::new(Tree::Branch(left, right))
+    }
+    let frequencies = vec![(b'a', 4), (b'b', 2), (b'c', 2), (b'd', 1), (b'e', 1)];
+    // Both trees are optimal, with an average length of 2.2 bits: lengths 1, 2, 3, 4, 4 when
+    // merging subtrees before leaves of the same weight, and 2, 2, 2, 3, 3 when merging leaves
+    // first, which gives the minimum variance.
+    let skewed = branch(
+        leaf(b'a'),
+        branch(
+            leaf(b'b'),
+            branch(leaf(b'c'), branch(leaf(b'd'), leaf(b'e'))),
+        ),
+    );
+    let balanced = branch(
+        branch(leaf(b'a'), leaf(b'b')),
+        branch(leaf(b'c'), branch(leaf(b'd'), leaf(b'e'))),
+    );
+    let skewed_variance = code_length_variance(&skewed, &frequencies);
+    let balanced_variance = code_length_variance(&balanced, &frequencies);
+    assert!((skewed_variance - 1.36).abs() < 1e-9, "{}", skewed_variance);
+    assert!(
+        (balanced_variance - 0.16).abs() < 1e-9,
+        "{}",
+        balanced_variance
+    );
+
+    let standard = build_tree(&frequencies);
+    let standard_variance = code_length_variance(&standard, &frequencies);
+    assert!(standard_variance >= balanced_variance - 1e-9);
+
+    assert_eq!(code_length_variance(&Tree::Leaf(b'a'), &frequencies), 0.0);
+    assert_eq!(code_length_variance(&balanced, &vec![(b'x', 1)]), 0.0);
+}
+
+#[test]
+fn test_matches_prefix_of() {
+    // A window of a stream encoded with a code, decoded by trying each codeword in turn.
+    let code = canonical_code(&vec![(b'a', 1), (b'b', 2), (b'c', 3), (b'd', 3)]).unwrap();
+    let input = b"abacdba";
+    let mut window = 0u64;
+    let mut window_len = 0;
+    for &sym in input {
+        window |= code[sym].bits[0] << window_len;
+        window_len += code[sym].bit_len;
+    }
+    // Garbage past the available bits.
+    window |= u64::MAX << window_len;
+
+    let mut decoded = vec![];
+    let mut available = window_len;
+    while available > 0 {
+        let (sym, len) = (0..NUM_SYMBOLS)
+            .map(|sym| (sym as u8, &code[sym as u8]))
+            .find(|(_, cw)| !cw.is_empty() && cw.matches_prefix_of(window, available))
+            .map(|(sym, cw)| (sym, cw.bit_len))
+            .unwrap();
+        decoded.push(sym);
+        window >>= len;
+        available -= len;
+    }
+    assert_eq!(decoded, input);
+
+    let long = Codeword::from_bits(&[B1; 65]);
+    assert!(!long.matches_prefix_of(u64::MAX, 64));
+    let full = Codeword::from_bits(&[B1; 64]);
+    assert!(full.matches_prefix_of(u64::MAX, 64));
+    assert!(!full.matches_prefix_of(u64::MAX, 63));
+    assert!(Codeword::empty().matches_prefix_of(0, 0));
+}
+
+#[test]
+fn test_mirrored_u64() {
+    let cw = |s: &str| s.parse::<Codeword>().unwrap();
+    // The first bit is stored at `1 << 0`, and is the top bit of the mirrored value.
+    assert_eq!(cw("101").bits[0], 0b101);
+    assert_eq!(cw("101").mirrored_u64(), Some(0b101));
+    assert_eq!(cw("0001").bits[0], 0b1000);
+    assert_eq!(cw("0001").mirrored_u64(), Some(0b0001));
+    assert_eq!(cw("1101000").mirrored_u64(), Some(104));
+    assert_eq!(cw("").mirrored_u64(), Some(0));
+    assert_eq!(cw(&"1".repeat(64)).mirrored_u64(), Some(u64::MAX));
+    assert_eq!(
+        cw(&format!("1{}", "0".repeat(63))).mirrored_u64(),
+        Some(1 << 63)
+    );
+    assert_eq!(cw(&"1".repeat(65)).mirrored_u64(), None);
+
+    // Canonical codewords are consecutive numbers MSB-first.
+    let code =
+        canonical_code(&vec![(b'a', 2), (b'b', 2), (b'c', 2), (b'd', 3), (b'e', 3)]).unwrap();
+    let values: Vec<_> = b"abcde"
+        .iter()
+        .map(|&sym| code[sym].mirrored_u64().unwrap())
+        .collect();
+    assert_eq!(values, vec![0b00, 0b01, 0b10, 0b110, 0b111]);
+}
@@ -0,0 +1,132 @@
+//! The encoder's steady state doesn't allocate, and building a `TreeArena` takes the same
+//! allocations for any alphabet, checked with an allocator that counts the allocations of each
+//! thread (tests run on several threads at once).
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{self, Read};
+
+use huff::bitstream::Buffers;
+use huff::{corpus, encode, tree};
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+fn count() {
+    // Fails only while the thread is being torn down, which isn't when anything is measured.
+    let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+}
+
+fn allocations() -> u64 {
+    ALLOCATIONS.with(|allocations| allocations.get())
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+const CHUNKS: usize = 100;
+const CHUNK_LEN: usize = 8192;
+
+#[test]
+fn encode_to_writer_steady_state() {
+    let input = corpus::text_like(CHUNKS * CHUNK_LEN, 1);
+    let code = tree::tree_to_code(&tree::build_tree(&tree::compute_frequencies(&input)));
+    let mut encoder = encode::Encoder::new(&code);
+    // Writing into a slice doesn't allocate, unlike a growing `Vec`.
+    let mut output_buf = vec![0u8; 2 * input.len()];
+    let mut output = &mut output_buf[..];
+    let mut counts = Vec::with_capacity(CHUNKS + 1);
+    for chunk in input.chunks(CHUNK_LEN) {
+        encoder.encode_to_writer(chunk, &mut output).unwrap();
+        counts.push(allocations());
+    }
+    encoder.finish_to_writer(&mut output).unwrap();
+    counts.push(allocations());
+    assert!(
+        counts.iter().all(|&count| count == counts[0]),
+        "{:?}",
+        counts
+    );
+}
+
+/// Records the number of allocations so far at every read.
+struct Recording<'a> {
+    input: &'a [u8],
+    counts: Vec<u64>,
+}
+
+impl Read for Recording<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.counts.push(allocations());
+        self.input.read(buf)
+    }
+}
+
+#[test]
+fn encode_stream_steady_state() {
+    let input = corpus::text_like(CHUNKS * CHUNK_LEN, 2);
+    let code = tree::tree_to_code(&tree::build_tree(&tree::compute_frequencies(&input)));
+    let mut output_buf = vec![0u8; 2 * input.len()];
+    let mut buffers = Buffers::new();
+    for _ in 0..2 {
+        // Room for reads of part of a chunk, so that recording doesn't allocate either.
+        let mut reader = Recording {
+            input: &input,
+            counts: Vec::with_capacity(4 * CHUNKS),
+        };
+        encode::encode_stream_with(&code, &mut reader, &mut output_buf[..], &mut buffers).unwrap();
+        // The first read comes before the input buffer is in use.
+        let counts = &reader.counts[1..];
+        assert!(counts.len() >= CHUNKS);
+        assert!(
+            counts.iter().all(|&count| count == counts[0]),
+            "{:?}",
+            counts
+        );
+    }
+}
+
+/// Allocations taken by `build`, for an alphabet of `n` symbols.
+fn tree_allocations<T>(n: usize, build: fn(&tree::Frequencies) -> T) -> u64 {
+    let frequencies: tree::Frequencies = (0..n).map(|sym| (sym as u8, 1 + sym as u64)).collect();
+    let before = allocations();
+    let tree = build(&frequencies);
+    let count = allocations() - before;
+    drop(tree);
+    count
+}
+
+#[test]
+fn build_tree_arena_allocations_dont_grow_with_alphabet() {
+    let arena: Vec<u64> = [2, 4, 16, 64]
+        .iter()
+        .map(|&n| tree_allocations(n, tree::build_tree_arena))
+        .collect();
+    assert!(arena.iter().all(|&count| count == arena[0]), "{:?}", arena);
+    // A boxed `Tree` allocates every node, on top of the arena it's built from.
+    assert!(tree_allocations(4, tree::build_tree) > arena[0] + 6);
+}
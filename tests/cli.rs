@@ -0,0 +1,274 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::{env, fs};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("huff-cli-test-{}-{}", std::process::id(), name))
+}
+
+fn huff(args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "huff {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+fn round_trip(name: &str, input: &[u8], extra_args: &[&str]) {
+    let input_path = temp_path(&format!("{}.in", name));
+    let compressed_path = temp_path(&format!("{}.huff", name));
+    fs::write(&input_path, input).unwrap();
+
+    let mut args = extra_args.to_vec();
+    args.push(input_path.to_str().unwrap());
+    let compressed = huff(&args);
+    fs::write(&compressed_path, &compressed).unwrap();
+    let decompressed = huff(&["--decompress", compressed_path.to_str().unwrap()]);
+
+    fs::remove_file(&input_path).unwrap();
+    fs::remove_file(&compressed_path).unwrap();
+    assert_eq!(decompressed, input);
+}
+
+fn sample_input() -> Vec<u8> {
+    // Fibonacci frequencies, so that plain Huffman produces codewords longer than 15 bits.
+    let mut input = vec![];
+    let (mut a, mut b) = (1, 1);
+    for sym in 0..20 {
+        input.extend(vec![sym as u8; a]);
+        let next = a + b;
+        a = b;
+        b = next;
+    }
+    input
+}
+
+#[test]
+fn round_trip_plain() {
+    round_trip("plain", &sample_input(), &["--level", "plain"]);
+}
+
+#[test]
+fn round_trip_canonical() {
+    round_trip("canonical", &sample_input(), &["--level", "canonical"]);
+}
+
+#[test]
+fn round_trip_length_limited() {
+    round_trip("limited", &sample_input(), &["--mode", "limited"]);
+}
+
+#[test]
+fn round_trip_empty() {
+    round_trip("empty", b"", &[]);
+}
+
+#[test]
+fn decompress_max_size() {
+    let input = sample_input();
+    let input_path = temp_path("max-size.in");
+    let compressed_path = temp_path("max-size.huff");
+    fs::write(&input_path, &input).unwrap();
+    fs::write(&compressed_path, huff(&[input_path.to_str().unwrap()])).unwrap();
+
+    let too_small = (input.len() - 1).to_string();
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(["--decompress", "--max-size", &too_small])
+        .arg(&compressed_path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("exceeds the limit"));
+
+    let exact = input.len().to_string();
+    let path = compressed_path.to_str().unwrap();
+    assert_eq!(huff(&["--decompress", "--max-size", &exact, path]), input);
+    assert_eq!(
+        huff(&["--decompress", "--max-size", "unlimited", path]),
+        input
+    );
+
+    fs::remove_file(&input_path).unwrap();
+    fs::remove_file(&compressed_path).unwrap();
+}
+
+#[test]
+fn compress_mmap() {
+    let input_path = temp_path("mmap.in");
+    let empty_path = temp_path("mmap-empty.in");
+    fs::write(&input_path, sample_input()).unwrap();
+    fs::write(&empty_path, b"").unwrap();
+    for path in &[&input_path, &empty_path] {
+        let path = path.to_str().unwrap();
+        for level in &["plain", "canonical", "limited"] {
+            // Empty files can't be mapped, and are read instead.
+            assert_eq!(
+                huff(&["--mmap", "--level", level, path]),
+                huff(&["--level", level, path])
+            );
+        }
+    }
+    fs::remove_file(&input_path).unwrap();
+    fs::remove_file(&empty_path).unwrap();
+
+    round_trip("mmap", &sample_input(), &["--mmap"]);
+}
+
+#[test]
+fn compress_threads() {
+    // More than one block of the parallel encoder.
+    let input = sample_input().repeat(200);
+    assert!(input.len() > 3 << 20);
+    let input_path = temp_path("threads.in");
+    fs::write(&input_path, &input).unwrap();
+    let path = input_path.to_str().unwrap();
+    let expected = huff(&[path]);
+    for threads in &["1", "2", "5"] {
+        assert!(huff(&["--threads", threads, path]) == expected);
+        assert!(huff(&["--mmap", "--threads", threads, path]) == expected);
+    }
+    fs::remove_file(&input_path).unwrap();
+
+    round_trip("threads", &input, &["--threads", "3"]);
+}
+
+#[test]
+fn compress_one_pass() {
+    let input_path = temp_path("one-pass.in");
+    fs::write(&input_path, sample_input()).unwrap();
+    let path = input_path.to_str().unwrap();
+    let expected = huff(&["--two-pass", path]);
+    assert_eq!(huff(&["--one-pass", path]), expected);
+    assert_eq!(huff(&[path]), expected);
+    fs::remove_file(&input_path).unwrap();
+
+    round_trip("one-pass", &sample_input(), &["--one-pass"]);
+}
+
+#[test]
+fn benchmark() {
+    let input_path = temp_path("benchmark.in");
+    fs::write(&input_path, sample_input()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(["--benchmark", "3", "--level", "canonical"])
+        .arg(&input_path)
+        .output()
+        .unwrap();
+    fs::remove_file(&input_path).unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with(&format!("3 runs over {} bytes:", sample_input().len())));
+    for name in &["min", "median", "max"] {
+        assert!(stderr.contains(name), "{}", stderr);
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(["--benchmark", "0", "Cargo.toml"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    let input_path = temp_path("benchmark-empty.in");
+    fs::write(&input_path, b"").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(["--benchmark", "3"])
+        .arg(&input_path)
+        .output()
+        .unwrap();
+    fs::remove_file(&input_path).unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("empty input"), "{}", stderr);
+}
+
+#[test]
+fn not_a_regular_file() {
+    let dir_path = temp_path("dir");
+    fs::create_dir(&dir_path).unwrap();
+    for args in &[&[][..], &["--decompress"][..], &["--benchmark", "1"][..]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+            .args(*args)
+            .arg(&dir_path)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!(
+                "reading {}: not a regular file",
+                dir_path.display()
+            )),
+            "{}",
+            stderr
+        );
+    }
+    fs::remove_dir(&dir_path).unwrap();
+}
+
+#[test]
+fn compress_incompressible() {
+    let input = huff::corpus::random(1 << 16, 1);
+    let input_path = temp_path("incompressible.in");
+    fs::write(&input_path, &input).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .arg(&input_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.len() <= input.len() + 14);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("warning: input is incompressible, storing it as it is"),
+        "{}",
+        stderr
+    );
+    let quiet = huff(&["--quiet", input_path.to_str().unwrap()]);
+    assert_eq!(quiet, output.stdout);
+    fs::remove_file(&input_path).unwrap();
+
+    round_trip("incompressible", &input, &[]);
+}
+
+#[test]
+fn dry_run() {
+    for (name, input) in &[
+        ("dry-run-text", huff::corpus::text_like(1 << 16, 1)),
+        ("dry-run-random", huff::corpus::random(1 << 12, 1)),
+    ] {
+        let input_path = temp_path(&format!("{}.in", name));
+        fs::write(&input_path, input).unwrap();
+        let path = input_path.to_str().unwrap();
+        let compressed = huff(&["--quiet", path]);
+        let output = String::from_utf8(huff(&["--quiet", "--dry-run", path])).unwrap();
+        fs::remove_file(&input_path).unwrap();
+        assert!(
+            output.starts_with(&format!(
+                "{} bytes predicted for {} bytes of input (ratio ",
+                compressed.len(),
+                input.len()
+            )),
+            "{}",
+            output
+        );
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(["--decompress", "--dry-run", "Cargo.toml"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_huff"))
+        .args(["--dry-run", "--benchmark", "1", "Cargo.toml"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}